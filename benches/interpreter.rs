@@ -0,0 +1,62 @@
+//! Instructions a second, decoding every instruction as it runs against
+//! running from the block cache.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use chippe_rs::asm::assemble;
+use chippe_rs::{Processor, Quirks};
+
+const INSTRUCTIONS: u64 = 100_000;
+
+// A bit of everything: arithmetic, skips, BCD and stores into data, and
+// drawing. SUPER-CHIP quirks, so DRW doesn't wait for the next frame and
+// it's all spent running code.
+const PROGRAM: &str = "
+main:
+    LD V0, 0
+    LD V1, 0
+    LD V3, 0
+loop:
+    ADD V0, 1
+    LD V2, V0
+    SHR V2, V2
+    XOR V3, V2
+    SE V0, 0
+    ADD V1, 1
+    LD I, scratch
+    LD B, V0
+    LD [I], V3
+    LD I, sprite
+    DRW V0, V1, 4
+    JP loop
+
+sprite:
+    DB 0x60, 0xf0, 0xf0, 0x60
+scratch:
+    DB 0, 0, 0, 0, 0, 0, 0, 0
+";
+
+fn processor(block_cache: bool) -> Processor {
+    let assembly = assemble(PROGRAM).unwrap();
+    let mut cpu = Processor::headless();
+    cpu.set_quirks(Quirks::SUPER_CHIP);
+    cpu.set_block_cache(block_cache);
+    // The whole run is one frame
+    cpu.set_instructions_per_frame(INSTRUCTIONS as u32);
+    cpu.reset();
+    cpu.load_rom_bytes(assembly.bytes()).unwrap();
+    cpu
+}
+
+fn interpreter(c: &mut Criterion) {
+    let mut group = c.benchmark_group("interpreter");
+    group.throughput(Throughput::Elements(INSTRUCTIONS));
+    for &(name, block_cache) in &[("decode every time", false), ("block cache", true)] {
+        let mut cpu = processor(block_cache);
+        group.bench_function(name, |b| b.iter(|| cpu.step_frame().unwrap()));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, interpreter);
+criterion_main!(benches);
@@ -0,0 +1,738 @@
+//! An assembler for the classic mnemonics the disassembler and the trace
+//! print.
+//!
+//! ```text
+//! ; comments run to the end of the line
+//! SPEED EQU 2             ; constants
+//! INCLUDE "sprites.asm"   ; relative to this file
+//!
+//! main:
+//!     LD V0, SPEED
+//!     LD I, ball
+//!     DRW V0, V1, 4
+//!     JP main
+//!
+//! ball:
+//!     DB 0x60, 0xf0, 0xf0, 0x60
+//!     DW 0x1234, main
+//! ```
+//!
+//! `ORG` sets the load address before anything's been assembled, and skips
+//! ahead after. Numbers are decimal, or hex with `0x`, or binary with `0b`,
+//! and can be added and subtracted. Whatever [`Disassembly`] writes out
+//! assembles back to the same bytes:
+//!
+//! ```
+//! use chippe_rs::asm::assemble;
+//! use chippe_rs::{Disassembly, Syntax};
+//!
+//! let rom = [0x60, 0x05, 0xa2, 0x06, 0x12, 0x04, 0x3c, 0x42];
+//! let mut source = Vec::new();
+//! Disassembly::new(&rom, 0x200)
+//!     .write_to(Syntax::Classic, &mut source)
+//!     .unwrap();
+//! let assembly = assemble(&String::from_utf8(source).unwrap()).unwrap();
+//! assert_eq!(assembly.bytes(), &rom[..]);
+//! ```
+//!
+//! [`Disassembly`]: crate::disasm::Disassembly
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::debugger::symbols::{SourceLine, SymbolMap};
+use crate::processor::DEFAULT_LOAD_ADDRESS;
+
+// Deep enough for any sensible program, shallow enough to catch a file that
+// includes itself
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// What went wrong, and where.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub file: String,
+    /// Starting at 1, or 0 for problems with the file as a whole
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}: {}", self.file, self.message)
+        } else {
+            write!(f, "{}:{}: {}", self.file, self.line, self.message)
+        }
+    }
+}
+
+impl Error for AsmError {}
+
+/// An assembled program.
+#[derive(Debug, Clone)]
+pub struct Assembly {
+    load_address: u16,
+    bytes: Vec<u8>,
+    labels: BTreeMap<String, u16>,
+    symbols: SymbolMap,
+}
+
+impl Assembly {
+    pub fn load_address(&self) -> u16 {
+        self.load_address
+    }
+
+    /// The ROM, to be loaded at [`Assembly::load_address`].
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn label(&self, name: &str) -> Option<u16> {
+        self.labels.get(name).copied()
+    }
+
+    /// Which line each instruction came from, for the debug adapter.
+    pub fn symbols(&self) -> &SymbolMap {
+        &self.symbols
+    }
+}
+
+/// Assemble `source`, with any includes relative to the current directory.
+pub fn assemble(source: &str) -> Result<Assembly, AsmError> {
+    let mut lines = Vec::new();
+    read_lines(Rc::from("<input>"), Path::new(""), source, 0, &mut lines)?;
+    Assembler::default().assemble(&lines)
+}
+
+pub fn assemble_file<P: AsRef<Path>>(path: P) -> Result<Assembly, AsmError> {
+    let path = path.as_ref();
+    let (text, path) = read_file(path).map_err(|message| AsmError {
+        file: path.display().to_string(),
+        line: 0,
+        message,
+    })?;
+    let mut lines = Vec::new();
+    let directory = path.parent().unwrap_or_else(|| Path::new(""));
+    let name = Rc::from(path.display().to_string());
+    read_lines(name, directory, &text, 0, &mut lines)?;
+    Assembler::default().assemble(&lines)
+}
+
+// Absolute paths, so symbol maps work from wherever they end up
+fn read_file(path: &Path) -> Result<(String, PathBuf), String> {
+    let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    Ok((text, path))
+}
+
+// One line of source, with includes already pulled in
+struct Line {
+    file: Rc<str>,
+    number: usize,
+    text: String,
+}
+
+impl Line {
+    fn error(&self, message: String) -> AsmError {
+        AsmError {
+            file: self.file.to_string(),
+            line: self.number,
+            message,
+        }
+    }
+}
+
+fn read_lines(
+    file: Rc<str>,
+    directory: &Path,
+    text: &str,
+    depth: usize,
+    lines: &mut Vec<Line>,
+) -> Result<(), AsmError> {
+    for (i, text) in text.lines().enumerate() {
+        let line = Line {
+            file: file.clone(),
+            number: i + 1,
+            text: strip_comment(text).trim().to_string(),
+        };
+        let (mnemonic, rest) = split_mnemonic(&line.text);
+        if !mnemonic.eq_ignore_ascii_case("include") {
+            lines.push(line);
+            continue;
+        }
+
+        if depth >= MAX_INCLUDE_DEPTH {
+            return Err(line.error("Includes nested too deeply".to_string()));
+        }
+        let name = rest.trim().trim_matches('"');
+        let (text, path) =
+            read_file(&directory.join(name)).map_err(|e| line.error(format!("{}: {}", name, e)))?;
+        let included = Rc::from(path.display().to_string());
+        let directory = path.parent().unwrap_or_else(|| Path::new(""));
+        read_lines(included, directory, &text, depth + 1, lines)?;
+    }
+    Ok(())
+}
+
+// Everything from a `;` that isn't in a string
+fn strip_comment(text: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &text[..i],
+            _ => (),
+        }
+    }
+    text
+}
+
+fn split_mnemonic(text: &str) -> (&str, &str) {
+    text.split_once(char::is_whitespace).unwrap_or((text, ""))
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+// What's left of a line once its label's been taken off
+enum Statement<'a> {
+    Instruction {
+        mnemonic: String,
+        operands: Vec<&'a str>,
+    },
+    Bytes(Vec<&'a str>),
+    Words(Vec<&'a str>),
+}
+
+#[derive(Default)]
+struct Assembler {
+    // Labels and constants
+    names: BTreeMap<String, i64>,
+    labels: BTreeMap<String, u16>,
+}
+
+impl Assembler {
+    fn assemble(mut self, lines: &[Line]) -> Result<Assembly, AsmError> {
+        // First work out where everything goes, so labels can be used before
+        // they're defined
+        let mut load_address = None;
+        let mut address = DEFAULT_LOAD_ADDRESS as i64;
+        let mut placed = Vec::new();
+        for line in lines {
+            let mut text = line.text.as_str();
+            if let Some((label, rest)) = text.split_once(':') {
+                let label = label.trim();
+                if is_identifier(label) {
+                    self.define(line, label, address)?;
+                    self.labels.insert(label.to_string(), address as u16);
+                    text = rest.trim();
+                }
+            }
+            if text.is_empty() {
+                continue;
+            }
+
+            let (mnemonic, rest) = split_mnemonic(text);
+            let upper = mnemonic.to_ascii_uppercase();
+            // NAME EQU VALUE
+            if let Some(value) = strip_keyword(rest, "EQU") {
+                let value = self.eval(line, value)?;
+                self.define(line, mnemonic, value)?;
+                continue;
+            }
+            if upper == "ORG" {
+                let origin = self.eval(line, rest)?;
+                self.check(line, origin, 0, 0xFFFF, "ORG address")?;
+                match load_address {
+                    None => load_address = Some(origin as u16),
+                    Some(_) if origin < address => {
+                        return Err(line.error(format!("ORG 0x{:x} would go backwards", origin)))
+                    }
+                    Some(_) => (),
+                }
+                address = origin;
+                continue;
+            }
+            load_address.get_or_insert(address as u16);
+
+            let operands = split_operands(rest);
+            let statement = match upper.as_str() {
+                "DB" => Statement::Bytes(operands),
+                "DW" => Statement::Words(operands),
+                _ => Statement::Instruction {
+                    mnemonic: upper,
+                    operands,
+                },
+            };
+            let len = match &statement {
+                Statement::Bytes(operands) => operands
+                    .iter()
+                    .map(|operand| string_literal(operand).map_or(1, |s| s.len()))
+                    .sum(),
+                Statement::Words(operands) => 2 * operands.len(),
+                Statement::Instruction { operands, .. } => {
+                    let parsed: Vec<_> = operands.iter().map(|o| operand(o)).collect();
+                    match parsed.as_slice() {
+                        [Some(Operand::I), Some(Operand::Long)] => 4,
+                        _ => 2,
+                    }
+                }
+            };
+            placed.push((line, address, statement));
+            address += len as i64;
+            if address > 0x10000 {
+                return Err(line.error("Program goes past the end of memory".to_string()));
+            }
+        }
+
+        // Then fill it in
+        let load_address = load_address.unwrap_or(DEFAULT_LOAD_ADDRESS);
+        let mut bytes = Vec::new();
+        let mut symbols = SymbolMap::new();
+        for (line, address, statement) in placed {
+            let offset = (address - load_address as i64) as usize;
+            bytes.resize(offset, 0);
+            match statement {
+                Statement::Bytes(operands) => {
+                    for operand in operands {
+                        match string_literal(operand) {
+                            Some(s) => bytes.extend(s.bytes()),
+                            None => {
+                                let value = self.eval(line, operand)?;
+                                self.check(line, value, -128, 0xFF, "Byte")?;
+                                bytes.push(value as u8);
+                            }
+                        }
+                    }
+                }
+                Statement::Words(operands) => {
+                    for operand in operands {
+                        let value = self.eval(line, operand)?;
+                        self.check(line, value, -32768, 0xFFFF, "Word")?;
+                        bytes.extend(&(value as u16).to_be_bytes());
+                    }
+                }
+                Statement::Instruction { mnemonic, operands } => {
+                    let words = self.encode(line, &mnemonic, &operands)?;
+                    for word in words {
+                        bytes.extend(&word.to_be_bytes());
+                    }
+                    symbols.insert(
+                        address as u16,
+                        SourceLine {
+                            file: line.file.to_string(),
+                            line: line.number as u32,
+                        },
+                    );
+                }
+            }
+        }
+
+        Ok(Assembly {
+            load_address,
+            bytes,
+            labels: self.labels,
+            symbols,
+        })
+    }
+
+    fn define(&mut self, line: &Line, name: &str, value: i64) -> Result<(), AsmError> {
+        if !is_identifier(name) || operand(name).is_some() {
+            return Err(line.error(format!("'{}' can't be used as a name", name)));
+        }
+        if self.names.insert(name.to_string(), value).is_some() {
+            return Err(line.error(format!("'{}' is defined twice", name)));
+        }
+        Ok(())
+    }
+
+    // Sums and differences of numbers and names
+    fn eval(&self, line: &Line, text: &str) -> Result<i64, AsmError> {
+        let text = text.trim();
+        if text.is_empty() {
+            return Err(line.error("Missing a value".to_string()));
+        }
+        let mut total = 0i64;
+        let mut sign = 1;
+        let mut term = String::new();
+        let mut add = |term: &mut String, sign: i64| -> Result<(), AsmError> {
+            let value = self.term(line, term.trim())?;
+            total = sign
+                .checked_mul(value)
+                .and_then(|value| total.checked_add(value))
+                .ok_or_else(|| line.error(format!("'{}' is too big", text)))?;
+            term.clear();
+            Ok(())
+        };
+        for c in text.chars() {
+            match c {
+                '+' | '-' if !term.trim().is_empty() => {
+                    add(&mut term, sign)?;
+                    sign = if c == '-' { -1 } else { 1 };
+                }
+                '-' => sign = -sign,
+                '+' => (),
+                _ => term.push(c),
+            }
+        }
+        add(&mut term, sign)?;
+        Ok(total)
+    }
+
+    fn term(&self, line: &Line, term: &str) -> Result<i64, AsmError> {
+        let lower = term.to_ascii_lowercase();
+        let number = if let Some(hex) = lower.strip_prefix("0x") {
+            i64::from_str_radix(hex, 16).ok()
+        } else if let Some(binary) = lower.strip_prefix("0b") {
+            i64::from_str_radix(binary, 2).ok()
+        } else if term.starts_with(|c: char| c.is_ascii_digit()) {
+            term.parse().ok()
+        } else {
+            return self
+                .names
+                .get(term)
+                .copied()
+                .ok_or_else(|| line.error(format!("'{}' isn't defined", term)));
+        };
+        number.ok_or_else(|| line.error(format!("'{}' isn't a number", term)))
+    }
+
+    fn check(
+        &self,
+        line: &Line,
+        value: i64,
+        min: i64,
+        max: i64,
+        what: &str,
+    ) -> Result<(), AsmError> {
+        if value < min || value > max {
+            return Err(line.error(format!("{} 0x{:x} is out of range", what, value)));
+        }
+        Ok(())
+    }
+
+    fn address(&self, line: &Line, text: &str) -> Result<u16, AsmError> {
+        let value = self.eval(line, text)?;
+        self.check(line, value, 0, 0xFFF, "Address")?;
+        Ok(value as u16)
+    }
+
+    fn byte(&self, line: &Line, text: &str) -> Result<u8, AsmError> {
+        let value = self.eval(line, text)?;
+        self.check(line, value, -128, 0xFF, "Byte")?;
+        Ok(value as u8)
+    }
+
+    fn nibble(&self, line: &Line, text: &str) -> Result<u8, AsmError> {
+        let value = self.eval(line, text)?;
+        self.check(line, value, 0, 0xF, "Value")?;
+        Ok(value as u8)
+    }
+
+    fn encode(&self, line: &Line, mnemonic: &str, operands: &[&str]) -> Result<Vec<u16>, AsmError> {
+        use self::Operand::*;
+        use crate::instruction::Instruction::*;
+
+        let parsed: Vec<Option<Operand>> = operands.iter().map(|o| operand(o)).collect();
+        let instruction = match (mnemonic, parsed.as_slice()) {
+            ("CLS", []) => Cls,
+            ("RET", []) => Ret,
+            ("SCD", [None]) => ScrollDown(self.nibble(line, operands[0])?),
+            ("SCU", [None]) => ScrollUp(self.nibble(line, operands[0])?),
+            ("SCR", []) => ScrollRight,
+            ("SCL", []) => ScrollLeft,
+            ("EXIT", []) => Exit,
+            ("LOW", []) => Low,
+            ("HIGH", []) => High,
+            ("JP", [None]) => Jump(self.address(line, operands[0])?),
+            ("JP", [Some(V(0)), None]) => JumpV0(self.address(line, operands[1])?),
+            ("CALL", [None]) => Call(self.address(line, operands[0])?),
+            ("SE", [Some(V(x)), None]) => SkipEqual {
+                x: *x,
+                value: self.byte(line, operands[1])?,
+            },
+            ("SNE", [Some(V(x)), None]) => SkipNotEqual {
+                x: *x,
+                value: self.byte(line, operands[1])?,
+            },
+            ("SE", [Some(V(x)), Some(V(y))]) => SkipEqualRegister { x: *x, y: *y },
+            ("SAVE", [Some(V(x)), Some(V(y))]) => Save { x: *x, y: *y },
+            ("LOAD", [Some(V(x)), Some(V(y))]) => Load { x: *x, y: *y },
+            ("LD", [Some(V(x)), None]) => LoadByte {
+                x: *x,
+                value: self.byte(line, operands[1])?,
+            },
+            ("ADD", [Some(V(x)), None]) => AddByte {
+                x: *x,
+                value: self.byte(line, operands[1])?,
+            },
+            ("LD", [Some(V(x)), Some(V(y))]) => Move { x: *x, y: *y },
+            ("OR", [Some(V(x)), Some(V(y))]) => Or { x: *x, y: *y },
+            ("AND", [Some(V(x)), Some(V(y))]) => And { x: *x, y: *y },
+            ("XOR", [Some(V(x)), Some(V(y))]) => Xor { x: *x, y: *y },
+            ("ADD", [Some(V(x)), Some(V(y))]) => Add { x: *x, y: *y },
+            ("SUB", [Some(V(x)), Some(V(y))]) => Sub { x: *x, y: *y },
+            ("SHR", [Some(V(x))]) => ShiftRight { x: *x, y: *x },
+            ("SHR", [Some(V(x)), Some(V(y))]) => ShiftRight { x: *x, y: *y },
+            ("SUBN", [Some(V(x)), Some(V(y))]) => SubN { x: *x, y: *y },
+            ("SHL", [Some(V(x))]) => ShiftLeft { x: *x, y: *x },
+            ("SHL", [Some(V(x)), Some(V(y))]) => ShiftLeft { x: *x, y: *y },
+            ("SNE", [Some(V(x)), Some(V(y))]) => SkipNotEqualRegister { x: *x, y: *y },
+            ("LD", [Some(I), None]) => LoadI(self.address(line, operands[1])?),
+            ("LD", [Some(I), Some(Long)]) => {
+                let address = strip_keyword(operands[1], "LONG").unwrap_or_default();
+                let address = self.eval(line, address)?;
+                self.check(line, address, 0, 0xFFFF, "Address")?;
+                return Ok(vec![LoadILong.encode(), address as u16]);
+            }
+            ("RND", [Some(V(x)), None]) => Random {
+                x: *x,
+                mask: self.byte(line, operands[1])?,
+            },
+            ("DRW", [Some(V(x)), Some(V(y)), None]) => Draw {
+                x: *x,
+                y: *y,
+                rows: self.nibble(line, operands[2])?,
+            },
+            ("SKP", [Some(V(x))]) => SkipKey(*x),
+            ("SKNP", [Some(V(x))]) => SkipNotKey(*x),
+            ("PLANE", [None]) => Plane(self.nibble(line, operands[0])?),
+            ("AUDIO", []) => Audio,
+            ("LD", [Some(V(x)), Some(Dt)]) => GetDelay(*x),
+            ("LD", [Some(V(x)), Some(K)]) => WaitKey(*x),
+            ("LD", [Some(Dt), Some(V(x))]) => SetDelay(*x),
+            ("LD", [Some(St), Some(V(x))]) => SetSound(*x),
+            ("ADD", [Some(I), Some(V(x))]) => AddI(*x),
+            ("LD", [Some(F), Some(V(x))]) => Font(*x),
+            ("LD", [Some(Hf), Some(V(x))]) => BigFont(*x),
+            ("LD", [Some(B), Some(V(x))]) => Bcd(*x),
+            ("PITCH", [Some(V(x))]) => Pitch(*x),
+            ("LD", [Some(IndirectI), Some(V(x))]) => StoreRegisters(*x),
+            ("LD", [Some(V(x)), Some(IndirectI)]) => LoadRegisters(*x),
+            ("LD", [Some(R), Some(V(x))]) => SaveFlags(*x),
+            ("LD", [Some(V(x)), Some(R)]) => LoadFlags(*x),
+            _ => {
+                return Err(line.error(format!(
+                    "Can't assemble '{} {}'",
+                    mnemonic,
+                    operands.join(", ")
+                )))
+            }
+        };
+        Ok(vec![instruction.encode()])
+    }
+}
+
+// The operands that aren't numbers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operand {
+    V(u8),
+    I,
+    IndirectI,
+    Long,
+    Dt,
+    St,
+    K,
+    F,
+    Hf,
+    B,
+    R,
+}
+
+fn operand(text: &str) -> Option<Operand> {
+    let upper = text.trim().to_ascii_uppercase();
+    let operand = match upper.as_str() {
+        "I" => Operand::I,
+        "[I]" => Operand::IndirectI,
+        "DT" => Operand::Dt,
+        "ST" => Operand::St,
+        "K" => Operand::K,
+        "F" => Operand::F,
+        "HF" => Operand::Hf,
+        "B" => Operand::B,
+        "R" => Operand::R,
+        _ if strip_keyword(text, "LONG").is_some() => Operand::Long,
+        _ => {
+            let register = upper.strip_prefix('V')?;
+            if register.len() != 1 {
+                return None;
+            }
+            Operand::V(u8::from_str_radix(register, 16).ok()?)
+        }
+    };
+    Some(operand)
+}
+
+// `keyword rest`, case insensitively
+fn strip_keyword<'a>(text: &'a str, keyword: &str) -> Option<&'a str> {
+    let (first, rest) = split_mnemonic(text.trim());
+    if first.eq_ignore_ascii_case(keyword) && !rest.trim().is_empty() {
+        Some(rest.trim())
+    } else {
+        None
+    }
+}
+
+fn string_literal(text: &str) -> Option<&str> {
+    let text = text.trim();
+    if text.len() >= 2 && text.starts_with('"') && text.ends_with('"') {
+        Some(&text[1..text.len() - 1])
+    } else {
+        None
+    }
+}
+
+// Split on commas, apart from the ones in strings
+fn split_operands(text: &str) -> Vec<&str> {
+    if text.trim().is_empty() {
+        return Vec::new();
+    }
+    let mut operands = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                operands.push(text[start..i].trim());
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    operands.push(text[start..].trim());
+    operands
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{assemble, assemble_file};
+    use crate::disasm::{Disassembly, Syntax};
+
+    fn error(source: &str) -> String {
+        let error = assemble(source).unwrap_err();
+        format!("{}:{}", error.line, error.message)
+    }
+
+    #[test]
+    fn disassembly_assembles_back() {
+        // Any old bytes, code or not, from a little xorshift so failures
+        // can be reproduced
+        let mut state = 0x2545_f491u32;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+        for _ in 0..300 {
+            let len = 1 + next() as usize % 128;
+            let rom: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+            let mut source = Vec::new();
+            Disassembly::new(&rom, 0x200)
+                .write_to(Syntax::Classic, &mut source)
+                .unwrap();
+            let source = String::from_utf8(source).unwrap();
+            let assembly = assemble(&source).unwrap_or_else(|e| panic!("{} in\n{}", e, source));
+            assert_eq!(assembly.bytes(), &rom[..], "{}", source);
+        }
+    }
+
+    #[test]
+    fn names_and_origins() {
+        let assembly = assemble(
+            "
+            ORG 0x300
+            SPEED EQU 2
+            start: LD V0, SPEED + 0x10 - 1
+                   JP TOP
+                   ORG 0x308
+            end:   DB 0b101, -1, \"hi\"
+            TOP EQU end - 2
+            ",
+        )
+        .unwrap();
+        assert_eq!(assembly.load_address(), 0x300);
+        assert_eq!(assembly.label("start"), Some(0x300));
+        assert_eq!(assembly.label("end"), Some(0x308));
+        assert_eq!(assembly.label("SPEED"), None);
+        assert_eq!(
+            assembly.bytes(),
+            &[0x60, 0x11, 0x13, 0x06, 0, 0, 0, 0, 0x05, 0xff, b'h', b'i'][..]
+        );
+    }
+
+    #[test]
+    fn includes() {
+        let directory = std::env::temp_dir().join(format!("chippe_rs-asm-{}", std::process::id()));
+        fs::create_dir_all(directory.join("lib")).unwrap();
+        fs::write(
+            directory.join("main.asm"),
+            "INCLUDE \"lib/sprites.asm\"\nLD I, ball\n",
+        )
+        .unwrap();
+        // Relative to the including file, not the current directory
+        fs::write(
+            directory.join("lib/sprites.asm"),
+            "JP over\nINCLUDE \"ball.asm\"\nover:\n",
+        )
+        .unwrap();
+        fs::write(directory.join("lib/ball.asm"), "ball: DB 0x60, 0xf0\n").unwrap();
+        fs::write(directory.join("loop.asm"), "INCLUDE \"loop.asm\"\n").unwrap();
+
+        let assembly = assemble_file(directory.join("main.asm"));
+        let looped = assemble_file(directory.join("loop.asm"));
+        let missing = assemble_file(directory.join("missing.asm"));
+        fs::remove_dir_all(&directory).unwrap();
+
+        let assembly = assembly.unwrap();
+        assert_eq!(assembly.bytes(), &[0x12, 0x04, 0x60, 0xf0, 0xa2, 0x02][..]);
+        let source = assembly.symbols().line_for(0x204).unwrap();
+        assert!(source.file.ends_with("main.asm"), "{}", source.file);
+        assert_eq!(source.line, 2);
+        assert_eq!(looped.unwrap_err().message, "Includes nested too deeply");
+        assert_eq!(missing.unwrap_err().line, 0);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error("LD V0, nowhere"), "1:'nowhere' isn't defined");
+        assert_eq!(error("a: CLS\na: CLS"), "2:'a' is defined twice");
+        assert_eq!(error("V0 EQU 1"), "1:'V0' can't be used as a name");
+        assert_eq!(error("CLS\nJP 0x1000"), "2:Address 0x1000 is out of range");
+        assert_eq!(error("LD V0, 0x100"), "1:Byte 0x100 is out of range");
+        assert_eq!(error("DB 0x12g"), "1:'0x12g' isn't a number");
+        assert_eq!(error("CLS\nORG 0x200"), "2:ORG 0x200 would go backwards");
+        assert_eq!(
+            error("ORG 0xfffe\nCLS\nCLS"),
+            "3:Program goes past the end of memory"
+        );
+        assert_eq!(error("DB 1,"), "1:Missing a value");
+    }
+
+    #[test]
+    fn overflow_is_an_error() {
+        let huge = "0x7fffffffffffffff";
+        assert_eq!(
+            error(&format!("DW {} + 1", huge)),
+            format!("1:'{} + 1' is too big", huge)
+        );
+        assert_eq!(
+            error(&format!("DW -{} - 2", huge)),
+            format!("1:'-{} - 2' is too big", huge)
+        );
+        assert_eq!(
+            error("DW 0x10000000000000000"),
+            "1:'0x10000000000000000' isn't a number"
+        );
+    }
+}
@@ -0,0 +1,243 @@
+use std::rc::Rc;
+
+use crate::instruction::Instruction;
+
+// Long enough to cover any sensible straight line of code
+const MAX_BLOCK_LEN: usize = 64;
+
+/// Straight-line code, decoded.
+pub(crate) struct Block {
+    /// Each instruction with its address
+    pub(crate) ops: Vec<(u16, Instruction)>,
+    start: usize,
+    // One past the last byte
+    end: usize,
+}
+
+impl Block {
+    // Up to the first jump, call or return, or anything that doesn't
+    // decode
+    fn decode(start: u16, memory: &[u8]) -> Block {
+        let mut ops = Vec::new();
+        let mut address = start as usize;
+        while ops.len() < MAX_BLOCK_LEN && address + 1 < memory.len() {
+            let op = (memory[address] as u16) << 8 | memory[address + 1] as u16;
+            let instruction = match Instruction::decode(op) {
+                Some(instruction) => instruction,
+                None => break,
+            };
+            ops.push((address as u16, instruction));
+            address += instruction.size() as usize;
+            if matches!(
+                instruction,
+                Instruction::Jump(_)
+                    | Instruction::JumpV0(_)
+                    | Instruction::Call(_)
+                    | Instruction::Ret
+                    | Instruction::Exit
+            ) {
+                break;
+            }
+        }
+        Block {
+            ops,
+            start: start as usize,
+            end: address.min(memory.len()),
+        }
+    }
+}
+
+/// Decoded instructions, so code that runs over and over only gets decoded
+/// once. Writes to RAM have to be passed to [`BlockCache::invalidate`] so
+/// self-modifying code still does what it says.
+pub(crate) struct BlockCache {
+    // By start address
+    blocks: Vec<Option<Rc<Block>>>,
+    // How many blocks each byte of RAM is part of
+    coverage: Vec<u16>,
+    // The block being run, and the index of the instruction expected next
+    current: Option<(Rc<Block>, usize)>,
+    // Goes up whenever blocks are thrown away
+    generation: u32,
+}
+
+impl BlockCache {
+    pub(crate) fn new(memory_size: usize) -> BlockCache {
+        BlockCache {
+            blocks: vec![None; memory_size],
+            coverage: vec![0; memory_size],
+            current: None,
+            generation: 0,
+        }
+    }
+
+    /// The instruction at `pc`, or None if there isn't one there.
+    pub(crate) fn instruction(&mut self, pc: u16, memory: &[u8]) -> Option<Instruction> {
+        // Usually it's the next one in the block, or the one after that when
+        // something skipped
+        if let Some((block, next)) = &mut self.current {
+            for index in *next..*next + 2 {
+                match block.ops.get(index) {
+                    Some(&(address, instruction)) if address == pc => {
+                        *next = index + 1;
+                        return Some(instruction);
+                    }
+                    Some(_) => (),
+                    None => break,
+                }
+            }
+        }
+
+        let block = self.block(pc, memory)?;
+        let instruction = block.ops.first().map(|&(_, instruction)| instruction);
+        self.current = Some((block, 1));
+        instruction
+    }
+
+    /// The block starting at `pc`, decoding it if it hasn't been already.
+    pub(crate) fn block(&mut self, pc: u16, memory: &[u8]) -> Option<Rc<Block>> {
+        if self.blocks.len() < memory.len() {
+            self.blocks.resize(memory.len(), None);
+            self.coverage.resize(memory.len(), 0);
+        }
+        match self.blocks.get(pc as usize)? {
+            Some(block) => Some(block.clone()),
+            None => {
+                let block = Rc::new(Block::decode(pc, memory));
+                for count in &mut self.coverage[block.start..block.end] {
+                    *count += 1;
+                }
+                self.blocks[pc as usize] = Some(block.clone());
+                Some(block)
+            }
+        }
+    }
+
+    /// Forget any blocks that include `start..start + len`.
+    pub(crate) fn invalidate(&mut self, start: usize, len: usize) {
+        let end = (start + len).min(self.coverage.len());
+        let start = start.min(end);
+        if self.coverage[start..end].iter().all(|&count| count == 0) {
+            return;
+        }
+
+        // Blocks are at most MAX_BLOCK_LEN instructions of up to 4 bytes, so
+        // that's as far back as one that overlaps could start
+        let earliest = start.saturating_sub(MAX_BLOCK_LEN * 4);
+        for slot in &mut self.blocks[earliest..end] {
+            let overlaps = matches!(slot, Some(block) if block.start < end && start < block.end);
+            if overlaps {
+                if let Some(block) = slot.take() {
+                    for count in &mut self.coverage[block.start..block.end] {
+                        *count -= 1;
+                    }
+                }
+            }
+        }
+        self.current = None;
+        self.generation = self.generation.wrapping_add(1);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.blocks.iter_mut().for_each(|block| *block = None);
+        self.coverage.iter_mut().for_each(|count| *count = 0);
+        self.current = None;
+        self.generation = self.generation.wrapping_add(1);
+    }
+
+    /// Changes whenever a block might have been thrown away, so anything
+    /// holding on to one knows to stop.
+    pub(crate) fn generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+    use crate::Processor;
+
+    fn processor(source: &str, block_cache: bool) -> Processor {
+        let mut cpu = testing::processor(source);
+        cpu.set_block_cache(block_cache);
+        cpu
+    }
+
+    fn run(source: &str, block_cache: bool, steps: usize) -> Processor {
+        let mut cpu = processor(source, block_cache);
+        for _ in 0..steps {
+            if cpu.step().is_err() {
+                break;
+            }
+        }
+        cpu
+    }
+
+    // step_frame runs whole blocks at a time when it can
+    fn run_frame(source: &str, steps: usize) -> Processor {
+        let mut cpu = processor(source, true);
+        cpu.set_instructions_per_frame(steps as u32);
+        let _ = cpu.step_frame();
+        cpu
+    }
+
+    fn same_as_uncached(source: &str, steps: usize) {
+        let plain = run(source, false, steps);
+        for cached in &[run(source, true, steps), run_frame(source, steps)] {
+            assert_eq!(cached.registers(), plain.registers());
+            assert_eq!(cached.reg_i(), plain.reg_i());
+            assert_eq!(cached.program_counter(), plain.program_counter());
+            assert_eq!(cached.memory(), plain.memory());
+            assert_eq!(cached.exit_reason(), plain.exit_reason());
+        }
+    }
+
+    #[test]
+    fn skips_and_calls() {
+        same_as_uncached(
+            "
+            loop:
+                ADD V0, 1
+                SNE V0, 3
+                CALL sub
+                SE V0, 0x10
+                JP loop
+                EXIT
+            sub:
+                ADD V1, 1
+                RET
+            ",
+            1000,
+        );
+    }
+
+    // Rewrites the LD V1 in its own loop, which only works if the cached
+    // copy gets thrown away
+    #[test]
+    fn self_modifying_code() {
+        same_as_uncached(
+            "
+            loop:
+                LD V1, 0
+                ADD V0, 1
+                LD I, loop + 1
+                LD [I], V0
+                SE V1, 5
+                JP loop
+                EXIT
+            ",
+            1000,
+        );
+        let cpu = run(
+            "LD V1, 0\nLD V0, 7\nLD I, 0x201\nLD [I], V0\nJP 0x200",
+            true,
+            6,
+        );
+        assert_eq!(cpu.registers()[1], 7);
+    }
+
+    #[test]
+    fn faults_report_the_opcode() {
+        same_as_uncached("LD V0, 1\nDW 0x5001", 10);
+    }
+}
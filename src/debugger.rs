@@ -0,0 +1,810 @@
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+use crate::fault::ExitReason;
+use crate::instruction::Instruction;
+use crate::processor::Processor;
+
+pub mod dap;
+pub mod expression;
+pub mod gdb;
+pub mod symbols;
+
+pub use self::expression::Expression;
+
+// Instructions shown either side of the PC by `list`
+const LIST_BEFORE: u16 = 4;
+const LIST_AFTER: u16 = 8;
+const DUMP_WIDTH: usize = 16;
+
+const HELP: &str = "\
+Numbers are hex, with or without a leading 0x.
+  b, break ADDR        stop before the instruction at ADDR runs
+  break ADDR if EXPR   ...but only when EXPR is true, e.g. v3 == 10 && i > 300
+  break if EXPR        stop anywhere, as soon as EXPR becomes true
+  watch ADDR [LEN]     stop after an instruction writes to ADDR..ADDR+LEN
+  rwatch ADDR [LEN]    ...or reads from it
+  awatch ADDR [LEN]    ...or does either
+  log ADDR MESSAGE     print MESSAGE at ADDR without stopping, {EXPR} in
+                       it shows that expression's value
+  d, delete ADDR       remove the breakpoint and logpoint at ADDR
+  delete #N            remove `break if` number N
+  unwatch ADDR         remove watchpoints starting at ADDR
+  bl, breakpoints      list all of the above
+  s, step [N]          run N instructions (default 1)
+  n, next              step, but run a CALL all the way through
+  c, continue          run until a breakpoint or the program stops
+  r, regs              show V0-VF, I, PC, timers and stack depth
+  set NAME VALUE       set v0-vf, i, pc, dt or st
+  stack                show the call stack
+  push ADDR / pop      push or pop a return address
+  x, mem ADDR [LEN]    dump memory
+  w, write ADDR BYTE.. write bytes to memory
+  l, list [ADDR]       disassemble around ADDR (default PC)
+  q, quit              stop debugging
+An empty line repeats the last command.";
+
+/// Which RAM accesses a [`Watchpoint`] cares about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    pub fn includes(self, access: Access) -> bool {
+        self == Access::ReadWrite || self == access
+    }
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+            Access::ReadWrite => write!(f, "read/write"),
+        }
+    }
+}
+
+/// Stop when an instruction touches RAM between `start` and `end`
+/// (inclusive). That's FX33, FX55, FX65, DXYN and XO-CHIP's 5XY2, 5XY3 and
+/// F002, not instruction fetches or the stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub access: Access,
+}
+
+/// A watchpoint going off, see `Processor::watch_hit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    /// The instruction that did it
+    pub pc: u16,
+    /// The first watched address it touched
+    pub address: u16,
+    /// Read or Write, never ReadWrite
+    pub access: Access,
+}
+
+#[derive(Debug)]
+enum LogPiece {
+    Text(String),
+    Value(Expression),
+}
+
+/// A command-line debugger, reading commands from stdin.
+///
+/// The program only runs when told to (`step`, `next`, `continue`), and
+/// frames still tick over at the normal rate while it does, so timers and
+/// the display behave as they would under [`Processor::run`].
+#[derive(Debug, Default)]
+pub struct Debugger {
+    // With a condition, if they have one
+    breakpoints: BTreeMap<u16, Option<Expression>>,
+    // `break if`, these fire when they go from false to true so they don't
+    // stop on every instruction after. The Cell is what they were last time.
+    conditions: Vec<(Expression, Cell<bool>)>,
+    logpoints: BTreeMap<u16, Vec<LogPiece>>,
+    last_command: String,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.breakpoints.insert(address, None);
+    }
+
+    /// Only stops at `address` when `condition` is true.
+    pub fn add_conditional_breakpoint(&mut self, address: u16, condition: Expression) {
+        self.breakpoints.insert(address, Some(condition));
+    }
+
+    /// Stops wherever the program is once `condition` becomes true.
+    pub fn add_condition(&mut self, condition: Expression) {
+        self.conditions.push((condition, Cell::new(false)));
+    }
+
+    /// Prints `message` every time the program gets to `address`, with any
+    /// `{expression}` in it replaced by its value.
+    pub fn add_logpoint(&mut self, address: u16, message: &str) -> Result<(), String> {
+        self.logpoints.insert(address, parse_log(message)?);
+        Ok(())
+    }
+
+    /// False if there wasn't one there.
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address).is_some()
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = &u16> {
+        self.breakpoints.keys()
+    }
+
+    /// Take commands until the user quits (or closes the window), then say
+    /// why the program stopped, if it did.
+    pub fn run(&mut self, cpu: &mut Processor) -> ExitReason {
+        println!("Type help for a list of commands.");
+        self.show_location(cpu);
+
+        let stdin = io::stdin();
+        let mut lines = stdin.lock().lines();
+        loop {
+            print!("(chip8) ");
+            io::stdout().flush().ok();
+            let line = match lines.next() {
+                Some(Ok(line)) => line,
+                // End of input, same as quitting
+                _ => break,
+            };
+            let line = if line.trim().is_empty() {
+                self.last_command.clone()
+            } else {
+                line
+            };
+            match self.command(cpu, &line) {
+                Ok(Some(exit)) => return exit,
+                Ok(None) => (),
+                Err(e) => println!("{}", e),
+            }
+            self.last_command = line;
+        }
+        cpu.exit_reason().unwrap_or(ExitReason::Quit)
+    }
+
+    // Some(exit) when it's time to stop debugging
+    fn command(&mut self, cpu: &mut Processor, line: &str) -> Result<Option<ExitReason>, String> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Ok(None),
+        };
+        let args: Vec<&str> = words.collect();
+
+        match command {
+            "h" | "help" => println!("{}", HELP),
+            "b" | "break" if arg(&args, 0)? == "if" => {
+                let condition = Expression::parse(rest(line, 2))?;
+                println!("Stopping when {} (#{})", condition, self.conditions.len());
+                self.add_condition(condition);
+            }
+            "b" | "break" => {
+                let address = parse_address(arg(&args, 0)?)?;
+                match args.get(1) {
+                    Some(&"if") => {
+                        let condition = Expression::parse(rest(line, 3))?;
+                        println!("Breakpoint at {:04x} if {}", address, condition);
+                        self.add_conditional_breakpoint(address, condition);
+                    }
+                    Some(word) => return Err(format!("Expected if, not '{}'", word)),
+                    None => {
+                        println!("Breakpoint at {:04x}", address);
+                        self.add_breakpoint(address);
+                    }
+                }
+            }
+            "watch" | "rwatch" | "awatch" => {
+                let start = parse_address(arg(&args, 0)?)?;
+                let len = match args.get(1) {
+                    Some(n) => parse_address(n)?.max(1),
+                    None => 1,
+                };
+                let access = match command {
+                    "watch" => Access::Write,
+                    "rwatch" => Access::Read,
+                    _ => Access::ReadWrite,
+                };
+                let end = start.saturating_add(len - 1);
+                cpu.add_watchpoint(Watchpoint { start, end, access });
+                println!("Watching {:04x}-{:04x} for {}s", start, end, access);
+            }
+            "unwatch" => {
+                let start = parse_address(arg(&args, 0)?)?;
+                if !cpu.remove_watchpoints(start) {
+                    return Err(format!("No watchpoint at {:04x}", start));
+                }
+            }
+            "log" => {
+                let address = parse_address(arg(&args, 0)?)?;
+                self.add_logpoint(address, rest(line, 2))?;
+            }
+            "d" | "delete" => {
+                let target = arg(&args, 0)?;
+                if let Some(n) = target.strip_prefix('#') {
+                    let n = parse_number(n)?;
+                    if n >= self.conditions.len() {
+                        return Err(format!("No condition #{}", n));
+                    }
+                    self.conditions.remove(n);
+                } else {
+                    let address = parse_address(target)?;
+                    let logpoint = self.logpoints.remove(&address).is_some();
+                    if !self.remove_breakpoint(address) && !logpoint {
+                        return Err(format!("No breakpoint at {:04x}", address));
+                    }
+                }
+            }
+            "bl" | "breakpoints" => {
+                for (address, condition) in &self.breakpoints {
+                    match condition {
+                        Some(condition) => println!("{:04x}  if {}", address, condition),
+                        None => println!("{:04x}  {}", address, describe(cpu, *address)),
+                    }
+                }
+                for (n, (condition, _)) in self.conditions.iter().enumerate() {
+                    println!("#{}    if {}", n, condition);
+                }
+                for watchpoint in cpu.watchpoints() {
+                    println!(
+                        "{:04x}-{:04x} {}",
+                        watchpoint.start, watchpoint.end, watchpoint.access
+                    );
+                }
+                for address in self.logpoints.keys() {
+                    println!("{:04x}  log", address);
+                }
+            }
+            "s" | "step" => {
+                let count = match args.first() {
+                    Some(n) => parse_number(n)?,
+                    None => 1,
+                };
+                for _ in 0..count {
+                    if let Err(exit) = cpu.step_instruction() {
+                        return Ok(stopped(cpu, exit));
+                    }
+                    if let Some(hit) = cpu.watch_hit() {
+                        println!("{}", describe_hit(&hit));
+                        break;
+                    }
+                }
+                self.show_location(cpu);
+            }
+            "n" | "next" => return Ok(self.next(cpu)),
+            "c" | "continue" => return Ok(self.resume(cpu, |_| false)),
+            "r" | "regs" => show_registers(cpu),
+            "set" => {
+                let name = arg(&args, 0)?.to_lowercase();
+                let value = parse_number(arg(&args, 1)?)?;
+                set(cpu, &name, value)?;
+            }
+            "stack" => {
+                for (depth, address) in cpu.stack().entries().iter().enumerate().rev() {
+                    println!("#{:<2} {:04x}", depth, address);
+                }
+            }
+            "push" => {
+                let address = parse_address(arg(&args, 0)?)?;
+                cpu.push_stack(address).map_err(|e| e.to_string())?;
+            }
+            "pop" => {
+                let address = cpu.pop_stack().map_err(|e| e.to_string())?;
+                println!("{:04x}", address);
+            }
+            "x" | "mem" => {
+                let start = parse_number(arg(&args, 0)?)?;
+                let len = match args.get(1) {
+                    Some(n) => parse_number(n)?,
+                    None => DUMP_WIDTH * 4,
+                };
+                dump(cpu.memory(), start, len);
+            }
+            "w" | "write" => {
+                let address = parse_number(arg(&args, 0)?)?;
+                let bytes = args[1..]
+                    .iter()
+                    .map(|b| parse_byte(b))
+                    .collect::<Result<Vec<u8>, String>>()?;
+                cpu.write_memory(address, &bytes)
+                    .map_err(|e| e.to_string())?;
+            }
+            "l" | "list" => {
+                let address = match args.first() {
+                    Some(a) => parse_address(a)?,
+                    None => cpu.program_counter(),
+                };
+                self.list(cpu, address);
+            }
+            "q" | "quit" => return Ok(Some(cpu.exit_reason().unwrap_or(ExitReason::Quit))),
+            _ => return Err(format!("Unknown command '{}', try help", command)),
+        }
+        Ok(None)
+    }
+
+    // Step, unless it's a CALL, in which case run until it returns (or
+    // something else stops us first)
+    fn next(&mut self, cpu: &mut Processor) -> Option<ExitReason> {
+        let pc = cpu.program_counter();
+        if !at_call(cpu) {
+            if let Err(exit) = cpu.step_instruction() {
+                return stopped(cpu, exit);
+            }
+            self.show_location(cpu);
+            return None;
+        }
+        let depth = cpu.stack().depth();
+        self.resume(cpu, |cpu| {
+            cpu.program_counter() == pc.wrapping_add(2) && cpu.stack().depth() <= depth
+        })
+    }
+
+    // Run until a breakpoint or `done` says to stop. Nothing stops it before
+    // the first instruction, otherwise continuing from a breakpoint would go
+    // nowhere, but a logpoint there still prints.
+    fn resume<F: Fn(&Processor) -> bool>(
+        &self,
+        cpu: &mut Processor,
+        done: F,
+    ) -> Option<ExitReason> {
+        let mut starting = true;
+        let mut reason = None;
+        let stop = cpu.run_until(|cpu| {
+            reason = self.check(cpu);
+            if starting {
+                starting = false;
+                reason = None;
+                return false;
+            }
+            reason.is_some() || done(cpu)
+        });
+        match stop {
+            // Closed the window, nothing left to debug
+            Some(ExitReason::Quit) => return Some(ExitReason::Quit),
+            Some(exit) => return stopped(cpu, exit),
+            None => (),
+        }
+        if let Some(reason) = reason {
+            println!("{}", reason);
+        }
+        self.show_location(cpu);
+        None
+    }
+
+    // Called before every instruction while running. Prints any logpoint
+    // and says why to stop, if we should.
+    fn check(&self, cpu: &Processor) -> Option<String> {
+        let pc = cpu.program_counter();
+        if let Some(pieces) = self.logpoints.get(&pc) {
+            println!("[{:04x}] {}", pc, format_log(pieces, cpu));
+        }
+
+        let mut reason = match self.breakpoints.get(&pc) {
+            Some(None) => Some(format!("Breakpoint at {:04x}", pc)),
+            Some(Some(condition)) if condition.is_true(cpu) => {
+                Some(format!("Breakpoint at {:04x}, {}", pc, condition))
+            }
+            _ => None,
+        };
+        if let Some(hit) = cpu.watch_hit() {
+            reason = Some(describe_hit(&hit));
+        }
+
+        // Kept up to date even when something else is stopping us, so they
+        // don't go off late
+        for (condition, was_true) in &self.conditions {
+            let is_true = condition.is_true(cpu);
+            if is_true && !was_true.get() && reason.is_none() {
+                reason = Some(format!("{} is now true", condition));
+            }
+            was_true.set(is_true);
+        }
+        reason
+    }
+
+    fn show_location(&self, cpu: &Processor) {
+        let pc = cpu.program_counter();
+        println!("=> {:04x}  {}", pc, describe(cpu, pc));
+        if cpu.waiting_for_key() {
+            println!("   (waiting for a key)");
+        }
+    }
+
+    fn list(&self, cpu: &Processor, around: u16) {
+        let start = around.saturating_sub(LIST_BEFORE * 2);
+        let pc = cpu.program_counter();
+        for i in 0..=(LIST_BEFORE + LIST_AFTER) {
+            let address = start + i * 2;
+            if address as usize + 1 >= cpu.memory().len() {
+                break;
+            }
+            let marker = if address == pc { "=>" } else { "  " };
+            let breakpoint = if self.breakpoints.contains_key(&address) {
+                '*'
+            } else if self.logpoints.contains_key(&address) {
+                '~'
+            } else {
+                ' '
+            };
+            println!(
+                "{}{}{:04x}  {}",
+                marker,
+                breakpoint,
+                address,
+                describe(cpu, address)
+            );
+        }
+    }
+}
+
+// Whether the instruction at the PC is a CALL, for stepping over it
+fn at_call(cpu: &Processor) -> bool {
+    let pc = cpu.program_counter() as usize;
+    match cpu.memory().get(pc..pc + 2) {
+        Some(&[op1, op2]) => matches!(
+            Instruction::decode((op1 as u16) << 8 | op2 as u16),
+            Some(Instruction::Call(_))
+        ),
+        _ => false,
+    }
+}
+
+// The program stopped by itself, say why but stay in the debugger so it can
+// still be poked at
+fn stopped(cpu: &Processor, exit: ExitReason) -> Option<ExitReason> {
+    match exit {
+        ExitReason::Quit => Some(exit),
+        _ => {
+            println!("Program stopped: {}", exit);
+            println!(
+                "=> {:04x}  {}",
+                cpu.program_counter(),
+                describe(cpu, cpu.program_counter())
+            );
+            None
+        }
+    }
+}
+
+// Raw bytes and the instruction they make
+fn describe(cpu: &Processor, address: u16) -> String {
+    let memory = cpu.memory();
+    match (
+        memory.get(address as usize),
+        memory.get(address as usize + 1),
+    ) {
+        (Some(op1), Some(op2)) => format!(
+            "{:02x} {:02x}  {}",
+            op1,
+            op2,
+            cpu.disassemble(address).unwrap_or_default()
+        ),
+        _ => "(out of memory)".to_string(),
+    }
+}
+
+fn describe_hit(hit: &WatchHit) -> String {
+    let access = match hit.access {
+        Access::Read => "read from",
+        _ => "write to",
+    };
+    format!(
+        "Watchpoint: {} {:04x} at {:04x}",
+        access, hit.address, hit.pc
+    )
+}
+
+// Text with {expression}s in it
+fn parse_log(message: &str) -> Result<Vec<LogPiece>, String> {
+    let mut pieces = Vec::new();
+    let mut rest = message;
+    while let Some(open) = rest.find('{') {
+        if open > 0 {
+            pieces.push(LogPiece::Text(rest[..open].to_string()));
+        }
+        let close = rest[open..]
+            .find('}')
+            .ok_or_else(|| "Missing '}' in log message".to_string())?;
+        let expression = Expression::parse(&rest[open + 1..open + close])?;
+        pieces.push(LogPiece::Value(expression));
+        rest = &rest[open + close + 1..];
+    }
+    if !rest.is_empty() {
+        pieces.push(LogPiece::Text(rest.to_string()));
+    }
+    Ok(pieces)
+}
+
+fn format_log(pieces: &[LogPiece], cpu: &Processor) -> String {
+    pieces
+        .iter()
+        .map(|piece| match piece {
+            LogPiece::Text(text) => text.clone(),
+            LogPiece::Value(expression) => format!("{:x}", expression.eval(cpu)),
+        })
+        .collect()
+}
+
+fn show_registers(cpu: &Processor) {
+    for (row, registers) in cpu.registers().chunks(8).enumerate() {
+        let line: Vec<String> = registers
+            .iter()
+            .enumerate()
+            .map(|(i, v)| format!("V{:X}={:02x}", row * 8 + i, v))
+            .collect();
+        println!("{}", line.join(" "));
+    }
+    println!(
+        "I={:04x} PC={:04x} DT={:02x} ST={:02x} SP={}",
+        cpu.reg_i(),
+        cpu.program_counter(),
+        cpu.delay_timer(),
+        cpu.sound_timer(),
+        cpu.stack().depth()
+    );
+}
+
+fn set(cpu: &mut Processor, name: &str, value: usize) -> Result<(), String> {
+    match name {
+        "i" => cpu.set_reg_i(value as u16),
+        "pc" => cpu.set_program_counter(value as u16),
+        "dt" => cpu.set_delay_timer(value as u8),
+        "st" => cpu.set_sound_timer(value as u8),
+        _ => {
+            let register = name
+                .strip_prefix('v')
+                .and_then(|r| usize::from_str_radix(r, 16).ok())
+                .filter(|&r| r < 16)
+                .ok_or_else(|| format!("Unknown register '{}'", name))?;
+            cpu.set_register(register, value as u8);
+        }
+    }
+    Ok(())
+}
+
+fn dump(memory: &[u8], start: usize, len: usize) {
+    let end = (start + len).min(memory.len());
+    if start >= end {
+        return;
+    }
+    for (row, bytes) in memory[start..end].chunks(DUMP_WIDTH).enumerate() {
+        let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        println!("{:04x}: {}", start + row * DUMP_WIDTH, hex.join(" "));
+    }
+}
+
+// Everything after the first `words` words of the line
+fn rest(line: &str, words: usize) -> &str {
+    let mut rest = line.trim_start();
+    for _ in 0..words {
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        rest = rest[end..].trim_start();
+    }
+    rest
+}
+
+fn arg<'a>(args: &[&'a str], i: usize) -> Result<&'a str, String> {
+    args.get(i)
+        .copied()
+        .ok_or_else(|| "Missing argument, try help".to_string())
+}
+
+fn parse_number(s: &str) -> Result<usize, String> {
+    let hex = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    usize::from_str_radix(hex, 16).map_err(|_| format!("'{}' isn't a hex number", s))
+}
+
+fn parse_address(s: &str) -> Result<u16, String> {
+    let n = parse_number(s)?;
+    if n > 0xFFFF {
+        return Err(format!("'{}' is too big for an address", s));
+    }
+    Ok(n as u16)
+}
+
+fn parse_byte(s: &str) -> Result<u8, String> {
+    let n = parse_number(s)?;
+    if n > 0xFF {
+        return Err(format!("'{}' is too big for a byte", s));
+    }
+    Ok(n as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{at_call, Access, Debugger, WatchHit, Watchpoint};
+    use crate::testing::processor;
+
+    const SOURCE: &str = "
+        main:
+            LD V0, 5
+            CALL sub
+            LD V2, 1
+            JP main
+        sub:
+            ADD V1, 1
+            ADD V1, 1
+            RET
+        ";
+
+    #[test]
+    fn commands() {
+        let mut debugger = Debugger::new();
+        let mut cpu = processor(SOURCE);
+        let mut ok = |line: &str| debugger.command(&mut cpu, line).unwrap();
+
+        assert_eq!(ok("break 204"), None);
+        ok("b 0x206 if v1 == 2 && i > 300");
+        ok("break if v2 != 0");
+        ok("watch 300 4");
+        ok("rwatch 400");
+        ok("set v3 2a");
+        ok("set pc 202");
+        ok("w 300 01 ff");
+
+        assert_eq!(debugger.breakpoints().collect::<Vec<_>>(), [&0x204, &0x206]);
+        assert!(debugger.breakpoints[&0x206].is_some());
+        assert_eq!(debugger.conditions.len(), 1);
+        assert_eq!(cpu.registers()[3], 0x2a);
+        assert_eq!(cpu.program_counter(), 0x202);
+        assert_eq!(&cpu.memory()[0x300..0x302], &[0x01, 0xff]);
+        let watchpoints = cpu.watchpoints();
+        assert_eq!((watchpoints[0].start, watchpoints[0].end), (0x300, 0x303));
+        assert_eq!(watchpoints[1].access, Access::Read);
+
+        let mut ok = |line: &str| debugger.command(&mut cpu, line).unwrap();
+        ok("delete 204");
+        ok("delete #0");
+        ok("unwatch 300");
+        assert_eq!(debugger.breakpoints().collect::<Vec<_>>(), [&0x206]);
+        assert!(debugger.conditions.is_empty());
+        assert_eq!(cpu.watchpoints().len(), 1);
+    }
+
+    #[test]
+    fn bad_commands() {
+        let mut debugger = Debugger::new();
+        let mut cpu = processor(SOURCE);
+        for line in &[
+            "frobnicate",
+            "break",
+            "break zz",
+            "break 10000",
+            "break 204 unless v0",
+            "break if v0 ==",
+            "delete 204",
+            "delete #3",
+            "set vg 1",
+            "w 300 100",
+            "log 204 {v0",
+        ] {
+            assert!(
+                debugger.command(&mut cpu, line).is_err(),
+                "{} should fail",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn next_runs_a_call_all_the_way_through() {
+        let mut debugger = Debugger::new();
+        let mut cpu = processor(SOURCE);
+        debugger.command(&mut cpu, "next").unwrap();
+        assert!(at_call(&cpu));
+        debugger.command(&mut cpu, "next").unwrap();
+        assert_eq!(cpu.program_counter(), 0x204);
+        assert_eq!(cpu.registers()[1], 2);
+        assert_eq!(cpu.stack().depth(), 0);
+        // Not a CALL, so just one step
+        debugger.command(&mut cpu, "n").unwrap();
+        assert_eq!(cpu.program_counter(), 0x206);
+    }
+
+    #[test]
+    fn continue_stops_at_a_breakpoint() {
+        let mut debugger = Debugger::new();
+        let mut cpu = processor(SOURCE);
+        debugger.command(&mut cpu, "break 20a if v1 == 1").unwrap();
+        debugger.command(&mut cpu, "c").unwrap();
+        assert_eq!(cpu.program_counter(), 0x20a);
+        assert_eq!(cpu.registers()[1], 1);
+
+        // And carries on from it, even with a condition that was already
+        // true to begin with
+        debugger.command(&mut cpu, "break 20a").unwrap();
+        debugger.command(&mut cpu, "break if v0 == 5").unwrap();
+        debugger.command(&mut cpu, "c").unwrap();
+        assert_eq!(cpu.program_counter(), 0x20a);
+        assert_eq!(cpu.registers()[1], 3);
+    }
+
+    // Breaking part way through a frame doesn't finish it, and carrying on
+    // finishes the rest of it
+    #[test]
+    fn run_until_stops_mid_frame() {
+        let mut cpu = processor("LD V0, 5\nLD DT, V0\nloop:\nADD V1, 1\nJP loop");
+        cpu.set_instructions_per_frame(10);
+        assert_eq!(cpu.run_until(|cpu| cpu.registers()[1] == 3), None);
+        assert_eq!(cpu.delay_timer(), 5);
+        // 7 instructions in, so the frame ends after 3 more and the 4th
+        // check sees the timer tick
+        let mut steps = 0;
+        cpu.run_until(|cpu| {
+            steps += 1;
+            cpu.delay_timer() == 4
+        });
+        assert_eq!(steps, 4);
+    }
+
+    #[test]
+    fn watchpoints_go_off() {
+        let mut cpu = processor(
+            "
+            LD I, 0x300
+            LD V0, [I]
+            LD I, 0x2fe
+            LD [I], V2
+            LD B, V0
+            ",
+        );
+        cpu.add_watchpoint(Watchpoint {
+            start: 0x300,
+            end: 0x303,
+            access: Access::Write,
+        });
+        cpu.add_watchpoint(Watchpoint {
+            start: 0x300,
+            end: 0x300,
+            access: Access::Read,
+        });
+        let mut hits = Vec::new();
+        for _ in 0..5 {
+            cpu.step().unwrap();
+            hits.push(cpu.watch_hit());
+        }
+        let hit = |pc, address, access| {
+            Some(WatchHit {
+                pc,
+                address,
+                access,
+            })
+        };
+        assert_eq!(
+            hits,
+            [
+                None,
+                hit(0x202, 0x300, Access::Read),
+                None,
+                // 0x2fe-0x2ff aren't watched, 0x300 is
+                hit(0x206, 0x300, Access::Write),
+                // I moved on to 0x301
+                hit(0x208, 0x301, Access::Write),
+            ]
+        );
+
+        // Gone once removed
+        assert!(cpu.remove_watchpoints(0x300));
+        assert!(!cpu.remove_watchpoints(0x300));
+        assert!(cpu.watchpoints().is_empty());
+    }
+}
@@ -0,0 +1,867 @@
+//! A Debug Adapter Protocol server, for debugging from editors.
+//!
+//! Speaks DAP over any reader/writer pair, in practice stdio or a TCP
+//! connection. The `launch` request takes:
+//!
+//! - `program`: the ROM to run
+//! - `symbols`: a [`SymbolMap`] file, for breakpoints by source line
+//!   (defaults to the ROM's path with a `.sym` extension, if there is one)
+//! - `stopOnEntry`: stop before the first instruction
+//! - `quirks`: a [`Quirks`] preset name
+//!
+//! Breakpoints can be set by line (through the symbol map) or by address
+//! with instruction breakpoints. The registers show up as variables, and
+//! `I` and `PC` carry memory references for the editor's memory view.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use serde_json::{json, Value};
+
+use super::at_call;
+use super::expression::Expression;
+use super::symbols::SymbolMap;
+use crate::fault::ExitReason;
+use crate::processor::Processor;
+use crate::quirks::Quirks;
+use crate::rom::Rom;
+
+const THREAD_ID: i64 = 1;
+
+const REGISTERS_REFERENCE: i64 = 1;
+const STACK_REFERENCE: i64 = 2;
+
+// Where running should stop, besides breakpoints
+#[derive(Debug, Clone, Copy)]
+enum Target {
+    Continue,
+    // Back at the instruction after a CALL
+    Over { pc: u16, depth: usize },
+    // Out of the current subroutine
+    Out { depth: usize },
+}
+
+impl Target {
+    fn reached(self, cpu: &Processor) -> bool {
+        match self {
+            Target::Continue => false,
+            Target::Over { pc, depth } => {
+                cpu.program_counter() == pc.wrapping_add(2) && cpu.stack().depth() <= depth
+            }
+            Target::Out { depth } => cpu.stack().depth() < depth,
+        }
+    }
+}
+
+/// A debug adapter for one editor session.
+pub struct DapServer<W: Write> {
+    output: W,
+    seq: i64,
+    requests: Receiver<Value>,
+    // Requests that turned up while the program was running
+    pending: VecDeque<Value>,
+    symbols: SymbolMap,
+    source_root: PathBuf,
+    // Addresses, by the source file they were set in
+    line_breakpoints: BTreeMap<String, Vec<u16>>,
+    instruction_breakpoints: BTreeSet<u16>,
+    breakpoints: BTreeSet<u16>,
+    launched: bool,
+    configured: bool,
+    stop_on_entry: bool,
+    running: Option<Target>,
+}
+
+impl<W: Write> DapServer<W> {
+    /// Requests are read from `input` on a thread of their own, so they can
+    /// still arrive (pause, say) while the program runs.
+    pub fn new<R: BufRead + Send + 'static>(input: R, output: W) -> DapServer<W> {
+        let (sender, requests) = mpsc::channel();
+        thread::spawn(move || {
+            let mut input = input;
+            while let Ok(Some(message)) = read_message(&mut input) {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+        DapServer {
+            output,
+            seq: 0,
+            requests,
+            pending: VecDeque::new(),
+            symbols: SymbolMap::new(),
+            source_root: PathBuf::new(),
+            line_breakpoints: BTreeMap::new(),
+            instruction_breakpoints: BTreeSet::new(),
+            breakpoints: BTreeSet::new(),
+            launched: false,
+            configured: false,
+            stop_on_entry: false,
+            running: None,
+        }
+    }
+
+    /// Serve requests until the editor disconnects.
+    pub fn run(&mut self, cpu: &mut Processor) -> io::Result<ExitReason> {
+        loop {
+            let request = match (self.pending.pop_front(), self.running) {
+                (Some(request), _) => request,
+                (None, Some(target)) => {
+                    self.run_for_a_while(cpu, target)?;
+                    continue;
+                }
+                (None, None) => match self.requests.recv() {
+                    Ok(request) => request,
+                    // Editor went away
+                    Err(_) => break,
+                },
+            };
+            if !self.handle(cpu, &request)? {
+                break;
+            }
+        }
+        Ok(cpu.exit_reason().unwrap_or(ExitReason::Quit))
+    }
+
+    // Run until something stops us: a breakpoint, the target, the program
+    // ending, or a request coming in
+    fn run_for_a_while(&mut self, cpu: &mut Processor, target: Target) -> io::Result<()> {
+        let breakpoints = &self.breakpoints;
+        let requests = &self.requests;
+        let mut request = None;
+        let exit = cpu.run_until(|cpu| {
+            if target.reached(cpu) || breakpoints.contains(&cpu.program_counter()) {
+                return true;
+            }
+            // Once a frame, like the keyboard
+            if cpu.frame_progress() == 0 {
+                request = requests.try_recv().ok();
+            }
+            request.is_some()
+        });
+
+        if let Some(exit) = exit {
+            self.running = None;
+            return self.program_stopped(exit);
+        }
+        match request {
+            // Deal with it and carry on, unless it was a pause
+            Some(request) => self.pending.push_back(request),
+            None => {
+                self.running = None;
+                let reason = if self.breakpoints.contains(&cpu.program_counter()) {
+                    "breakpoint"
+                } else {
+                    "step"
+                };
+                self.stopped(reason, None)?;
+            }
+        }
+        Ok(())
+    }
+
+    // False once it's time to stop serving
+    fn handle(&mut self, cpu: &mut Processor, request: &Value) -> io::Result<bool> {
+        let command = request["command"].as_str().unwrap_or_default();
+        let args = &request["arguments"];
+        let result = match command {
+            "initialize" => {
+                self.respond(request, Ok(capabilities()))?;
+                self.event("initialized", json!({}))?;
+                return Ok(true);
+            }
+            "launch" => self.launch(cpu, args),
+            "setBreakpoints" => Ok(self.set_breakpoints(args)),
+            "setInstructionBreakpoints" => Ok(self.set_instruction_breakpoints(args)),
+            "setExceptionBreakpoints" => Ok(json!({ "breakpoints": [] })),
+            "configurationDone" => {
+                self.configured = true;
+                Ok(json!({}))
+            }
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "CHIP-8" }] })),
+            "stackTrace" => Ok(self.stack_trace(cpu)),
+            "scopes" => Ok(scopes()),
+            "variables" => Ok(variables(cpu, args)),
+            "setVariable" => set_variable(cpu, args),
+            "evaluate" => evaluate(cpu, args),
+            "readMemory" => read_memory(cpu, args),
+            "writeMemory" => write_memory(cpu, args),
+            "continue" => {
+                self.resume(cpu, Target::Continue)?;
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "next" => {
+                let pc = cpu.program_counter();
+                if at_call(cpu) {
+                    let depth = cpu.stack().depth();
+                    self.resume(cpu, Target::Over { pc, depth })?;
+                } else {
+                    self.step(cpu)?;
+                }
+                Ok(json!({}))
+            }
+            "stepIn" => {
+                self.step(cpu)?;
+                Ok(json!({}))
+            }
+            "stepOut" => {
+                let depth = cpu.stack().depth();
+                self.resume(cpu, Target::Out { depth })?;
+                Ok(json!({}))
+            }
+            "pause" => {
+                if self.running.take().is_some() {
+                    self.respond(request, Ok(json!({})))?;
+                    self.stopped("pause", None)?;
+                    return Ok(true);
+                }
+                Ok(json!({}))
+            }
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(json!({})))?;
+                return Ok(false);
+            }
+            _ => Err(format!("{} isn't supported", command)),
+        };
+        self.respond(request, result)?;
+
+        // Everything's set up, off we go
+        let starting = command == "launch" || command == "configurationDone";
+        if starting && self.launched && self.configured {
+            if self.stop_on_entry {
+                self.stopped("entry", None)?;
+            } else {
+                self.running = Some(Target::Continue);
+            }
+        }
+        Ok(true)
+    }
+
+    fn launch(&mut self, cpu: &mut Processor, args: &Value) -> Result<Value, String> {
+        let program = args["program"].as_str().ok_or("launch needs a program")?;
+        if let Some(name) = args["quirks"].as_str() {
+            let quirks =
+                Quirks::preset(name).ok_or_else(|| format!("Unknown quirks '{}'", name))?;
+            cpu.set_quirks(quirks);
+        }
+        let rom = Rom::from_path(program).map_err(|e| format!("{}: {}", program, e))?;
+        cpu.reset();
+        cpu.load_rom(&rom)
+            .map_err(|e| format!("{}: {}", program, e))?;
+
+        let symbols = match args["symbols"].as_str() {
+            Some(path) => Some(PathBuf::from(path)),
+            None => Some(Path::new(program).with_extension("sym")).filter(|path| path.exists()),
+        };
+        self.symbols = match &symbols {
+            Some(path) => {
+                SymbolMap::load_from_path(path).map_err(|e| format!("{}: {}", path.display(), e))?
+            }
+            None => SymbolMap::new(),
+        };
+        // Source files in the map are relative to the map itself
+        self.source_root = symbols
+            .as_ref()
+            .and_then(|path| path.parent())
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        self.launched = true;
+        Ok(json!({}))
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Value {
+        let path = args["source"]["path"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        let lines = args["breakpoints"]
+            .as_array()
+            .map(|breakpoints| {
+                breakpoints
+                    .iter()
+                    .filter_map(|b| b["line"].as_u64())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        let mut addresses = Vec::new();
+        let breakpoints: Vec<Value> = lines
+            .iter()
+            .map(|&line| {
+                let found = self.symbols.addresses_for(&path, line as u32);
+                addresses.extend(&found);
+                match found.first() {
+                    Some(address) => json!({
+                        "verified": true,
+                        "line": line,
+                        "instructionReference": format!("0x{:04x}", address),
+                    }),
+                    None => json!({
+                        "verified": false,
+                        "line": line,
+                        "message": "No instructions on this line",
+                    }),
+                }
+            })
+            .collect();
+
+        self.line_breakpoints.insert(path, addresses);
+        self.rebuild_breakpoints();
+        json!({ "breakpoints": breakpoints })
+    }
+
+    fn set_instruction_breakpoints(&mut self, args: &Value) -> Value {
+        self.instruction_breakpoints.clear();
+        let mut results = Vec::new();
+        for breakpoint in args["breakpoints"].as_array().into_iter().flatten() {
+            let address = breakpoint["instructionReference"]
+                .as_str()
+                .and_then(parse_reference)
+                .map(|address| address + breakpoint["offset"].as_i64().unwrap_or(0));
+            match address.filter(|a| (0..=0xFFFF).contains(a)) {
+                Some(address) => {
+                    self.instruction_breakpoints.insert(address as u16);
+                    results.push(json!({ "verified": true }));
+                }
+                None => results.push(json!({ "verified": false, "message": "Bad address" })),
+            }
+        }
+        self.rebuild_breakpoints();
+        json!({ "breakpoints": results })
+    }
+
+    fn rebuild_breakpoints(&mut self) {
+        self.breakpoints = self
+            .line_breakpoints
+            .values()
+            .flatten()
+            .chain(&self.instruction_breakpoints)
+            .copied()
+            .collect();
+    }
+
+    fn stack_trace(&self, cpu: &Processor) -> Value {
+        // The current instruction, then each CALL on the way here
+        let pc = cpu.program_counter();
+        let calls = cpu
+            .stack()
+            .entries()
+            .iter()
+            .rev()
+            .map(|&return_address| return_address.wrapping_sub(2));
+        let frames: Vec<Value> = std::iter::once(pc)
+            .chain(calls)
+            .enumerate()
+            .map(|(id, address)| self.frame(id, address))
+            .collect();
+        json!({ "stackFrames": frames, "totalFrames": frames.len() })
+    }
+
+    fn frame(&self, id: usize, address: u16) -> Value {
+        let mut frame = json!({
+            "id": id,
+            "name": format!("{:04x}", address),
+            "line": 0,
+            "column": 0,
+            "instructionPointerReference": format!("0x{:04x}", address),
+        });
+        if let Some(source) = self.symbols.line_for(address) {
+            let path = self.source_root.join(&source.file);
+            let name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            frame["source"] = json!({ "name": name, "path": path.to_string_lossy() });
+            frame["line"] = json!(source.line);
+            frame["column"] = json!(1);
+        }
+        frame
+    }
+
+    // Continuing always runs the first instruction, otherwise it would go
+    // nowhere from a breakpoint
+    fn resume(&mut self, cpu: &mut Processor, target: Target) -> io::Result<()> {
+        match cpu.step_instruction() {
+            Ok(()) if target.reached(cpu) => self.stopped_later("step"),
+            Ok(()) if self.breakpoints.contains(&cpu.program_counter()) => {
+                self.stopped_later("breakpoint")
+            }
+            Ok(()) => self.running = Some(target),
+            Err(exit) => self.pending_exit(exit),
+        }
+        Ok(())
+    }
+
+    fn step(&mut self, cpu: &mut Processor) -> io::Result<()> {
+        match cpu.step_instruction() {
+            Ok(()) => self.stopped_later("step"),
+            Err(exit) => self.pending_exit(exit),
+        }
+        Ok(())
+    }
+
+    // Events for things that happened while handling a request have to
+    // wait until after its response, so they go through a fake request
+    fn stopped_later(&mut self, reason: &str) {
+        self.pending
+            .push_front(json!({ "command": "_stopped", "reason": reason }));
+    }
+
+    fn pending_exit(&mut self, exit: ExitReason) {
+        let exit = match exit {
+            ExitReason::Fault { .. } => json!({ "fault": exit.to_string() }),
+            ExitReason::Quit => json!({ "quit": true }),
+            ExitReason::ProgramExit => json!({}),
+        };
+        self.pending
+            .push_front(json!({ "command": "_exited", "exit": exit }));
+    }
+
+    fn program_stopped(&mut self, exit: ExitReason) -> io::Result<()> {
+        match exit {
+            // Stay stopped so the state that caused it can be looked at
+            ExitReason::Fault { .. } => self.stopped("exception", Some(exit.to_string())),
+            ExitReason::ProgramExit => {
+                self.event("exited", json!({ "exitCode": exit.exit_code() }))?;
+                self.event("terminated", json!({}))
+            }
+            ExitReason::Quit => self.event("terminated", json!({})),
+        }
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<String>) -> io::Result<()> {
+        let mut body = json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        });
+        if let Some(text) = text {
+            body["text"] = json!(text);
+        }
+        self.event("stopped", body)
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) -> io::Result<()> {
+        // Our own deferred events, see stopped_later
+        match request["command"].as_str() {
+            Some("_stopped") => {
+                let reason = request["reason"].as_str().unwrap_or("step").to_string();
+                return self.stopped(&reason, None);
+            }
+            Some("_exited") => {
+                let exit = &request["exit"];
+                return if let Some(fault) = exit["fault"].as_str() {
+                    self.stopped("exception", Some(fault.to_string()))
+                } else if exit["quit"].as_bool() == Some(true) {
+                    self.event("terminated", json!({}))
+                } else {
+                    self.event("exited", json!({ "exitCode": 0 }))?;
+                    self.event("terminated", json!({}))
+                };
+            }
+            _ => (),
+        }
+
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response)
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        write!(
+            self.output,
+            "Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+        self.output.flush()
+    }
+}
+
+fn capabilities() -> Value {
+    json!({
+        "supportsConfigurationDoneRequest": true,
+        "supportsInstructionBreakpoints": true,
+        "supportsSetVariable": true,
+        "supportsEvaluateForHovers": true,
+        "supportsReadMemoryRequest": true,
+        "supportsWriteMemoryRequest": true,
+        "supportsTerminateRequest": true,
+    })
+}
+
+fn scopes() -> Value {
+    json!({ "scopes": [
+        { "name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false },
+        { "name": "Stack", "variablesReference": STACK_REFERENCE, "expensive": false },
+    ]})
+}
+
+fn variables(cpu: &Processor, args: &Value) -> Value {
+    let byte = |name: String, value: u8| json!({ "name": name, "value": format!("0x{:02x}", value), "variablesReference": 0 });
+    let address = |name: &str, value: u16| {
+        json!({
+            "name": name,
+            "value": format!("0x{:04x}", value),
+            "variablesReference": 0,
+            "memoryReference": format!("0x{:04x}", value),
+        })
+    };
+
+    let variables: Vec<Value> = match args["variablesReference"].as_i64() {
+        Some(REGISTERS_REFERENCE) => {
+            let mut variables: Vec<Value> = cpu
+                .registers()
+                .iter()
+                .enumerate()
+                .map(|(i, &v)| byte(format!("V{:X}", i), v))
+                .collect();
+            variables.push(address("I", cpu.reg_i()));
+            variables.push(address("PC", cpu.program_counter()));
+            variables.push(byte("SP".to_string(), cpu.stack().depth() as u8));
+            variables.push(byte("DT".to_string(), cpu.delay_timer()));
+            variables.push(byte("ST".to_string(), cpu.sound_timer()));
+            variables
+        }
+        Some(STACK_REFERENCE) => cpu
+            .stack()
+            .entries()
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(depth, &return_address)| address(&format!("#{}", depth), return_address))
+            .collect(),
+        _ => Vec::new(),
+    };
+    json!({ "variables": variables })
+}
+
+// Anything the debugger's expressions can do, so `v3 + 1` works as well as
+// plain numbers
+fn set_variable(cpu: &mut Processor, args: &Value) -> Result<Value, String> {
+    let name = args["name"].as_str().unwrap_or_default().to_lowercase();
+    let value = Expression::parse(args["value"].as_str().unwrap_or_default())?.eval(cpu);
+    let shown = match name.as_str() {
+        "i" => {
+            cpu.set_reg_i(value as u16);
+            format!("0x{:04x}", value as u16)
+        }
+        "pc" => {
+            cpu.set_program_counter(value as u16);
+            format!("0x{:04x}", value as u16)
+        }
+        "dt" => {
+            cpu.set_delay_timer(value as u8);
+            format!("0x{:02x}", value as u8)
+        }
+        "st" => {
+            cpu.set_sound_timer(value as u8);
+            format!("0x{:02x}", value as u8)
+        }
+        _ => {
+            let register = name
+                .strip_prefix('v')
+                .and_then(|r| usize::from_str_radix(r, 16).ok())
+                .filter(|&r| r < 16)
+                .ok_or_else(|| format!("{} can't be changed", name))?;
+            cpu.set_register(register, value as u8);
+            format!("0x{:02x}", value as u8)
+        }
+    };
+    Ok(json!({ "value": shown }))
+}
+
+fn evaluate(cpu: &Processor, args: &Value) -> Result<Value, String> {
+    let expression = Expression::parse(args["expression"].as_str().unwrap_or_default())?;
+    let value = expression.eval(cpu);
+    Ok(json!({ "result": format!("0x{:x}", value), "variablesReference": 0 }))
+}
+
+fn read_memory(cpu: &Processor, args: &Value) -> Result<Value, String> {
+    let start = memory_address(args)?;
+    let count = args["count"].as_u64().unwrap_or(0) as usize;
+    let memory = cpu.memory();
+    let from = (start.max(0) as usize).min(memory.len());
+    let to = ((start + count as i64).max(0) as usize).min(memory.len());
+    let data = &memory[from..to];
+    Ok(json!({
+        "address": format!("0x{:04x}", start),
+        "data": base64_encode(data),
+        "unreadableBytes": count - data.len(),
+    }))
+}
+
+fn write_memory(cpu: &mut Processor, args: &Value) -> Result<Value, String> {
+    let start = memory_address(args)?;
+    let data = base64_decode(args["data"].as_str().unwrap_or_default())?;
+    if start < 0 {
+        return Err("Address out of range".to_string());
+    }
+    cpu.write_memory(start as usize, &data)
+        .map_err(|e| e.to_string())?;
+    Ok(json!({ "bytesWritten": data.len() }))
+}
+
+fn memory_address(args: &Value) -> Result<i64, String> {
+    let reference = args["memoryReference"].as_str().unwrap_or_default();
+    let base = parse_reference(reference)
+        .ok_or_else(|| format!("Bad memory reference '{}'", reference))?;
+    Ok(base + args["offset"].as_i64().unwrap_or(0))
+}
+
+// We hand out references like "0x0200"
+fn parse_reference(reference: &str) -> Option<i64> {
+    let hex = reference.strip_prefix("0x").unwrap_or(reference);
+    i64::from_str_radix(hex, 16).ok()
+}
+
+// Content-Length header, blank line, then that many bytes of JSON. None at
+// the end of the input.
+fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let mut body = vec![0; length.unwrap_or(0)];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &byte)| {
+            bits | (byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64[(bits >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+fn base64_decode(text: &str) -> Result<Vec<u8>, String> {
+    let mut decoded = Vec::new();
+    let mut bits = 0u32;
+    let mut count = 0;
+    for c in text
+        .bytes()
+        .filter(|&c| c != b'=' && !c.is_ascii_whitespace())
+    {
+        let value = BASE64
+            .iter()
+            .position(|&b| b == c)
+            .ok_or("Bad base64 data")?;
+        bits = bits << 6 | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            decoded.push((bits >> count) as u8);
+        }
+    }
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, BufReader, Cursor, Write};
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::{json, Value};
+
+    use super::{base64_decode, base64_encode, read_message, DapServer};
+    use crate::asm::assemble;
+    use crate::Processor;
+
+    fn message(body: &Value) -> String {
+        let body = body.to_string();
+        format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+    }
+
+    #[test]
+    fn reads_messages() {
+        let input = format!(
+            "{}Content-Type: x\r\nContent-Length: 8\r\n\r\n{{\"a\": 1}}\r\n{}",
+            message(&json!({ "seq": 1 })),
+            message(&json!([]))
+        );
+        let mut input = Cursor::new(input.into_bytes());
+        assert_eq!(read_message(&mut input).unwrap(), Some(json!({ "seq": 1 })));
+        assert_eq!(read_message(&mut input).unwrap(), Some(json!({ "a": 1 })));
+        assert_eq!(read_message(&mut input).unwrap(), Some(json!([])));
+        assert_eq!(read_message(&mut input).unwrap(), None);
+
+        let mut bad = Cursor::new(b"Content-Length: 3\r\n\r\n{{{".to_vec());
+        assert!(read_message(&mut bad).is_err());
+        let mut short = Cursor::new(b"Content-Length: 30\r\n\r\n{}".to_vec());
+        assert!(read_message(&mut short).is_err());
+    }
+
+    #[test]
+    fn base64() {
+        for (data, text) in &[
+            (&b""[..], ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"foob", "Zm9vYg=="),
+            (b"\x00\xff\xfe", "AP/+"),
+        ] {
+            assert_eq!(base64_encode(data), *text);
+            assert_eq!(base64_decode(text).unwrap(), *data);
+        }
+        assert_eq!(base64_decode("Zm 9v\n").unwrap(), b"foo");
+        assert!(base64_decode("Zm9v!").is_err());
+    }
+
+    // Somewhere to write that the test can still read afterwards
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Everything the server sent back for `requests`, numbered in order
+    fn serve(rom: &str, requests: &[Value]) -> Vec<Value> {
+        let path = std::env::temp_dir().join(format!("chippe_rs-dap-{}.ch8", std::process::id()));
+        std::fs::write(&path, assemble(rom).unwrap().bytes()).unwrap();
+
+        let mut input = String::new();
+        let launch = json!({ "program": path, "stopOnEntry": true });
+        let mut all = vec![
+            json!({ "command": "initialize" }),
+            json!({ "command": "launch", "arguments": launch }),
+            json!({ "command": "configurationDone" }),
+        ];
+        all.extend_from_slice(requests);
+        all.push(json!({ "command": "disconnect" }));
+        for (seq, mut request) in all.into_iter().enumerate() {
+            request["seq"] = json!(seq + 1);
+            request["type"] = json!("request");
+            input += &message(&request);
+        }
+
+        let output = Shared::default();
+        let mut cpu = Processor::headless();
+        DapServer::new(Cursor::new(input.into_bytes()), output.clone())
+            .run(&mut cpu)
+            .unwrap();
+        std::fs::remove_file(&path).ok();
+
+        let bytes = output.0.lock().unwrap().clone();
+        let mut reader = BufReader::new(&bytes[..]);
+        std::iter::from_fn(|| read_message(&mut reader).unwrap()).collect()
+    }
+
+    fn response(messages: &[Value], request_seq: u64) -> &Value {
+        messages
+            .iter()
+            .find(|m| m["type"] == "response" && m["request_seq"] == request_seq)
+            .unwrap_or_else(|| panic!("no response to {}", request_seq))
+    }
+
+    fn stops(messages: &[Value]) -> Vec<&str> {
+        messages
+            .iter()
+            .filter(|m| m["event"] == "stopped")
+            .map(|m| m["body"]["reason"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn requests() {
+        let messages = serve(
+            "LD V0, 5\nCALL sub\nLD V2, 1\nEXIT\nsub:\nADD V0, 1\nRET",
+            &[
+                json!({ "command": "setInstructionBreakpoints", "arguments": {
+                    "breakpoints": [{ "instructionReference": "0x0202" }],
+                }}),
+                json!({ "command": "continue" }),
+                json!({ "command": "next" }),
+                json!({ "command": "evaluate", "arguments": { "expression": "v0 + 1" } }),
+                json!({ "command": "setVariable", "arguments": { "name": "V1", "value": "v0" } }),
+                json!({ "command": "writeMemory", "arguments": {
+                    "memoryReference": "0x0300", "data": "AQI=",
+                }}),
+                json!({ "command": "readMemory", "arguments": {
+                    "memoryReference": "0x0300", "offset": 1, "count": 2,
+                }}),
+                json!({ "command": "evaluate", "arguments": { "expression": "v0 == é" } }),
+                json!({ "command": "flyToTheMoon" }),
+            ],
+        );
+
+        // Every message numbered, in order
+        let seqs: Vec<_> = messages
+            .iter()
+            .map(|m| m["seq"].as_u64().unwrap())
+            .collect();
+        assert_eq!(seqs, (1..=messages.len() as u64).collect::<Vec<_>>());
+        assert_eq!(messages[1]["event"], "initialized");
+        assert_eq!(response(&messages, 2)["success"], true);
+        // Stopped on entry, at the breakpoint, then over the CALL
+        assert_eq!(stops(&messages), ["entry", "breakpoint", "step"]);
+
+        assert_eq!(
+            response(&messages, 4)["body"]["breakpoints"][0]["verified"],
+            true
+        );
+        assert_eq!(response(&messages, 7)["body"]["result"], "0x7");
+        assert_eq!(response(&messages, 8)["body"]["value"], "0x06");
+        assert_eq!(response(&messages, 9)["body"]["bytesWritten"], 2);
+        let read = &response(&messages, 10)["body"];
+        assert_eq!(read["address"], "0x0301");
+        assert_eq!(read["data"], base64_encode(&[2, 0]));
+        assert_eq!(response(&messages, 11)["success"], false);
+        assert_eq!(response(&messages, 12)["success"], false);
+        assert_eq!(response(&messages, 13)["success"], true);
+    }
+}
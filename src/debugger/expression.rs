@@ -0,0 +1,362 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::processor::Processor;
+
+/// A little expression over the machine's state, for conditional
+/// breakpoints and logpoints, like `v3 == 10 && i > 300`.
+///
+/// Registers are `v0`-`vf`, `i`, `pc`, `dt`, `st` and `sp` (stack depth),
+/// `[addr]` reads a byte of RAM and numbers are hex, as everywhere else in
+/// the debugger. There's `||`, `&&`, comparisons, `|`, `&`, `+`, `-` and
+/// `!`, with the usual precedence, and true is 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expression {
+    source: String,
+    root: Node,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Register {
+    V(usize),
+    I,
+    Pc,
+    Dt,
+    St,
+    Sp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BitOr,
+    BitAnd,
+    Add,
+    Sub,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Number(i64),
+    Register(Register),
+    Memory(Box<Node>),
+    Not(Box<Node>),
+    Binary(Op, Box<Node>, Box<Node>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Symbol(&'static str),
+}
+
+// Longest first so `<=` isn't read as `<` then `=`
+const SYMBOLS: [&str; 17] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<", ">", "|", "&", "+", "-", "!", "(", ")", "[", "]",
+];
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Expression, String> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser { tokens, next: 0 };
+        let root = parser.or()?;
+        if let Some(token) = parser.peek() {
+            return Err(format!("Unexpected {} in expression", describe(token)));
+        }
+        Ok(Expression {
+            source: source.trim().to_string(),
+            root,
+        })
+    }
+
+    pub fn eval(&self, cpu: &Processor) -> i64 {
+        eval(&self.root, cpu)
+    }
+
+    pub fn is_true(&self, cpu: &Processor) -> bool {
+        self.eval(cpu) != 0
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+fn eval(node: &Node, cpu: &Processor) -> i64 {
+    match node {
+        Node::Number(n) => *n,
+        Node::Register(register) => match register {
+            Register::V(x) => cpu.registers()[*x] as i64,
+            Register::I => cpu.reg_i() as i64,
+            Register::Pc => cpu.program_counter() as i64,
+            Register::Dt => cpu.delay_timer() as i64,
+            Register::St => cpu.sound_timer() as i64,
+            Register::Sp => cpu.stack().depth() as i64,
+        },
+        Node::Memory(address) => {
+            let address = eval(address, cpu);
+            usize::try_from(address)
+                .ok()
+                .and_then(|a| cpu.memory().get(a))
+                .map_or(0, |&b| b as i64)
+        }
+        Node::Not(node) => (eval(node, cpu) == 0) as i64,
+        Node::Binary(op, left, right) => {
+            let left = eval(left, cpu);
+            // Short circuit, so `[i] == 0 && ...` doesn't cost anything
+            match op {
+                Op::Or if left != 0 => return 1,
+                Op::And if left == 0 => return 0,
+                _ => (),
+            }
+            let right = eval(right, cpu);
+            match op {
+                Op::Or | Op::And => (right != 0) as i64,
+                Op::Eq => (left == right) as i64,
+                Op::Ne => (left != right) as i64,
+                Op::Lt => (left < right) as i64,
+                Op::Le => (left <= right) as i64,
+                Op::Gt => (left > right) as i64,
+                Op::Ge => (left >= right) as i64,
+                Op::BitOr => left | right,
+                Op::BitAnd => left & right,
+                Op::Add => left.wrapping_add(right),
+                Op::Sub => left.wrapping_sub(right),
+            }
+        }
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = source.trim_start();
+    while !rest.is_empty() {
+        if let Some(symbol) = SYMBOLS.iter().find(|s| rest.starts_with(*s)) {
+            tokens.push(Token::Symbol(symbol));
+            rest = &rest[symbol.len()..];
+        } else {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            if len == 0 {
+                let c = rest.chars().next().unwrap_or_default();
+                return Err(format!("Unexpected '{}' in expression", c));
+            }
+            tokens.push(Token::Word(rest[..len].to_lowercase()));
+            rest = &rest[len..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Word(word) => format!("'{}'", word),
+        Token::Symbol(symbol) => format!("'{}'", symbol),
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    next: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next)
+    }
+
+    // Consume `symbol` if it's next
+    fn eat(&mut self, symbol: &str) -> bool {
+        match self.peek() {
+            Some(Token::Symbol(s)) if *s == symbol => {
+                self.next += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn binary(
+        &mut self,
+        ops: &[(&str, Op)],
+        operand: fn(&mut Parser) -> Result<Node, String>,
+    ) -> Result<Node, String> {
+        let mut node = operand(self)?;
+        'outer: loop {
+            for &(symbol, op) in ops {
+                if self.eat(symbol) {
+                    node = Node::Binary(op, Box::new(node), Box::new(operand(self)?));
+                    continue 'outer;
+                }
+            }
+            return Ok(node);
+        }
+    }
+
+    fn or(&mut self) -> Result<Node, String> {
+        self.binary(&[("||", Op::Or)], Parser::and)
+    }
+
+    fn and(&mut self) -> Result<Node, String> {
+        self.binary(&[("&&", Op::And)], Parser::comparison)
+    }
+
+    fn comparison(&mut self) -> Result<Node, String> {
+        self.binary(
+            &[
+                ("==", Op::Eq),
+                ("!=", Op::Ne),
+                ("<=", Op::Le),
+                (">=", Op::Ge),
+                ("<", Op::Lt),
+                (">", Op::Gt),
+            ],
+            Parser::bit_or,
+        )
+    }
+
+    fn bit_or(&mut self) -> Result<Node, String> {
+        self.binary(&[("|", Op::BitOr)], Parser::bit_and)
+    }
+
+    fn bit_and(&mut self) -> Result<Node, String> {
+        self.binary(&[("&", Op::BitAnd)], Parser::sum)
+    }
+
+    fn sum(&mut self) -> Result<Node, String> {
+        self.binary(&[("+", Op::Add), ("-", Op::Sub)], Parser::unary)
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        if self.eat("!") {
+            return Ok(Node::Not(Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Node, String> {
+        if self.eat("(") {
+            let node = self.or()?;
+            return self.close(")", node);
+        }
+        if self.eat("[") {
+            let node = self.or()?;
+            return self.close("]", Node::Memory(Box::new(node)));
+        }
+        let word = match self.tokens.get(self.next) {
+            Some(Token::Word(word)) => word.clone(),
+            Some(token) => return Err(format!("Unexpected {} in expression", describe(token))),
+            None => return Err("Expression ends too soon".to_string()),
+        };
+        self.next += 1;
+        Ok(match word.as_str() {
+            "i" => Node::Register(Register::I),
+            "pc" => Node::Register(Register::Pc),
+            "dt" => Node::Register(Register::Dt),
+            "st" => Node::Register(Register::St),
+            "sp" => Node::Register(Register::Sp),
+            _ => match register_number(&word) {
+                Some(x) => Node::Register(Register::V(x)),
+                None => Node::Number(parse_hex(&word)?),
+            },
+        })
+    }
+
+    fn close(&mut self, symbol: &str, node: Node) -> Result<Node, String> {
+        if self.eat(symbol) {
+            Ok(node)
+        } else {
+            Err(format!("Missing '{}' in expression", symbol))
+        }
+    }
+}
+
+fn register_number(word: &str) -> Option<usize> {
+    let digit = word.strip_prefix('v')?;
+    if digit.len() != 1 {
+        return None;
+    }
+    usize::from_str_radix(digit, 16).ok()
+}
+
+fn parse_hex(word: &str) -> Result<i64, String> {
+    let hex = word.strip_prefix("0x").unwrap_or(word);
+    i64::from_str_radix(hex, 16).map_err(|_| format!("'{}' isn't a register or hex number", word))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Expression;
+    use crate::testing::processor;
+    use crate::Processor;
+
+    fn eval(source: &str, cpu: &Processor) -> i64 {
+        Expression::parse(source).unwrap().eval(cpu)
+    }
+
+    #[test]
+    fn precedence() {
+        let cpu = processor("LD V0, 1");
+        assert_eq!(eval("1 + 2 & 6", &cpu), 2);
+        assert_eq!(eval("1 | 2 == 3", &cpu), 1);
+        assert_eq!(eval("(1 | 2) == 3", &cpu), 1);
+        assert_eq!(eval("1 == 1 && 2 == 3 || 4 > 3", &cpu), 1);
+        assert_eq!(eval("!0 + 1", &cpu), 2);
+        assert_eq!(eval("!(1 - 1)", &cpu), 1);
+        assert_eq!(eval("5 - 3 - 1", &cpu), 1);
+    }
+
+    #[test]
+    fn numbers_registers_and_memory() {
+        let mut cpu = processor("LD V0, 1");
+        cpu.set_register(0xa, 0x2a);
+        cpu.set_reg_i(0x300);
+        cpu.write_memory(0x300, &[0x7f]).unwrap();
+        assert_eq!(eval("ff", &cpu), 0xff);
+        assert_eq!(eval("0x10", &cpu), 0x10);
+        assert_eq!(eval("VA", &cpu), 0x2a);
+        assert_eq!(eval("pc", &cpu), 0x200);
+        assert_eq!(eval("[i]", &cpu), 0x7f);
+        assert_eq!(eval("[2ff + 1] == 7f", &cpu), 1);
+        // Off the end of RAM reads as 0
+        assert_eq!(eval("[10000]", &cpu), 0);
+        assert_eq!(eval("[0 - 1]", &cpu), 0);
+        // Whitespace or not, and it keeps what it was given
+        let expression = Expression::parse("  v3==10&&i>300 ").unwrap();
+        assert_eq!(expression.to_string(), "v3==10&&i>300");
+    }
+
+    #[test]
+    fn errors() {
+        for source in &[
+            "",
+            "v3 ==",
+            "(v3",
+            "[i",
+            "v3 == 10)",
+            "vg",
+            "zz",
+            "v3 = 1",
+            "v3 == é",
+            "é",
+            "v3 == 1 ☃",
+        ] {
+            assert!(Expression::parse(source).is_err(), "{:?}", source);
+        }
+        assert_eq!(
+            Expression::parse("v3 == é"),
+            Err("Unexpected 'é' in expression".to_string())
+        );
+    }
+}
@@ -0,0 +1,634 @@
+//! A GDB remote serial protocol stub, so `target remote :PORT` (or anything
+//! else that speaks RSP) can drive the interpreter.
+//!
+//! Registers, in `g`/`p` order: V0-VF (8 bits each), I and PC (16 bits,
+//! big endian like everything else on CHIP-8), then SP (stack depth), DT
+//! and ST (8 bits each). A target description with those names is served
+//! through `qXfer:features:read`. Memory is the whole of RAM, 4 KB or 64 KB
+//! for XO-CHIP.
+
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use crate::fault::{ExitReason, Fault};
+use crate::processor::Processor;
+
+const INTERRUPT: u8 = 0x03;
+const PACKET_SIZE: usize = 0x1000;
+
+// Register numbers
+const REG_I: usize = 16;
+const REG_PC: usize = 17;
+const REG_SP: usize = 18;
+const REG_DT: usize = 19;
+const REG_ST: usize = 20;
+const REGISTER_COUNT: usize = 21;
+
+// Signals for stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.chip8.core">
+    <reg name="v0" bitsize="8" regnum="0"/>
+    <reg name="v1" bitsize="8"/>
+    <reg name="v2" bitsize="8"/>
+    <reg name="v3" bitsize="8"/>
+    <reg name="v4" bitsize="8"/>
+    <reg name="v5" bitsize="8"/>
+    <reg name="v6" bitsize="8"/>
+    <reg name="v7" bitsize="8"/>
+    <reg name="v8" bitsize="8"/>
+    <reg name="v9" bitsize="8"/>
+    <reg name="va" bitsize="8"/>
+    <reg name="vb" bitsize="8"/>
+    <reg name="vc" bitsize="8"/>
+    <reg name="vd" bitsize="8"/>
+    <reg name="ve" bitsize="8"/>
+    <reg name="vf" bitsize="8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8"/>
+    <reg name="dt" bitsize="8"/>
+    <reg name="st" bitsize="8"/>
+  </feature>
+</target>
+"#;
+
+/// Waits for a debugger to connect on a TCP port, then lets it run the
+/// show until it detaches, kills the program or goes away.
+pub struct GdbServer {
+    listener: TcpListener,
+}
+
+enum Packet {
+    Command(String),
+    Interrupt,
+}
+
+// Why we handed control back to the debugger
+enum Stop {
+    Signal(u8),
+    Exited(ExitReason),
+}
+
+impl GdbServer {
+    pub fn bind<A: ToSocketAddrs>(address: A) -> io::Result<GdbServer> {
+        Ok(GdbServer {
+            listener: TcpListener::bind(address)?,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<std::net::SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Serve one debugger connection. The program doesn't run until the
+    /// debugger says so.
+    pub fn run(&self, cpu: &mut Processor) -> io::Result<ExitReason> {
+        let (stream, _) = self.listener.accept()?;
+        stream.set_nodelay(true)?;
+        let mut session = Session {
+            connection: Connection {
+                stream,
+                pending: Vec::new(),
+            },
+            breakpoints: BTreeSet::new(),
+        };
+        session.serve(cpu)?;
+        Ok(cpu.exit_reason().unwrap_or(ExitReason::Quit))
+    }
+}
+
+struct Session {
+    connection: Connection,
+    breakpoints: BTreeSet<u16>,
+}
+
+impl Session {
+    fn serve(&mut self, cpu: &mut Processor) -> io::Result<()> {
+        loop {
+            let command = match self.connection.read_packet()? {
+                Some(Packet::Command(command)) => command,
+                // Already stopped, just say so again
+                Some(Packet::Interrupt) => {
+                    self.connection
+                        .write_packet(&stop_reply(&Stop::Signal(SIGINT)))?;
+                    continue;
+                }
+                None => return Ok(()),
+            };
+
+            let reply = match command.as_bytes().first() {
+                Some(b'?') => stop_reply(&Stop::Signal(SIGTRAP)),
+                Some(b'g') => read_registers(cpu),
+                Some(b'G') => or_error(write_registers(cpu, &command[1..])),
+                // An unknown register is "not supported" rather than an error
+                Some(b'p') => read_register(cpu, &command[1..]).unwrap_or_default(),
+                Some(b'P') => or_error(write_register(cpu, &command[1..])),
+                Some(b'm') => or_error(read_memory(cpu, &command[1..])),
+                Some(b'M') => or_error(write_memory(cpu, &command[1..])),
+                Some(b'Z') | Some(b'z') => self.breakpoint(&command),
+                Some(b's') => {
+                    let stop = self.step(cpu, &command[1..]);
+                    stop_reply(&stop)
+                }
+                Some(b'c') => {
+                    let stop = self.resume(cpu, &command[1..])?;
+                    stop_reply(&stop)
+                }
+                Some(b'k') => return Ok(()),
+                Some(b'D') => {
+                    self.connection.write_packet("OK")?;
+                    return Ok(());
+                }
+                Some(b'H') => "OK".to_string(),
+                _ => query(&command),
+            };
+            self.connection.write_packet(&reply)?;
+        }
+    }
+
+    // Z0,addr,kind / z0,addr,kind. Only software breakpoints, and hardware
+    // ones (Z1) which are just the same thing here.
+    fn breakpoint(&mut self, command: &str) -> String {
+        let insert = command.starts_with('Z');
+        let mut fields = command[1..].split(',');
+        let kind = fields.next();
+        let address = fields.next().and_then(|a| u16::from_str_radix(a, 16).ok());
+        match (kind, address) {
+            (Some("0"), Some(address)) | (Some("1"), Some(address)) => {
+                if insert {
+                    self.breakpoints.insert(address);
+                } else {
+                    self.breakpoints.remove(&address);
+                }
+                "OK".to_string()
+            }
+            (Some("0"), None) | (Some("1"), None) => "E01".to_string(),
+            // Watchpoints and the like, not supported
+            _ => String::new(),
+        }
+    }
+
+    fn step(&mut self, cpu: &mut Processor, address: &str) -> Stop {
+        resume_at(cpu, address);
+        match cpu.step_instruction() {
+            Ok(()) => Stop::Signal(SIGTRAP),
+            Err(exit) => Stop::Exited(exit),
+        }
+    }
+
+    fn resume(&mut self, cpu: &mut Processor, address: &str) -> io::Result<Stop> {
+        resume_at(cpu, address);
+        // Always get off the breakpoint we're sitting on first
+        if let Err(exit) = cpu.step_instruction() {
+            return Ok(Stop::Exited(exit));
+        }
+
+        let breakpoints = &self.breakpoints;
+        let connection = &mut self.connection;
+        let mut interrupted = false;
+        let mut error = None;
+        let exit = cpu.run_until(|cpu| {
+            if breakpoints.contains(&cpu.program_counter()) {
+                return true;
+            }
+            // Once a frame, like the keyboard
+            if cpu.frame_progress() == 0 {
+                match connection.poll_interrupt() {
+                    Ok(found) => interrupted = found,
+                    Err(e) => error = Some(e),
+                }
+            }
+            interrupted || error.is_some()
+        });
+        if let Some(e) = error {
+            return Err(e);
+        }
+        Ok(match exit {
+            Some(exit) => Stop::Exited(exit),
+            None if interrupted => Stop::Signal(SIGINT),
+            None => Stop::Signal(SIGTRAP),
+        })
+    }
+}
+
+// `s addr` / `c addr` carry on from somewhere else
+fn resume_at(cpu: &mut Processor, address: &str) {
+    if let Ok(address) = u16::from_str_radix(address, 16) {
+        cpu.set_program_counter(address);
+    }
+}
+
+fn stop_reply(stop: &Stop) -> String {
+    match stop {
+        Stop::Signal(signal) => format!("S{:02x}", signal),
+        Stop::Exited(ExitReason::Fault { fault, .. }) => {
+            let signal = match fault {
+                Fault::UnknownOpcode => SIGILL,
+                _ => SIGSEGV,
+            };
+            format!("S{:02x}", signal)
+        }
+        Stop::Exited(_) => "W00".to_string(),
+    }
+}
+
+// Anything else, mostly q packets. An empty reply means "not supported".
+fn query(command: &str) -> String {
+    if command.starts_with("qSupported") {
+        format!("PacketSize={:x};qXfer:features:read+", PACKET_SIZE)
+    } else if command == "qAttached" {
+        "1".to_string()
+    } else if command == "qC" {
+        "QC1".to_string()
+    } else if command == "qfThreadInfo" {
+        "m1".to_string()
+    } else if command == "qsThreadInfo" {
+        "l".to_string()
+    } else if let Some(range) = command.strip_prefix("qXfer:features:read:target.xml:") {
+        read_target_xml(range)
+    } else {
+        String::new()
+    }
+}
+
+// offset,length into the target description, m for more, l for the end
+fn read_target_xml(range: &str) -> String {
+    let mut fields = range.split(',');
+    let offset = fields
+        .next()
+        .and_then(|n| usize::from_str_radix(n, 16).ok());
+    let length = fields
+        .next()
+        .and_then(|n| usize::from_str_radix(n, 16).ok());
+    let (offset, length) = match (offset, length) {
+        (Some(offset), Some(length)) => (offset, length),
+        _ => return "E01".to_string(),
+    };
+    let xml = TARGET_XML.as_bytes();
+    let start = offset.min(xml.len());
+    let end = (start + length).min(xml.len());
+    let marker = if end == xml.len() { 'l' } else { 'm' };
+    let chunk = String::from_utf8_lossy(&xml[start..end]);
+    format!("{}{}", marker, escape(&chunk))
+}
+
+fn register_bytes(cpu: &Processor, register: usize) -> Option<Vec<u8>> {
+    Some(match register {
+        0..=15 => vec![cpu.registers()[register]],
+        REG_I => cpu.reg_i().to_be_bytes().to_vec(),
+        REG_PC => cpu.program_counter().to_be_bytes().to_vec(),
+        REG_SP => vec![cpu.stack().depth() as u8],
+        REG_DT => vec![cpu.delay_timer()],
+        REG_ST => vec![cpu.sound_timer()],
+        _ => return None,
+    })
+}
+
+fn set_register_bytes(cpu: &mut Processor, register: usize, bytes: &[u8]) -> Result<(), ()> {
+    let word = || match bytes {
+        [high, low] => Ok(u16::from_be_bytes([*high, *low])),
+        _ => Err(()),
+    };
+    let byte = || match bytes {
+        [byte] => Ok(*byte),
+        _ => Err(()),
+    };
+    match register {
+        0..=15 => cpu.set_register(register, byte()?),
+        REG_I => cpu.set_reg_i(word()?),
+        REG_PC => cpu.set_program_counter(word()?),
+        // The stack is only changed by CALL and RET (or push/pop in the
+        // built-in debugger), so SP is read only
+        REG_SP if byte()? as usize == cpu.stack().depth() => (),
+        REG_SP => return Err(()),
+        REG_DT => cpu.set_delay_timer(byte()?),
+        REG_ST => cpu.set_sound_timer(byte()?),
+        _ => return Err(()),
+    }
+    Ok(())
+}
+
+fn read_registers(cpu: &Processor) -> String {
+    (0..REGISTER_COUNT)
+        .filter_map(|register| register_bytes(cpu, register))
+        .map(|bytes| to_hex(&bytes))
+        .collect()
+}
+
+fn write_registers(cpu: &mut Processor, hex: &str) -> Result<String, ()> {
+    let bytes = from_hex(hex)?;
+    let mut offset = 0;
+    for register in 0..REGISTER_COUNT {
+        let len = register_bytes(cpu, register).map_or(0, |b| b.len());
+        let value = bytes.get(offset..offset + len).ok_or(())?;
+        set_register_bytes(cpu, register, value)?;
+        offset += len;
+    }
+    Ok("OK".to_string())
+}
+
+fn read_register(cpu: &Processor, args: &str) -> Result<String, ()> {
+    let register = usize::from_str_radix(args, 16).map_err(|_| ())?;
+    register_bytes(cpu, register)
+        .map(|bytes| to_hex(&bytes))
+        .ok_or(())
+}
+
+fn write_register(cpu: &mut Processor, args: &str) -> Result<String, ()> {
+    let (register, value) = args.split_once('=').ok_or(())?;
+    let register = usize::from_str_radix(register, 16).map_err(|_| ())?;
+    set_register_bytes(cpu, register, &from_hex(value)?)?;
+    Ok("OK".to_string())
+}
+
+// addr,length
+fn read_memory(cpu: &Processor, args: &str) -> Result<String, ()> {
+    let (address, length) = args.split_once(',').ok_or(())?;
+    let address = usize::from_str_radix(address, 16).map_err(|_| ())?;
+    let length = usize::from_str_radix(length, 16).map_err(|_| ())?;
+    let memory = cpu.memory();
+    if address >= memory.len() {
+        return Err(());
+    }
+    let end = (address + length.min(PACKET_SIZE / 2)).min(memory.len());
+    Ok(to_hex(&memory[address..end]))
+}
+
+// addr,length:XX...
+fn write_memory(cpu: &mut Processor, args: &str) -> Result<String, ()> {
+    let (header, data) = args.split_once(':').ok_or(())?;
+    let (address, length) = header.split_once(',').ok_or(())?;
+    let address = usize::from_str_radix(address, 16).map_err(|_| ())?;
+    let length = usize::from_str_radix(length, 16).map_err(|_| ())?;
+    let bytes = from_hex(data)?;
+    if bytes.len() != length {
+        return Err(());
+    }
+    cpu.write_memory(address, &bytes).map_err(|_| ())?;
+    Ok("OK".to_string())
+}
+
+fn or_error(result: Result<String, ()>) -> String {
+    result.unwrap_or_else(|()| "E01".to_string())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// A byte at a time rather than slicing the str, which could land in the
+// middle of a character
+fn from_hex(hex: &str) -> Result<Vec<u8>, ()> {
+    let hex = hex.as_bytes();
+    if !hex.len().is_multiple_of(2) {
+        return Err(());
+    }
+    let digit = |c: u8| (c as char).to_digit(16).map(|d| d as u8).ok_or(());
+    hex.chunks(2)
+        .map(|pair| Ok(digit(pair[0])? << 4 | digit(pair[1])?))
+        .collect()
+}
+
+// $ # } and * can't appear as themselves in a packet
+fn escape(data: &str) -> String {
+    let mut escaped = String::new();
+    for c in data.chars() {
+        match c {
+            '$' | '#' | '}' | '*' => {
+                escaped.push('}');
+                escaped.push((c as u8 ^ 0x20) as char);
+            }
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+struct Connection {
+    stream: TcpStream,
+    // Read but not yet dealt with
+    pending: Vec<u8>,
+}
+
+impl Connection {
+    fn next_byte(&mut self) -> io::Result<Option<u8>> {
+        if !self.pending.is_empty() {
+            return Ok(Some(self.pending.remove(0)));
+        }
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    // None once the other end has gone away
+    fn read_packet(&mut self) -> io::Result<Option<Packet>> {
+        loop {
+            match self.next_byte()? {
+                None => return Ok(None),
+                Some(INTERRUPT) => return Ok(Some(Packet::Interrupt)),
+                Some(b'$') => (),
+                // Acks, naks and noise between packets
+                Some(_) => continue,
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.next_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                }
+            }
+            let mut checksum = [0; 2];
+            for digit in checksum.iter_mut() {
+                *digit = match self.next_byte()? {
+                    Some(byte) => byte,
+                    None => return Ok(None),
+                };
+            }
+
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            if expected == Some(checksum_of(&data)) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(Packet::Command(
+                    String::from_utf8_lossy(&data).into_owned(),
+                )));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())
+    }
+
+    // Without blocking, has the debugger asked us to stop? Anything else
+    // that turned up is kept for later.
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut buf = [0; 64];
+        let result = self.stream.read(&mut buf);
+        self.stream.set_nonblocking(false)?;
+        match result {
+            // Gone away, stop and let read_packet find out
+            Ok(0) => Ok(true),
+            Ok(n) => {
+                let interrupted = buf[..n].contains(&INTERRUPT);
+                self.pending
+                    .extend(buf[..n].iter().filter(|&&byte| byte != INTERRUPT));
+                Ok(interrupted)
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+
+    use super::{checksum_of, escape, from_hex, to_hex, GdbServer};
+    use crate::testing::processor;
+
+    #[test]
+    fn hex() {
+        assert_eq!(to_hex(&[0x00, 0x1f, 0xff]), "001fff");
+        assert_eq!(from_hex("001fFF"), Ok(vec![0x00, 0x1f, 0xff]));
+        assert_eq!(from_hex(""), Ok(vec![]));
+        for bad in &["0", "0g", "+1", "a\u{e9}a", "\u{e9}\u{e9}"] {
+            assert_eq!(from_hex(bad), Err(()), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn checksums_and_escapes() {
+        assert_eq!(checksum_of(b"OK"), 0x9a);
+        assert_eq!(checksum_of(b""), 0);
+        assert_eq!(escape("a$b#c}d*"), "a}\u{4}b}\u{3}c}]d}\n");
+    }
+
+    // Talks to the server like GDB would, and gives back what came back
+    // for each packet: the ack, then the reply
+    fn session(packets: Vec<Vec<u8>>) -> (Vec<String>, crate::Processor) {
+        let server = GdbServer::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            let mut replies = Vec::new();
+            for packet in packets {
+                stream.write_all(&packet).unwrap();
+                let mut reply = Vec::new();
+                let mut byte = [0];
+                // Up to and including the checksum, or just the nak
+                while stream.read(&mut byte).unwrap() == 1 {
+                    reply.push(byte[0]);
+                    let len = reply.len();
+                    if reply == b"-" || (len > 3 && reply[len - 3] == b'#') {
+                        break;
+                    }
+                }
+                replies.push(String::from_utf8(reply).unwrap());
+            }
+            stream.write_all(&command(b"k")).unwrap();
+            replies
+        });
+        let mut cpu = processor("LD V0, 5\nLD I, 0x300\nADD V0, 1");
+        server.run(&mut cpu).unwrap();
+        (client.join().unwrap(), cpu)
+    }
+
+    fn command(data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![b'$'];
+        packet.extend(data);
+        packet.extend(format!("#{:02x}", checksum_of(data)).bytes());
+        packet
+    }
+
+    // Acked, then the reply
+    fn reply(data: &str) -> String {
+        format!("+${}#{:02x}", data, checksum_of(data.as_bytes()))
+    }
+
+    #[test]
+    fn framing() {
+        let (replies, _) = session(vec![
+            b"$?#3f".to_vec(),
+            b"$?#00".to_vec(),
+            b"+-noise$?#3F".to_vec(),
+            vec![0x03],
+        ]);
+        assert_eq!(replies[0], reply("S05"));
+        // A bad checksum gets a nak and is dropped
+        assert_eq!(replies[1], "-");
+        // Anything between packets is ignored, and hex is hex
+        assert_eq!(replies[2], reply("S05"));
+        // Interrupting while stopped just says it's stopped
+        assert_eq!(replies[3], "$S02#b5");
+    }
+
+    #[test]
+    fn interrupted_within_a_frame() {
+        let (replies, cpu) = session(vec![
+            // JP 0x206 at the end, so it runs until it's stopped
+            command(b"M206,2:1206"),
+            [command(b"c"), vec![0x03]].concat(),
+        ]);
+        assert_eq!(replies[1], reply("S02"));
+        assert_eq!(cpu.program_counter(), 0x206);
+        assert_eq!(cpu.instructions(), cpu.instructions_per_frame() as u64);
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        let (replies, cpu) = session(vec![
+            command(b"s"),
+            command(b"g"),
+            // V0-VF, I, PC, then SP, DT and ST
+            command(b"G0102030405060708090a0b0c0d0e0f10030002040000ff"),
+            command(b"G0102"),
+            command(b"m200,4"),
+            command(b"M300,2:abcd"),
+            command(b"m300,2"),
+            command(b"M300,2:a\xc3\xa9a"),
+            command(b"m10000,1"),
+        ]);
+        // V0 is 5 and the PC 0x202, everything else is 0
+        let registers = format!("05{}00000202000000", "00".repeat(15));
+        assert_eq!(replies[0], reply("S05"));
+        assert_eq!(replies[1], reply(&registers));
+        assert_eq!(replies[2], reply("OK"));
+        assert_eq!(replies[3], reply("E01"));
+        assert_eq!(replies[4], reply("6005a300"));
+        assert_eq!(replies[5], reply("OK"));
+        assert_eq!(replies[6], reply("abcd"));
+        // Not hex, and not a panic either
+        assert_eq!(replies[7], reply("E01"));
+        assert_eq!(replies[8], reply("E01"));
+
+        assert_eq!(cpu.registers()[0xf], 0x10);
+        assert_eq!(cpu.reg_i(), 0x0300);
+        assert_eq!(cpu.program_counter(), 0x0204);
+        assert_eq!(cpu.sound_timer(), 0xff);
+        assert_eq!(&cpu.memory()[0x300..0x302], &[0xab, 0xcd]);
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Which source line each instruction came from, so breakpoints can be set
+/// by line and stack traces can show where they are.
+///
+/// The file format is one instruction a line, its address in hex then
+/// `file:line`:
+///
+/// ```text
+/// # comments and blank lines are ignored
+/// 0200 pong.8o:12
+/// 0202 pong.8o:13
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolMap {
+    lines: BTreeMap<u16, SourceLine>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLine {
+    pub file: String,
+    /// Starting at 1
+    pub line: u32,
+}
+
+impl SymbolMap {
+    pub fn new() -> SymbolMap {
+        SymbolMap::default()
+    }
+
+    pub fn insert(&mut self, address: u16, line: SourceLine) {
+        self.lines.insert(address, line);
+    }
+
+    pub fn parse(text: &str) -> Result<SymbolMap, String> {
+        let mut map = SymbolMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let bad = || format!("line {}: expected ADDRESS FILE:LINE", number + 1);
+            let (address, location) = line.split_once(char::is_whitespace).ok_or_else(bad)?;
+            let (file, source_line) = location.trim().rsplit_once(':').ok_or_else(bad)?;
+            let address = u16::from_str_radix(address, 16).map_err(|_| bad())?;
+            let source_line = source_line.parse().map_err(|_| bad())?;
+            map.insert(
+                address,
+                SourceLine {
+                    file: file.to_string(),
+                    line: source_line,
+                },
+            );
+        }
+        Ok(map)
+    }
+
+    pub fn load_from_path<P: AsRef<Path>>(path: P) -> io::Result<SymbolMap> {
+        let text = fs::read_to_string(path)?;
+        SymbolMap::parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn save_to_path<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_string())
+    }
+
+    pub fn line_for(&self, address: u16) -> Option<&SourceLine> {
+        self.lines.get(&address)
+    }
+
+    /// Every instruction that came from `line` of `file`. Editors tend to
+    /// send absolute paths, so a map entry of `pong.8o` matches
+    /// `/home/me/pong.8o` too.
+    pub fn addresses_for(&self, file: &str, line: u32) -> Vec<u16> {
+        self.lines
+            .iter()
+            .filter(|(_, source)| source.line == line && same_file(&source.file, file))
+            .map(|(&address, _)| address)
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+}
+
+fn same_file(map_file: &str, file: &str) -> bool {
+    let map_file = Path::new(map_file);
+    let file = Path::new(file);
+    file == map_file || file.ends_with(map_file) || map_file.ends_with(file)
+}
+
+impl fmt::Display for SymbolMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (address, source) in &self.lines {
+            writeln!(f, "{:04x} {}:{}", address, source.file, source.line)?;
+        }
+        Ok(())
+    }
+}
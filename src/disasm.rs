@@ -0,0 +1,492 @@
+//! Turning ROMs back into source.
+//!
+//! [`Disassembly`] follows the program from its load address through jumps,
+//! calls and skips to work out which bytes are code, names everything that
+//! gets jumped to, called or pointed at with `I`, and writes the lot out in
+//! either the classic Cowgod-style mnemonics or Octo's syntax.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+use crate::instruction::Instruction;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syntax {
+    /// `LD V0, 0x05`, `JP label_0206`
+    Classic,
+    /// `v0 := 0x05`, `jump label_0206`
+    Octo,
+}
+
+impl Syntax {
+    pub const NAMES: &'static [&'static str] = &["classic", "octo"];
+
+    pub fn from_name(name: &str) -> Option<Syntax> {
+        match name {
+            "classic" => Some(Syntax::Classic),
+            "octo" => Some(Syntax::Octo),
+            _ => None,
+        }
+    }
+
+    fn comment(self) -> char {
+        match self {
+            Syntax::Classic => ';',
+            Syntax::Octo => '#',
+        }
+    }
+}
+
+// What made an address worth naming. Later ones win when there's more than
+// one reason.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Label {
+    Data,
+    Jump,
+    Call,
+    Main,
+}
+
+/// A ROM split into code and data.
+pub struct Disassembly {
+    load_address: u16,
+    bytes: Vec<u8>,
+    // Where each instruction reached by the program starts
+    code: BTreeSet<u16>,
+    labels: BTreeMap<u16, Label>,
+}
+
+impl Disassembly {
+    pub fn new(rom: &[u8], load_address: u16) -> Disassembly {
+        let mut disassembly = Disassembly {
+            load_address,
+            bytes: rom.to_vec(),
+            code: BTreeSet::new(),
+            labels: BTreeMap::new(),
+        };
+        disassembly.trace();
+        disassembly
+    }
+
+    /// True if `address` starts an instruction the program can reach.
+    pub fn is_code(&self, address: u16) -> bool {
+        self.code.contains(&address)
+    }
+
+    /// Every instruction the program can reach, in address order.
+    pub fn code(&self) -> impl Iterator<Item = u16> + '_ {
+        self.code.iter().copied()
+    }
+
+    /// The name given to `address`, if anything refers to it.
+    pub fn label(&self, address: u16) -> Option<String> {
+        let label = self.labels.get(&address)?;
+        Some(match label {
+            Label::Main => "main".to_string(),
+            Label::Call => format!("sub_{:04x}", address),
+            Label::Jump => format!("label_{:04x}", address),
+            Label::Data => format!("data_{:04x}", address),
+        })
+    }
+
+    fn opcode(&self, address: u16) -> Option<u16> {
+        let offset = address.checked_sub(self.load_address)? as usize;
+        let bytes = self.bytes.get(offset..offset + 2)?;
+        Some((bytes[0] as u16) << 8 | bytes[1] as u16)
+    }
+
+    fn in_rom(&self, address: u16) -> bool {
+        address >= self.load_address && ((address - self.load_address) as usize) < self.bytes.len()
+    }
+
+    fn add_label(&mut self, address: u16, label: Label) {
+        if self.in_rom(address) {
+            let existing = self.labels.entry(address).or_insert(label);
+            *existing = (*existing).max(label);
+        }
+    }
+
+    // Instructions are 2 bytes, except F000 NNNN
+    fn instruction_len(&self, address: u16) -> u16 {
+        match self.opcode(address) {
+            Some(0xF000) => 4,
+            _ => 2,
+        }
+    }
+
+    fn trace(&mut self) {
+        use crate::instruction::Instruction::*;
+
+        let mut pending = vec![self.load_address];
+        self.add_label(self.load_address, Label::Main);
+        while let Some(address) = pending.pop() {
+            if self.code.contains(&address) {
+                continue;
+            }
+            let instruction = match self.opcode(address).and_then(Instruction::decode) {
+                Some(instruction) => instruction,
+                // Off the end of the ROM, or not an instruction at all
+                None => continue,
+            };
+            self.code.insert(address);
+            let next = address.wrapping_add(instruction.size());
+            match instruction {
+                Ret | Exit => (),
+                Jump(target) => {
+                    self.add_label(target, Label::Jump);
+                    pending.push(target);
+                }
+                Call(target) => {
+                    self.add_label(target, Label::Call);
+                    pending.push(target);
+                    pending.push(next);
+                }
+                // Either the next instruction runs or the one after
+                _ if instruction.is_skip() => {
+                    pending.push(next);
+                    pending.push(next.wrapping_add(self.instruction_len(next)));
+                }
+                LoadI(target) => {
+                    self.add_label(target, Label::Data);
+                    pending.push(next);
+                }
+                // The start of a jump table, where the rest of it is depends
+                // on V0
+                JumpV0(target) => {
+                    self.add_label(target, Label::Jump);
+                    pending.push(target);
+                }
+                LoadILong => {
+                    if let Some(target) = self.opcode(address.wrapping_add(2)) {
+                        self.add_label(target, Label::Data);
+                    }
+                    pending.push(next);
+                }
+                _ => pending.push(next),
+            }
+        }
+    }
+
+    // How to refer to an address in the output
+    fn name(&self, address: u16) -> String {
+        self.label(address).unwrap_or_else(|| hex_address(address))
+    }
+
+    /// Write the whole ROM out as source.
+    pub fn write_to<W: Write>(&self, syntax: Syntax, mut writer: W) -> io::Result<()> {
+        let comment = syntax.comment();
+        writeln!(
+            writer,
+            "{} {} bytes, loaded at 0x{:03x}",
+            comment,
+            self.bytes.len(),
+            self.load_address
+        )?;
+        if self.load_address != 0x200 {
+            match syntax {
+                Syntax::Classic => writeln!(writer, "ORG 0x{:03x}", self.load_address)?,
+                Syntax::Octo => writeln!(writer, ":org 0x{:03x}", self.load_address)?,
+            }
+        }
+
+        let end = self.load_address as usize + self.bytes.len();
+        let mut address = self.load_address as usize;
+        while address < end {
+            let here = address as u16;
+            if let Some(label) = self.label(here) {
+                match syntax {
+                    Syntax::Classic => writeln!(writer, "\n{}:", label)?,
+                    Syntax::Octo => writeln!(writer, "\n: {}", label)?,
+                }
+            }
+            if let Some(text) = self.instruction_at(here, syntax) {
+                let len = self.instruction_len(here) as usize;
+                let offset = address - self.load_address as usize;
+                let hex: String = self.bytes[offset..offset + len]
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect();
+                writeln!(
+                    writer,
+                    "    {:<23} {} {:04x}: {}",
+                    text, comment, address, hex
+                )?;
+                address += len;
+            } else {
+                address += self.write_data(here, end, syntax, &mut writer)?;
+            }
+        }
+        Ok(())
+    }
+
+    // None if it's data, or an instruction with a label somewhere inside it,
+    // which can only be written out as bytes
+    fn instruction_at(&self, address: u16, syntax: Syntax) -> Option<String> {
+        if !self.is_code(address) {
+            return None;
+        }
+        let len = self.instruction_len(address);
+        if (1..len).any(|i| self.labels.contains_key(&address.wrapping_add(i))) {
+            return None;
+        }
+        let long = if len == 4 {
+            Some(self.opcode(address.wrapping_add(2))?)
+        } else {
+            None
+        };
+        let instruction = Instruction::decode(self.opcode(address)?)?;
+        let name = |address| self.name(address);
+        Some(match syntax {
+            Syntax::Classic => instruction.classic(long, &name),
+            Syntax::Octo => octo(instruction, long, &name),
+        })
+    }
+
+    // A run of data, up to the next code or label. Things I points at are
+    // probably sprites, so they go a byte a line with a picture of the bits.
+    // Returns how many bytes were written.
+    fn write_data<W: Write>(
+        &self,
+        start: u16,
+        end: usize,
+        syntax: Syntax,
+        writer: &mut W,
+    ) -> io::Result<usize> {
+        let comment = syntax.comment();
+        let sprite = matches!(
+            self.labels.range(..=start).next_back(),
+            Some((_, Label::Data))
+        );
+        let per_line = if sprite { 1 } else { 8 };
+
+        let mut len = 1;
+        while (start as usize + len) < end
+            && len < per_line
+            && !self.is_code(start + len as u16)
+            && !self.labels.contains_key(&(start + len as u16))
+        {
+            len += 1;
+        }
+        let offset = (start - self.load_address) as usize;
+        let bytes = &self.bytes[offset..offset + len];
+
+        let mut line = String::new();
+        match syntax {
+            Syntax::Classic => {
+                let list: Vec<String> = bytes.iter().map(|b| format!("0x{:02x}", b)).collect();
+                write!(line, "DB {}", list.join(", ")).unwrap();
+            }
+            Syntax::Octo => {
+                let list: Vec<String> = bytes.iter().map(|b| format!("0x{:02x}", b)).collect();
+                write!(line, "{}", list.join(" ")).unwrap();
+            }
+        }
+        write!(writer, "    {:<23} {} {:04x}", line, comment, start)?;
+        if sprite {
+            let bits: String = (0..8)
+                .map(|bit| {
+                    if bytes[0] & 0x80 >> bit != 0 {
+                        '#'
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            write!(writer, " {}", bits)?;
+        }
+        writeln!(writer)?;
+        Ok(len)
+    }
+}
+
+/// A single instruction in the classic syntax, as shown by the trace and the
+/// debugger. `long` is the word after an F000, when there is one.
+pub fn disassemble_instruction(op: u16, long: Option<u16>) -> String {
+    match Instruction::decode(op) {
+        Some(instruction) => instruction.classic(long, &hex_address),
+        None => format!("not supported ({:04x})", op),
+    }
+}
+
+fn hex_address(address: u16) -> String {
+    format!("0x{:03x}", address)
+}
+
+// Octo's `if ... then` says when the next instruction runs, which is the
+// opposite of when the instruction skips it
+fn octo(instruction: Instruction, long: Option<u16>, name: &dyn Fn(u16) -> String) -> String {
+    use crate::instruction::Instruction::*;
+
+    match instruction {
+        Cls => "clear".to_string(),
+        Ret => "return".to_string(),
+        ScrollDown(n) => format!("scroll-down {}", n),
+        ScrollUp(n) => format!("scroll-up {}", n),
+        ScrollRight => "scroll-right".to_string(),
+        ScrollLeft => "scroll-left".to_string(),
+        Exit => "exit".to_string(),
+        Low => "lores".to_string(),
+        High => "hires".to_string(),
+        Jump(nnn) => format!("jump {}", name(nnn)),
+        Call(nnn) => format!(":call {}", name(nnn)),
+        SkipEqual { x, value } => format!("if v{:x} != 0x{:02x} then", x, value),
+        SkipNotEqual { x, value } => format!("if v{:x} == 0x{:02x} then", x, value),
+        SkipEqualRegister { x, y } => format!("if v{:x} != v{:x} then", x, y),
+        Save { x, y } => format!("save v{:x} - v{:x}", x, y),
+        Load { x, y } => format!("load v{:x} - v{:x}", x, y),
+        LoadByte { x, value } => format!("v{:x} := 0x{:02x}", x, value),
+        AddByte { x, value } => format!("v{:x} += 0x{:02x}", x, value),
+        Move { x, y } => format!("v{:x} := v{:x}", x, y),
+        Or { x, y } => format!("v{:x} |= v{:x}", x, y),
+        And { x, y } => format!("v{:x} &= v{:x}", x, y),
+        Xor { x, y } => format!("v{:x} ^= v{:x}", x, y),
+        Add { x, y } => format!("v{:x} += v{:x}", x, y),
+        Sub { x, y } => format!("v{:x} -= v{:x}", x, y),
+        ShiftRight { x, y } => format!("v{:x} >>= v{:x}", x, y),
+        SubN { x, y } => format!("v{:x} =- v{:x}", x, y),
+        ShiftLeft { x, y } => format!("v{:x} <<= v{:x}", x, y),
+        SkipNotEqualRegister { x, y } => format!("if v{:x} == v{:x} then", x, y),
+        LoadI(nnn) => format!("i := {}", name(nnn)),
+        JumpV0(nnn) => format!("jump0 {}", name(nnn)),
+        Random { x, mask } => format!("v{:x} := random 0x{:02x}", x, mask),
+        Draw { x, y, rows } => format!("sprite v{:x} v{:x} {}", x, y, rows),
+        SkipKey(x) => format!("if v{:x} -key then", x),
+        SkipNotKey(x) => format!("if v{:x} key then", x),
+        LoadILong => match long {
+            Some(address) => format!("i := long {}", name(address)),
+            None => "i := long".to_string(),
+        },
+        Plane(n) => format!("plane {}", n),
+        Audio => "audio".to_string(),
+        GetDelay(x) => format!("v{:x} := delay", x),
+        WaitKey(x) => format!("v{:x} := key", x),
+        SetDelay(x) => format!("delay := v{:x}", x),
+        SetSound(x) => format!("buzzer := v{:x}", x),
+        AddI(x) => format!("i += v{:x}", x),
+        Font(x) => format!("i := hex v{:x}", x),
+        BigFont(x) => format!("i := bighex v{:x}", x),
+        Bcd(x) => format!("bcd v{:x}", x),
+        Pitch(x) => format!("pitch := v{:x}", x),
+        StoreRegisters(x) => format!("save v{:x}", x),
+        LoadRegisters(x) => format!("load v{:x}", x),
+        SaveFlags(x) => format!("saveflags v{:x}", x),
+        LoadFlags(x) => format!("loadflags v{:x}", x),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{disassemble_instruction, Disassembly, Syntax};
+    use crate::asm::assemble;
+
+    // The LD V1 is only reached by the SE skipping the JP, and the sprite
+    // and the word after the RET are never run
+    const SOURCE: &str = "
+        LD I, sprite
+        CALL sub
+        SE V0, 1
+        JP end
+        LD V1, 2
+    end:
+        JP end
+    sub:
+        RET
+        DW 0x1234
+    sprite:
+        DB 0xf0, 0x90
+    ";
+
+    fn disassembly() -> Disassembly {
+        let assembly = assemble(SOURCE).unwrap();
+        Disassembly::new(assembly.bytes(), assembly.load_address())
+    }
+
+    fn text(syntax: Syntax) -> String {
+        let mut out = Vec::new();
+        disassembly().write_to(syntax, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn follows_skips_and_calls() {
+        let disassembly = disassembly();
+        let code: Vec<u16> = disassembly.code().collect();
+        assert_eq!(code, [0x200, 0x202, 0x204, 0x206, 0x208, 0x20a, 0x20c]);
+        for data in &[0x20e, 0x210, 0x211] {
+            assert!(!disassembly.is_code(*data));
+        }
+    }
+
+    #[test]
+    fn labels() {
+        let disassembly = disassembly();
+        let label = |address| disassembly.label(address);
+        assert_eq!(label(0x200).as_deref(), Some("main"));
+        assert_eq!(label(0x20a).as_deref(), Some("label_020a"));
+        assert_eq!(label(0x20c).as_deref(), Some("sub_020c"));
+        assert_eq!(label(0x210).as_deref(), Some("data_0210"));
+        assert_eq!(label(0x208), None);
+        // Outside the ROM never gets one
+        let far = Disassembly::new(&[0x13, 0x00], 0x200);
+        assert_eq!(far.label(0x300), None);
+    }
+
+    #[test]
+    fn classic() {
+        let text = text(Syntax::Classic);
+        for line in &[
+            "\nmain:\n",
+            "LD I, data_0210",
+            "CALL sub_020c",
+            "SE V0, 0x01",
+            "JP label_020a",
+            "\nsub_020c:\n",
+            "DB 0x12, 0x34",
+            // A sprite, with a picture of it
+            "DB 0xf0                 ; 0210 ####....",
+            "DB 0x90                 ; 0211 #..#....",
+        ] {
+            assert!(text.contains(line), "no {:?} in\n{}", line, text);
+        }
+        // And back again
+        assert_eq!(
+            assemble(&text).unwrap().bytes(),
+            assemble(SOURCE).unwrap().bytes()
+        );
+    }
+
+    #[test]
+    fn octo() {
+        let text = text(Syntax::Octo);
+        for line in &[
+            "\n: main\n",
+            "i := data_0210",
+            ":call sub_020c",
+            // Octo says when the next instruction runs, not when it's skipped
+            "if v0 != 0x01 then",
+            "jump label_020a",
+            "return",
+            "0x12 0x34",
+            "0xf0                    # 0210 ####....",
+        ] {
+            assert!(text.contains(line), "no {:?} in\n{}", line, text);
+        }
+    }
+
+    #[test]
+    fn single_instructions() {
+        assert_eq!(disassemble_instruction(0x9120, None), "SNE V1, V2");
+        assert_eq!(disassemble_instruction(0x5120, None), "SE V1, V2");
+        assert_eq!(disassemble_instruction(0x1234, None), "JP 0x234");
+        assert_eq!(disassemble_instruction(0x1004, None), "JP 0x004");
+        assert_eq!(
+            disassemble_instruction(0xF000, Some(0x1234)),
+            "LD I, long 0x1234"
+        );
+        assert_eq!(
+            disassemble_instruction(0x9121, None),
+            "not supported (9121)"
+        );
+    }
+}
@@ -1,7 +1,70 @@
-pub mod display;
+use crate::framebuffer::Framebuffer;
+
+pub mod headless;
+
+#[cfg(feature = "sdl")]
 pub mod audio;
+#[cfg(feature = "sdl")]
+pub mod display;
+#[cfg(feature = "sdl")]
 pub mod keyboard;
 
-pub use self::display::DisplayDriver;
+#[cfg(feature = "sdl")]
 pub use self::audio::AudioDriver;
+#[cfg(feature = "sdl")]
+pub use self::display::DisplayDriver;
+#[cfg(feature = "sdl")]
 pub use self::keyboard::KeyboardDriver;
+
+/// Something that can show the framebuffer, 64x32 or 128x64.
+pub trait Display {
+    fn draw(&mut self, frame: &Framebuffer);
+}
+
+// Square wave for when an XO-CHIP program hasn't loaded its own pattern,
+// at the default pitch it comes out near the classic beep
+pub const DEFAULT_AUDIO_PATTERN: [u8; 16] = [
+    0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00,
+];
+pub const DEFAULT_PITCH: u8 = 64;
+
+/// Something that can make the single tone the CHIP-8 knows about.
+pub trait Audio {
+    fn start_beep(&mut self);
+    fn stop_beep(&mut self);
+
+    /// XO-CHIP programs can replace the tone with a 128 bit pattern, played
+    /// at 4000 * 2^((pitch - 64) / 48) bits a second.
+    fn set_pattern(&mut self, pattern: &[u8; 16], pitch: u8);
+}
+
+/// Returned by [`Keyboard::poll`] when the user asked to quit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quit;
+
+/// Things the user can ask the emulator itself to do, rather than the game.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    SaveState(u8),
+    LoadState(u8),
+}
+
+/// What the 16-key hex keypad did since the last poll.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyState {
+    /// Keys that are down right now
+    pub held: [bool; 16],
+    /// Keys that went down since the last poll
+    pub pressed: [bool; 16],
+    /// Keys that came back up since the last poll
+    pub released: [bool; 16],
+    /// Anything else that got asked for since the last poll
+    pub command: Option<Command>,
+    /// The rewind key is down, play backwards for as long as it is
+    pub rewind: bool,
+}
+
+/// Something that can report the state of the 16-key hex keypad.
+pub trait Keyboard {
+    fn poll(&mut self) -> Result<KeyState, Quit>;
+}
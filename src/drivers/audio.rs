@@ -1,8 +1,9 @@
-use sdl2;
 use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
 
+use super::{DEFAULT_AUDIO_PATTERN, DEFAULT_PITCH};
+
 pub struct AudioDriver {
-    device: AudioDevice<SquareWave>,
+    device: AudioDevice<PatternWave>,
 }
 
 impl AudioDriver {
@@ -17,47 +18,71 @@ impl AudioDriver {
 
         let device = audio_subsystem
             .open_playback(None, &desired_spec, |spec| {
-                // Show optained audio spec
-                println!("{:?}", spec);
+                // Show optained audio spec, on stderr since stdout might
+                // be a debug adapter's protocol stream
+                eprintln!("{:?}", spec);
 
                 // initialize the audio callback
-                SquareWave {
-                    phase_inc: 240.0 / spec.freq as f32,
+                let mut wave = PatternWave {
+                    pattern: [0; 16],
+                    sample_rate: spec.freq as f32,
+                    phase_inc: 0.0,
                     phase: 0.0,
                     volume: 0.25,
-                }
+                };
+                // Plain CHIP-8 never sets a pattern, so start out on a
+                // square wave near the classic beep
+                wave.set(&DEFAULT_AUDIO_PATTERN, DEFAULT_PITCH);
+                wave
             })
             .unwrap();
 
-        AudioDriver { device: device }
+        AudioDriver { device }
     }
+}
 
-    pub fn start_beep(&self) {
+impl super::Audio for AudioDriver {
+    fn start_beep(&mut self) {
         self.device.resume();
     }
 
-    pub fn stop_beep(&self) {
+    fn stop_beep(&mut self) {
         self.device.pause();
     }
+
+    fn set_pattern(&mut self, pattern: &[u8; 16], pitch: u8) {
+        self.device.lock().set(pattern, pitch);
+    }
 }
 
-struct SquareWave {
+// Plays a 128 bit XO-CHIP pattern on loop, one bit at a time
+struct PatternWave {
+    pattern: [u8; 16],
+    sample_rate: f32,
+    // Pattern bits per output sample
     phase_inc: f32,
+    // Position in the pattern, 0..128
     phase: f32,
     volume: f32,
 }
 
-impl AudioCallback for SquareWave {
+impl PatternWave {
+    fn set(&mut self, pattern: &[u8; 16], pitch: u8) {
+        let bits_per_second = 4000.0 * 2f32.powf((pitch as f32 - 64.0) / 48.0);
+        self.pattern = *pattern;
+        self.phase_inc = bits_per_second / self.sample_rate;
+    }
+}
+
+impl AudioCallback for PatternWave {
     type Channel = f32;
 
     fn callback(&mut self, out: &mut [f32]) {
-        // Generate a square wave
         for x in out.iter_mut() {
-            *x = match self.phase {
-                0.0...0.5 => self.volume,
-                _ => -self.volume,
-            };
-            self.phase = (self.phase + self.phase_inc) % 1.0;
+            let bit = self.phase as usize;
+            let on = (self.pattern[bit / 8] >> (7 - bit % 8)) & 1 == 1;
+            *x = if on { self.volume } else { -self.volume };
+            self.phase = (self.phase + self.phase_inc) % 128.0;
         }
     }
 }
@@ -1,9 +1,9 @@
-use sdl2;
 use sdl2::pixels;
 use sdl2::rect::Rect;
 use sdl2::render::Canvas;
 use sdl2::video::Window;
 
+use crate::framebuffer::Framebuffer;
 use crate::CHIP8_HEIGHT;
 use crate::CHIP8_WIDTH;
 
@@ -32,32 +32,36 @@ impl DisplayDriver {
         canvas.clear();
         canvas.present();
 
-        DisplayDriver { canvas: canvas }
+        DisplayDriver { canvas }
     }
+}
 
-    pub fn draw(&mut self, pixels: &[[u8; CHIP8_WIDTH as usize]; CHIP8_HEIGHT as usize]) {
-        for (y, row) in pixels.iter().enumerate() {
+impl super::Display for DisplayDriver {
+    fn draw(&mut self, frame: &Framebuffer) {
+        // The window stays the same size, hires pixels are just smaller
+        let scale = SCREEN_WIDTH / frame.width() as u32;
+        for (y, row) in frame.rows().enumerate() {
             for (x, &col) in row.iter().enumerate() {
-                let x = (x as u32) * SCALE_FACTOR;
-                let y = (y as u32) * SCALE_FACTOR;
+                let x = (x as u32) * scale;
+                let y = (y as u32) * scale;
 
                 self.canvas.set_draw_color(color(col));
-                let _ = self.canvas.fill_rect(Rect::new(
-                    x as i32,
-                    y as i32,
-                    SCALE_FACTOR,
-                    SCALE_FACTOR,
-                ));
+                let _ = self
+                    .canvas
+                    .fill_rect(Rect::new(x as i32, y as i32, scale, scale));
             }
         }
         self.canvas.present();
     }
 }
 
+// One colour per combination of XO-CHIP bitplanes, plain CHIP-8 only ever
+// uses the first two
 fn color(value: u8) -> pixels::Color {
-    if value == 0 {
-        pixels::Color::RGB(0, 0, 0)
-    } else {
-        pixels::Color::RGB(34, 139, 34)
+    match value {
+        0 => pixels::Color::RGB(0, 0, 0),
+        1 => pixels::Color::RGB(34, 139, 34),
+        2 => pixels::Color::RGB(152, 251, 152),
+        _ => pixels::Color::RGB(240, 255, 240),
     }
 }
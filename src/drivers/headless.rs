@@ -0,0 +1,31 @@
+use super::{Audio, Display, KeyState, Keyboard, Quit};
+
+use crate::framebuffer::Framebuffer;
+
+// Drivers that don't need a window, audio device or anything else.
+// Useful for tests and running ROMs on a box with no SDL.
+
+#[derive(Debug, Default)]
+pub struct NullDisplay;
+
+impl Display for NullDisplay {
+    fn draw(&mut self, _frame: &Framebuffer) {}
+}
+
+#[derive(Debug, Default)]
+pub struct NullAudio;
+
+impl Audio for NullAudio {
+    fn start_beep(&mut self) {}
+    fn stop_beep(&mut self) {}
+    fn set_pattern(&mut self, _pattern: &[u8; 16], _pitch: u8) {}
+}
+
+#[derive(Debug, Default)]
+pub struct NullKeyboard;
+
+impl Keyboard for NullKeyboard {
+    fn poll(&mut self) -> Result<KeyState, Quit> {
+        Ok(KeyState::default())
+    }
+}
@@ -1,7 +1,7 @@
-use sdl2;
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use std;
+use sdl2::keyboard::{self, Keycode};
+
+use super::{Command, KeyState, Quit};
 
 pub struct KeyboardDriver {
     events: sdl2::EventPump,
@@ -13,16 +13,46 @@ impl KeyboardDriver {
             events: sdl_context.event_pump().unwrap(),
         }
     }
+}
+
+impl super::Keyboard for KeyboardDriver {
+    fn poll(&mut self) -> Result<KeyState, Quit> {
+        let mut key_state = KeyState::default();
 
-    // return a Result<Array of keys>
-    pub fn poll(&mut self) -> Result<[bool; 16], ()> {
+        // Edges come from the event queue so a quick tap between two polls
+        // still shows up as a press and a release
         for event in self.events.poll_iter() {
             match event {
                 Event::Quit { .. }
                 | Event::KeyDown {
                     keycode: Some(Keycode::Escape),
                     ..
-                } => return Err(()),
+                } => return Err(Quit),
+                Event::KeyDown {
+                    keycode: Some(key),
+                    keymod,
+                    repeat: false,
+                    ..
+                } => {
+                    if let Some(i) = keypad_index(key) {
+                        key_state.pressed[i] = true;
+                    } else if let Some(slot) = state_slot(key) {
+                        // F1-F8 saves, shift + F1-F8 loads
+                        let shift = keymod.intersects(keyboard::LSHIFTMOD | keyboard::RSHIFTMOD);
+                        key_state.command = Some(if shift {
+                            Command::LoadState(slot)
+                        } else {
+                            Command::SaveState(slot)
+                        });
+                    }
+                }
+                Event::KeyUp {
+                    keycode: Some(key), ..
+                } => {
+                    if let Some(i) = keypad_index(key) {
+                        key_state.released[i] = true;
+                    }
+                }
                 _ => (),
             }
         }
@@ -35,34 +65,50 @@ impl KeyboardDriver {
             .filter_map(Keycode::from_scancode)
             .collect();
 
-        let mut key_state = [false; 16];
-
         for key in keys {
-            let index = match key {
-                Keycode::Num1 => Some(0x1),
-                Keycode::Num2 => Some(0x2),
-                Keycode::Num3 => Some(0x3),
-                Keycode::Num4 => Some(0xc),
-                Keycode::Q => Some(0x4),
-                Keycode::W => Some(0x5),
-                Keycode::E => Some(0x6),
-                Keycode::R => Some(0xd),
-                Keycode::A => Some(0x7),
-                Keycode::S => Some(0x8),
-                Keycode::D => Some(0x9),
-                Keycode::F => Some(0xe),
-                Keycode::Z => Some(0xa),
-                Keycode::X => Some(0x0),
-                Keycode::C => Some(0xb),
-                Keycode::V => Some(0xf),
-                _ => None,
-            };
-
-            if let Some(i) = index {
-                key_state[i] = true;
+            if let Some(i) = keypad_index(key) {
+                key_state.held[i] = true;
+            } else if key == Keycode::Backspace {
+                key_state.rewind = true;
             }
         }
 
         Ok(key_state)
     }
 }
+
+fn state_slot(key: Keycode) -> Option<u8> {
+    match key {
+        Keycode::F1 => Some(1),
+        Keycode::F2 => Some(2),
+        Keycode::F3 => Some(3),
+        Keycode::F4 => Some(4),
+        Keycode::F5 => Some(5),
+        Keycode::F6 => Some(6),
+        Keycode::F7 => Some(7),
+        Keycode::F8 => Some(8),
+        _ => None,
+    }
+}
+
+fn keypad_index(key: Keycode) -> Option<usize> {
+    match key {
+        Keycode::Num1 => Some(0x1),
+        Keycode::Num2 => Some(0x2),
+        Keycode::Num3 => Some(0x3),
+        Keycode::Num4 => Some(0xc),
+        Keycode::Q => Some(0x4),
+        Keycode::W => Some(0x5),
+        Keycode::E => Some(0x6),
+        Keycode::R => Some(0xd),
+        Keycode::A => Some(0x7),
+        Keycode::S => Some(0x8),
+        Keycode::D => Some(0x9),
+        Keycode::F => Some(0xe),
+        Keycode::Z => Some(0xa),
+        Keycode::X => Some(0x0),
+        Keycode::C => Some(0xb),
+        Keycode::V => Some(0xf),
+        _ => None,
+    }
+}
@@ -0,0 +1,76 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::stack::StackError;
+
+/// Something the program did that the interpreter can't carry on from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Not an instruction we know, including running into a 0x0000 word
+    UnknownOpcode,
+    /// Read or write past the end of RAM
+    MemoryOutOfBounds {
+        address: usize,
+    },
+    Stack(StackError),
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::UnknownOpcode => write!(f, "unknown opcode"),
+            Fault::MemoryOutOfBounds { address } => {
+                write!(f, "memory access out of bounds at {:#06x}", address)
+            }
+            Fault::Stack(e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for Fault {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Fault::Stack(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<StackError> for Fault {
+    fn from(e: StackError) -> Fault {
+        Fault::Stack(e)
+    }
+}
+
+/// Why the interpreter stopped running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    /// The user closed the window or hit escape
+    Quit,
+    /// The program asked to stop (SUPER-CHIP 00FD)
+    ProgramExit,
+    /// The program broke, `pc` and `opcode` are the instruction that did it
+    Fault { pc: u16, opcode: u16, fault: Fault },
+}
+
+impl ExitReason {
+    /// Something for `std::process::exit`.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ExitReason::Quit | ExitReason::ProgramExit => 0,
+            ExitReason::Fault { .. } => 2,
+        }
+    }
+}
+
+impl fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExitReason::Quit => write!(f, "quit"),
+            ExitReason::ProgramExit => write!(f, "program exited"),
+            ExitReason::Fault { pc, opcode, fault } => {
+                write!(f, "fault at {:04x} (opcode {:04x}): {}", pc, opcode, fault)
+            }
+        }
+    }
+}
@@ -0,0 +1,311 @@
+use crate::{CHIP8_HEIGHT, CHIP8_WIDTH, SCHIP_HEIGHT, SCHIP_WIDTH};
+
+/// The screen. Always big enough for SUPER-CHIP's 128x64 high resolution
+/// mode, in low resolution only the top left 64x32 is used.
+///
+/// Each pixel holds one bit per XO-CHIP bitplane, so a pixel is 0-3 and
+/// plain CHIP-8 only ever uses the first plane.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Framebuffer {
+    hires: bool,
+    // FN01, bitmask of the planes that drawing, clearing and scrolling touch
+    planes: u8,
+    pixels: Box<[[u8; SCHIP_WIDTH]; SCHIP_HEIGHT]>,
+}
+
+impl Framebuffer {
+    pub const PLANE_COUNT: usize = 2;
+
+    pub fn new() -> Framebuffer {
+        Framebuffer {
+            hires: false,
+            planes: 0b01,
+            pixels: Box::new([[0; SCHIP_WIDTH]; SCHIP_HEIGHT]),
+        }
+    }
+
+    // Save states need the whole thing, visible or not
+    pub(crate) fn from_raw(
+        hires: bool,
+        planes: u8,
+        pixels: Box<[[u8; SCHIP_WIDTH]; SCHIP_HEIGHT]>,
+    ) -> Framebuffer {
+        Framebuffer {
+            hires,
+            planes,
+            pixels,
+        }
+    }
+
+    pub(crate) fn raw(&self) -> &[[u8; SCHIP_WIDTH]; SCHIP_HEIGHT] {
+        &self.pixels
+    }
+
+    pub fn hires(&self) -> bool {
+        self.hires
+    }
+
+    /// Switch resolution, which also clears every plane.
+    pub fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        *self.pixels = [[0; SCHIP_WIDTH]; SCHIP_HEIGHT];
+    }
+
+    pub fn planes(&self) -> u8 {
+        self.planes
+    }
+
+    pub fn set_planes(&mut self, planes: u8) {
+        self.planes = planes & 0b11;
+    }
+
+    pub fn width(&self) -> usize {
+        if self.hires {
+            SCHIP_WIDTH
+        } else {
+            CHIP8_WIDTH
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        if self.hires {
+            SCHIP_HEIGHT
+        } else {
+            CHIP8_HEIGHT
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> u8 {
+        self.pixels[y][x]
+    }
+
+    /// The visible rows, each `width()` pixels long.
+    pub fn rows(&self) -> impl Iterator<Item = &[u8]> {
+        let width = self.width();
+        self.pixels[..self.height()]
+            .iter()
+            .map(move |row| &row[..width])
+    }
+
+    /// Clear the selected planes.
+    pub fn clear(&mut self) {
+        let keep = !self.planes;
+        for row in self.pixels.iter_mut() {
+            row.iter_mut().for_each(|p| *p &= keep);
+        }
+    }
+
+    /// How many bytes of sprite data a DXYN draw reads with the current
+    /// plane selection.
+    pub fn sprite_len(&self, rows: usize, wide: bool) -> usize {
+        let bytes_per_row = if wide { 2 } else { 1 };
+        rows * bytes_per_row * self.planes.count_ones() as usize
+    }
+
+    /// XOR a sprite onto the selected planes, returning how many of its rows
+    /// turned a lit pixel off, 0 if none did. Sprites are 8 pixels wide, one
+    /// byte a row, or 16 pixels wide and two bytes a row when `wide` is set.
+    /// With more than one plane selected the data for each plane follows
+    /// the last.
+    pub fn draw_sprite(
+        &mut self,
+        x: usize,
+        y: usize,
+        sprite: &[u8],
+        rows: usize,
+        wide: bool,
+        wrap: bool,
+    ) -> usize {
+        let (width, height) = (self.width(), self.height());
+        let sprite_width = if wide { 16 } else { 8 };
+        let bytes_per_row = sprite_width / 8;
+
+        // The starting position always wraps, the rest of the sprite either
+        // wraps too or gets clipped at the edges.
+        let x0 = x % width;
+        let y0 = y % height;
+        let mut collisions = 0;
+        let mut plane_data = sprite.chunks(rows * bytes_per_row);
+        for plane in 0..Self::PLANE_COUNT {
+            let mask = 1 << plane;
+            if self.planes & mask == 0 {
+                continue;
+            }
+            let data = plane_data.next().unwrap_or(&[]);
+
+            for (row, bytes) in data.chunks(bytes_per_row).enumerate() {
+                let mut y = y0 + row;
+                if y >= height {
+                    if !wrap {
+                        break;
+                    }
+                    y %= height;
+                }
+                let bits = bytes.iter().fold(0u16, |acc, &b| (acc << 8) | b as u16);
+                let mut collision = false;
+                for bit in 0..sprite_width {
+                    let mut x = x0 + bit;
+                    if x >= width {
+                        if !wrap {
+                            break;
+                        }
+                        x %= width;
+                    }
+                    if (bits >> (sprite_width - 1 - bit)) & 1 == 1 {
+                        collision |= self.pixels[y][x] & mask != 0;
+                        self.pixels[y][x] ^= mask;
+                    }
+                }
+                collisions += collision as usize;
+            }
+        }
+        collisions
+    }
+
+    /// 00CN, scroll the selected planes down `n` pixels.
+    pub fn scroll_down(&mut self, n: usize) {
+        self.scroll(0, n as isize);
+    }
+
+    /// 00DN, scroll the selected planes up `n` pixels.
+    pub fn scroll_up(&mut self, n: usize) {
+        self.scroll(0, -(n as isize));
+    }
+
+    /// 00FB, scroll the selected planes right 4 pixels.
+    pub fn scroll_right(&mut self) {
+        self.scroll(4, 0);
+    }
+
+    /// 00FC, scroll the selected planes left 4 pixels.
+    pub fn scroll_left(&mut self) {
+        self.scroll(-4, 0);
+    }
+
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let (width, height) = (self.width() as isize, self.height() as isize);
+        let planes = self.planes;
+        let old = self.pixels.clone();
+        for y in 0..height {
+            for x in 0..width {
+                let (from_x, from_y) = (x - dx, y - dy);
+                let moved = if from_x >= 0 && from_x < width && from_y >= 0 && from_y < height {
+                    old[from_y as usize][from_x as usize] & planes
+                } else {
+                    0
+                };
+                let pixel = &mut self.pixels[y as usize][x as usize];
+                *pixel = (*pixel & !planes) | moved;
+            }
+        }
+    }
+}
+
+impl Default for Framebuffer {
+    fn default() -> Framebuffer {
+        Framebuffer::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Framebuffer;
+
+    fn dot(x: usize, y: usize, hires: bool) -> Framebuffer {
+        let mut framebuffer = Framebuffer::new();
+        framebuffer.set_hires(hires);
+        framebuffer.draw_sprite(x, y, &[0x80], 1, false, false);
+        framebuffer
+    }
+
+    // Where the only lit pixel is
+    fn lit(framebuffer: &Framebuffer) -> Option<(usize, usize)> {
+        let mut lit = None;
+        for (y, row) in framebuffer.rows().enumerate() {
+            for (x, &pixel) in row.iter().enumerate() {
+                if pixel != 0 {
+                    assert_eq!(lit, None, "more than one pixel lit");
+                    lit = Some((x, y));
+                }
+            }
+        }
+        lit
+    }
+
+    #[test]
+    fn scrolling() {
+        for &(hires, x, y) in &[(false, 10, 4), (true, 100, 50)] {
+            let mut framebuffer = dot(x, y, hires);
+            framebuffer.scroll_down(3);
+            assert_eq!(lit(&framebuffer), Some((x, y + 3)));
+            framebuffer.scroll_up(3);
+            assert_eq!(lit(&framebuffer), Some((x, y)));
+            framebuffer.scroll_right();
+            assert_eq!(lit(&framebuffer), Some((x + 4, y)));
+            framebuffer.scroll_left();
+            framebuffer.scroll_left();
+            assert_eq!(lit(&framebuffer), Some((x - 4, y)));
+        }
+    }
+
+    // What goes off the edge is gone, it doesn't come back round or hide
+    // outside the low resolution screen
+    #[test]
+    fn scrolling_off_the_edge() {
+        let mut framebuffer = dot(0, 31, false);
+        framebuffer.scroll_down(1);
+        assert_eq!(lit(&framebuffer), None);
+        framebuffer.set_hires(false);
+        assert_eq!(framebuffer.get(0, 32), 0);
+
+        let mut framebuffer = dot(126, 0, true);
+        framebuffer.scroll_right();
+        assert_eq!(lit(&framebuffer), None);
+        let mut framebuffer = dot(2, 63, true);
+        framebuffer.scroll_left();
+        assert_eq!(lit(&framebuffer), None);
+    }
+
+    #[test]
+    fn switching_resolution_clears() {
+        let mut framebuffer = dot(5, 5, false);
+        assert_eq!((framebuffer.width(), framebuffer.height()), (64, 32));
+        framebuffer.set_hires(true);
+        assert_eq!((framebuffer.width(), framebuffer.height()), (128, 64));
+        assert_eq!(lit(&framebuffer), None);
+        framebuffer.draw_sprite(5, 5, &[0x80], 1, false, false);
+        framebuffer.set_hires(false);
+        assert_eq!(lit(&framebuffer), None);
+    }
+
+    #[test]
+    fn sixteen_by_sixteen() {
+        let mut framebuffer = Framebuffer::new();
+        framebuffer.set_hires(true);
+        assert_eq!(framebuffer.sprite_len(16, true), 32);
+        assert_eq!(
+            framebuffer.draw_sprite(8, 4, &[0xFF; 32], 16, true, false),
+            0
+        );
+        let lit: Vec<_> = framebuffer
+            .rows()
+            .map(|row| row.iter().sum::<u8>())
+            .collect();
+        assert!(lit[..4].iter().all(|&count| count == 0));
+        assert!(lit[4..20].iter().all(|&count| count == 16));
+        assert_eq!(framebuffer.get(8, 4), 1);
+        assert_eq!(framebuffer.get(23, 19), 1);
+        assert_eq!(framebuffer.get(24, 19), 0);
+
+        // Every row hits, then half of them
+        assert_eq!(
+            framebuffer.draw_sprite(8, 4, &[0xFF; 32], 16, true, false),
+            16
+        );
+        framebuffer.draw_sprite(8, 4, &[0xFF; 16], 8, true, false);
+        assert_eq!(
+            framebuffer.draw_sprite(8, 4, &[0xFF; 32], 16, true, false),
+            8
+        );
+    }
+}
@@ -0,0 +1,438 @@
+//! Instructions, decoded.
+//!
+//! [`Instruction::decode`] is the one place opcodes get taken apart; the
+//! interpreter runs what it returns, the trace and the disassembler print
+//! it, and the assembler builds it back up with [`Instruction::encode`].
+//! Every instruction any of the supported machines has is here, whether the
+//! current [`Quirks`](crate::Quirks) allow it to run is up to the
+//! interpreter.
+
+use std::fmt;
+
+/// One instruction. `x` and `y` are register numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    /// 00E0 CLS
+    Cls,
+    /// 00EE RET
+    Ret,
+    /// 00CN SCD n
+    ScrollDown(u8),
+    /// 00DN SCU n
+    ScrollUp(u8),
+    /// 00FB SCR
+    ScrollRight,
+    /// 00FC SCL
+    ScrollLeft,
+    /// 00FD EXIT
+    Exit,
+    /// 00FE LOW
+    Low,
+    /// 00FF HIGH
+    High,
+    /// 1NNN JP addr
+    Jump(u16),
+    /// 2NNN CALL addr
+    Call(u16),
+    /// 3XNN SE Vx, byte
+    SkipEqual { x: u8, value: u8 },
+    /// 4XNN SNE Vx, byte
+    SkipNotEqual { x: u8, value: u8 },
+    /// 5XY0 SE Vx, Vy
+    SkipEqualRegister { x: u8, y: u8 },
+    /// 5XY2 SAVE Vx, Vy
+    Save { x: u8, y: u8 },
+    /// 5XY3 LOAD Vx, Vy
+    Load { x: u8, y: u8 },
+    /// 6XNN LD Vx, byte
+    LoadByte { x: u8, value: u8 },
+    /// 7XNN ADD Vx, byte
+    AddByte { x: u8, value: u8 },
+    /// 8XY0 LD Vx, Vy
+    Move { x: u8, y: u8 },
+    /// 8XY1 OR Vx, Vy
+    Or { x: u8, y: u8 },
+    /// 8XY2 AND Vx, Vy
+    And { x: u8, y: u8 },
+    /// 8XY3 XOR Vx, Vy
+    Xor { x: u8, y: u8 },
+    /// 8XY4 ADD Vx, Vy
+    Add { x: u8, y: u8 },
+    /// 8XY5 SUB Vx, Vy
+    Sub { x: u8, y: u8 },
+    /// 8XY6 SHR Vx, Vy
+    ShiftRight { x: u8, y: u8 },
+    /// 8XY7 SUBN Vx, Vy
+    SubN { x: u8, y: u8 },
+    /// 8XYE SHL Vx, Vy
+    ShiftLeft { x: u8, y: u8 },
+    /// 9XY0 SNE Vx, Vy
+    SkipNotEqualRegister { x: u8, y: u8 },
+    /// ANNN LD I, addr
+    LoadI(u16),
+    /// BNNN JP V0, addr
+    JumpV0(u16),
+    /// CXNN RND Vx, byte
+    Random { x: u8, mask: u8 },
+    /// DXYN DRW Vx, Vy, n
+    Draw { x: u8, y: u8, rows: u8 },
+    /// EX9E SKP Vx
+    SkipKey(u8),
+    /// EXA1 SKNP Vx
+    SkipNotKey(u8),
+    /// F000 NNNN LD I, long addr. The address is the next word, so this is
+    /// the only four byte instruction.
+    LoadILong,
+    /// FN01 PLANE n
+    Plane(u8),
+    /// F002 AUDIO
+    Audio,
+    /// FX07 LD Vx, DT
+    GetDelay(u8),
+    /// FX0A LD Vx, K
+    WaitKey(u8),
+    /// FX15 LD DT, Vx
+    SetDelay(u8),
+    /// FX18 LD ST, Vx
+    SetSound(u8),
+    /// FX1E ADD I, Vx
+    AddI(u8),
+    /// FX29 LD F, Vx
+    Font(u8),
+    /// FX30 LD HF, Vx
+    BigFont(u8),
+    /// FX33 LD B, Vx
+    Bcd(u8),
+    /// FX3A PITCH Vx
+    Pitch(u8),
+    /// FX55 LD [I], Vx
+    StoreRegisters(u8),
+    /// FX65 LD Vx, [I]
+    LoadRegisters(u8),
+    /// FX75 LD R, Vx
+    SaveFlags(u8),
+    /// FX85 LD Vx, R
+    LoadFlags(u8),
+}
+
+impl Instruction {
+    /// None if `op` isn't an instruction at all.
+    pub fn decode(op: u16) -> Option<Instruction> {
+        use self::Instruction::*;
+
+        let x = (op >> 8 & 0xF) as u8;
+        let y = (op >> 4 & 0xF) as u8;
+        let n = (op & 0xF) as u8;
+        let nn = (op & 0xFF) as u8;
+        let nnn = op & 0xFFF;
+
+        let instruction = match op >> 12 {
+            0x0 => match op {
+                0x00E0 => Cls,
+                0x00EE => Ret,
+                0x00C0..=0x00CF => ScrollDown(n),
+                0x00D0..=0x00DF => ScrollUp(n),
+                0x00FB => ScrollRight,
+                0x00FC => ScrollLeft,
+                0x00FD => Exit,
+                0x00FE => Low,
+                0x00FF => High,
+                _ => return None,
+            },
+            0x1 => Jump(nnn),
+            0x2 => Call(nnn),
+            0x3 => SkipEqual { x, value: nn },
+            0x4 => SkipNotEqual { x, value: nn },
+            0x5 => match n {
+                0x0 => SkipEqualRegister { x, y },
+                0x2 => Save { x, y },
+                0x3 => Load { x, y },
+                _ => return None,
+            },
+            0x6 => LoadByte { x, value: nn },
+            0x7 => AddByte { x, value: nn },
+            0x8 => match n {
+                0x0 => Move { x, y },
+                0x1 => Or { x, y },
+                0x2 => And { x, y },
+                0x3 => Xor { x, y },
+                0x4 => Add { x, y },
+                0x5 => Sub { x, y },
+                0x6 => ShiftRight { x, y },
+                0x7 => SubN { x, y },
+                0xE => ShiftLeft { x, y },
+                _ => return None,
+            },
+            0x9 if n == 0 => SkipNotEqualRegister { x, y },
+            0xA => LoadI(nnn),
+            0xB => JumpV0(nnn),
+            0xC => Random { x, mask: nn },
+            0xD => Draw { x, y, rows: n },
+            0xE => match nn {
+                0x9E => SkipKey(x),
+                0xA1 => SkipNotKey(x),
+                _ => return None,
+            },
+            0xF => match nn {
+                0x00 if x == 0 => LoadILong,
+                0x01 => Plane(x),
+                0x02 if x == 0 => Audio,
+                0x07 => GetDelay(x),
+                0x0A => WaitKey(x),
+                0x15 => SetDelay(x),
+                0x18 => SetSound(x),
+                0x1E => AddI(x),
+                0x29 => Font(x),
+                0x30 => BigFont(x),
+                0x33 => Bcd(x),
+                0x3A => Pitch(x),
+                0x55 => StoreRegisters(x),
+                0x65 => LoadRegisters(x),
+                0x75 => SaveFlags(x),
+                0x85 => LoadFlags(x),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some(instruction)
+    }
+
+    /// The opcode, the first word of it for [`Instruction::LoadILong`].
+    /// Fields too big for their part of the opcode get cut down to size.
+    pub fn encode(&self) -> u16 {
+        use self::Instruction::*;
+
+        let xy = |x: u8, y: u8| ((x & 0xF) as u16) << 8 | ((y & 0xF) as u16) << 4;
+        let xnn = |x: u8, nn: u8| ((x & 0xF) as u16) << 8 | nn as u16;
+        match *self {
+            Cls => 0x00E0,
+            Ret => 0x00EE,
+            ScrollDown(n) => 0x00C0 | (n & 0xF) as u16,
+            ScrollUp(n) => 0x00D0 | (n & 0xF) as u16,
+            ScrollRight => 0x00FB,
+            ScrollLeft => 0x00FC,
+            Exit => 0x00FD,
+            Low => 0x00FE,
+            High => 0x00FF,
+            Jump(nnn) => 0x1000 | nnn & 0xFFF,
+            Call(nnn) => 0x2000 | nnn & 0xFFF,
+            SkipEqual { x, value } => 0x3000 | xnn(x, value),
+            SkipNotEqual { x, value } => 0x4000 | xnn(x, value),
+            SkipEqualRegister { x, y } => 0x5000 | xy(x, y),
+            Save { x, y } => 0x5002 | xy(x, y),
+            Load { x, y } => 0x5003 | xy(x, y),
+            LoadByte { x, value } => 0x6000 | xnn(x, value),
+            AddByte { x, value } => 0x7000 | xnn(x, value),
+            Move { x, y } => 0x8000 | xy(x, y),
+            Or { x, y } => 0x8001 | xy(x, y),
+            And { x, y } => 0x8002 | xy(x, y),
+            Xor { x, y } => 0x8003 | xy(x, y),
+            Add { x, y } => 0x8004 | xy(x, y),
+            Sub { x, y } => 0x8005 | xy(x, y),
+            ShiftRight { x, y } => 0x8006 | xy(x, y),
+            SubN { x, y } => 0x8007 | xy(x, y),
+            ShiftLeft { x, y } => 0x800E | xy(x, y),
+            SkipNotEqualRegister { x, y } => 0x9000 | xy(x, y),
+            LoadI(nnn) => 0xA000 | nnn & 0xFFF,
+            JumpV0(nnn) => 0xB000 | nnn & 0xFFF,
+            Random { x, mask } => 0xC000 | xnn(x, mask),
+            Draw { x, y, rows } => 0xD000 | xy(x, y) | (rows & 0xF) as u16,
+            SkipKey(x) => 0xE09E | xy(x, 0),
+            SkipNotKey(x) => 0xE0A1 | xy(x, 0),
+            LoadILong => 0xF000,
+            Plane(n) => 0xF001 | xy(n, 0),
+            Audio => 0xF002,
+            GetDelay(x) => 0xF007 | xy(x, 0),
+            WaitKey(x) => 0xF00A | xy(x, 0),
+            SetDelay(x) => 0xF015 | xy(x, 0),
+            SetSound(x) => 0xF018 | xy(x, 0),
+            AddI(x) => 0xF01E | xy(x, 0),
+            Font(x) => 0xF029 | xy(x, 0),
+            BigFont(x) => 0xF030 | xy(x, 0),
+            Bcd(x) => 0xF033 | xy(x, 0),
+            Pitch(x) => 0xF03A | xy(x, 0),
+            StoreRegisters(x) => 0xF055 | xy(x, 0),
+            LoadRegisters(x) => 0xF065 | xy(x, 0),
+            SaveFlags(x) => 0xF075 | xy(x, 0),
+            LoadFlags(x) => 0xF085 | xy(x, 0),
+        }
+    }
+
+    /// In bytes.
+    pub fn size(&self) -> u16 {
+        match self {
+            Instruction::LoadILong => 4,
+            _ => 2,
+        }
+    }
+
+    /// True for the instructions that might skip the next one.
+    pub fn is_skip(&self) -> bool {
+        use self::Instruction::*;
+
+        matches!(
+            self,
+            SkipEqual { .. }
+                | SkipNotEqual { .. }
+                | SkipEqualRegister { .. }
+                | SkipNotEqualRegister { .. }
+                | SkipKey(_)
+                | SkipNotKey(_)
+        )
+    }
+
+    /// The classic mnemonics, with addresses written by `name` so the
+    /// disassembler can put labels in. `long` is the word after an F000.
+    pub(crate) fn classic(&self, long: Option<u16>, name: &dyn Fn(u16) -> String) -> String {
+        use self::Instruction::*;
+
+        match *self {
+            Cls => "CLS".to_string(),
+            Ret => "RET".to_string(),
+            ScrollDown(n) => format!("SCD 0x{:x}", n),
+            ScrollUp(n) => format!("SCU 0x{:x}", n),
+            ScrollRight => "SCR".to_string(),
+            ScrollLeft => "SCL".to_string(),
+            Exit => "EXIT".to_string(),
+            Low => "LOW".to_string(),
+            High => "HIGH".to_string(),
+            Jump(nnn) => format!("JP {}", name(nnn)),
+            Call(nnn) => format!("CALL {}", name(nnn)),
+            SkipEqual { x, value } => format!("SE V{:X}, 0x{:02x}", x, value),
+            SkipNotEqual { x, value } => format!("SNE V{:X}, 0x{:02x}", x, value),
+            SkipEqualRegister { x, y } => format!("SE V{:X}, V{:X}", x, y),
+            Save { x, y } => format!("SAVE V{:X}, V{:X}", x, y),
+            Load { x, y } => format!("LOAD V{:X}, V{:X}", x, y),
+            LoadByte { x, value } => format!("LD V{:X}, 0x{:02x}", x, value),
+            AddByte { x, value } => format!("ADD V{:X}, 0x{:02x}", x, value),
+            Move { x, y } => format!("LD V{:X}, V{:X}", x, y),
+            Or { x, y } => format!("OR V{:X}, V{:X}", x, y),
+            And { x, y } => format!("AND V{:X}, V{:X}", x, y),
+            Xor { x, y } => format!("XOR V{:X}, V{:X}", x, y),
+            Add { x, y } => format!("ADD V{:X}, V{:X}", x, y),
+            Sub { x, y } => format!("SUB V{:X}, V{:X}", x, y),
+            ShiftRight { x, y } => format!("SHR V{:X}, V{:X}", x, y),
+            SubN { x, y } => format!("SUBN V{:X}, V{:X}", x, y),
+            ShiftLeft { x, y } => format!("SHL V{:X}, V{:X}", x, y),
+            SkipNotEqualRegister { x, y } => format!("SNE V{:X}, V{:X}", x, y),
+            LoadI(nnn) => format!("LD I, {}", name(nnn)),
+            JumpV0(nnn) => format!("JP V0, {}", name(nnn)),
+            Random { x, mask } => format!("RND V{:X}, 0x{:02x}", x, mask),
+            Draw { x, y, rows } => format!("DRW V{:X}, V{:X}, 0x{:x}", x, y, rows),
+            SkipKey(x) => format!("SKP V{:X}", x),
+            SkipNotKey(x) => format!("SKNP V{:X}", x),
+            LoadILong => match long {
+                Some(address) => format!("LD I, long {}", name(address)),
+                None => "LD I, long".to_string(),
+            },
+            Plane(n) => format!("PLANE 0x{:x}", n),
+            Audio => "AUDIO".to_string(),
+            GetDelay(x) => format!("LD V{:X}, DT", x),
+            WaitKey(x) => format!("LD V{:X}, K", x),
+            SetDelay(x) => format!("LD DT, V{:X}", x),
+            SetSound(x) => format!("LD ST, V{:X}", x),
+            AddI(x) => format!("ADD I, V{:X}", x),
+            Font(x) => format!("LD F, V{:X}", x),
+            BigFont(x) => format!("LD HF, V{:X}", x),
+            Bcd(x) => format!("LD B, V{:X}", x),
+            Pitch(x) => format!("PITCH V{:X}", x),
+            StoreRegisters(x) => format!("LD [I], V{:X}", x),
+            LoadRegisters(x) => format!("LD V{:X}, [I]", x),
+            SaveFlags(x) => format!("LD R, V{:X}", x),
+            LoadFlags(x) => format!("LD V{:X}, R", x),
+        }
+    }
+}
+
+/// The classic mnemonics, with addresses in hex.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.classic(None, &|address| format!("0x{:03x}", address)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::fault::{ExitReason, Fault};
+    use crate::{Processor, Quirks};
+
+    fn words() -> impl Iterator<Item = u16> {
+        0..=0xFFFF
+    }
+
+    #[test]
+    fn decode_then_encode_gives_the_same_word() {
+        for op in words() {
+            if let Some(instruction) = Instruction::decode(op) {
+                assert_eq!(instruction.encode(), op, "{:04x} {}", op, instruction);
+            }
+        }
+    }
+
+    #[test]
+    fn display_assembles_back_to_the_same_word() {
+        for op in words() {
+            let instruction = match Instruction::decode(op) {
+                Some(instruction) => instruction,
+                None => continue,
+            };
+            let text = instruction.classic(Some(0x1234), &|a| format!("0x{:x}", a));
+            let assembly = assemble(&text).unwrap_or_else(|e| panic!("{:04x} {}: {}", op, text, e));
+            let mut expected = op.to_be_bytes().to_vec();
+            if instruction == Instruction::LoadILong {
+                expected.extend(&[0x12, 0x34]);
+            }
+            assert_eq!(assembly.bytes(), &expected[..], "{:04x} {}", op, text);
+        }
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(Instruction::decode(0xF000).map(|i| i.size()), Some(4));
+        assert!(words()
+            .filter(|&op| op != 0xF000)
+            .filter_map(Instruction::decode)
+            .all(|instruction| instruction.size() == 2));
+    }
+
+    // Some words that look close to instructions but aren't
+    #[test]
+    fn near_misses_dont_decode() {
+        for &op in &[
+            0x0000, 0x00E1, 0x00FA, 0x5001, 0x5004, 0x800F, 0x8008, 0x9001, 0xE09F, 0xF100, 0xF102,
+            0xF0FF,
+        ] {
+            assert_eq!(Instruction::decode(op), None, "{:04x}", op);
+        }
+    }
+
+    // With every extension turned on, the interpreter should run exactly
+    // the words that decode
+    #[test]
+    fn the_interpreter_runs_what_decodes() {
+        let mut cpu = Processor::headless();
+        cpu.set_quirks(Quirks::XO_CHIP);
+        for op in words() {
+            cpu.reset();
+            cpu.load_rom_bytes(&[(op >> 8) as u8, op as u8, 0x12, 0x34])
+                .unwrap();
+            let result = cpu.step();
+            let unknown = matches!(
+                result,
+                Err(ExitReason::Fault {
+                    fault: Fault::UnknownOpcode,
+                    ..
+                })
+            );
+            assert_eq!(
+                unknown,
+                Instruction::decode(op).is_none(),
+                "{:04x} {:?}",
+                op,
+                result
+            );
+        }
+    }
+}
@@ -0,0 +1,69 @@
+//! A CHIP-8 interpreter.
+//!
+//! The interpreter itself doesn't care where its pixels, beeps and key
+//! presses go; hand [`Processor::new`] whatever [`drivers`] you like, or
+//! use [`Processor::headless`] to run without any.
+//!
+//! ```
+//! use chippe_rs::Processor;
+//!
+//! let mut cpu = Processor::headless();
+//! cpu.reset();
+//! cpu.load_rom_bytes(&[0x60, 0x2a]).unwrap(); // LD V0, 2a
+//! cpu.step().unwrap();
+//! assert_eq!(cpu.registers()[0], 0x2a);
+//! ```
+
+#[cfg(feature = "sdl")]
+extern crate sdl2;
+
+pub mod asm;
+mod cache;
+pub mod debugger;
+pub mod disasm;
+pub mod drivers;
+pub mod fault;
+mod font;
+pub mod framebuffer;
+pub mod instruction;
+pub mod movie;
+mod processor;
+pub mod quirks;
+pub mod random;
+pub mod recompile;
+pub mod rewind;
+pub mod rom;
+pub mod savestate;
+pub mod stack;
+#[cfg(test)]
+mod testing;
+pub mod timing;
+pub mod trace;
+
+pub use crate::debugger::Debugger;
+pub use crate::disasm::{Disassembly, Syntax};
+pub use crate::fault::{ExitReason, Fault};
+pub use crate::framebuffer::Framebuffer;
+pub use crate::instruction::Instruction;
+pub use crate::movie::{Movie, MovieError};
+pub use crate::processor::{
+    Processor, DEFAULT_INSTRUCTIONS_PER_FRAME, DEFAULT_LOAD_ADDRESS, DEFAULT_STATE_DIR,
+    FRAMES_PER_SECOND,
+};
+pub use crate::quirks::Quirks;
+pub use crate::random::Random;
+pub use crate::rewind::RewindBuffer;
+pub use crate::rom::{Rom, RomError};
+pub use crate::savestate::{SaveState, SaveStateError};
+pub use crate::timing::Timing;
+
+pub const RAM_SIZE: usize = 4 * 1024; // 4 KB
+pub const XO_RAM_SIZE: usize = 64 * 1024; // 64 KB for XO-CHIP
+pub const GPR_SIZE: usize = 16;
+
+pub const CHIP8_WIDTH: usize = 64;
+pub const CHIP8_HEIGHT: usize = 32;
+
+// SUPER-CHIP high resolution mode
+pub const SCHIP_WIDTH: usize = 128;
+pub const SCHIP_HEIGHT: usize = 64;
@@ -1,24 +1,510 @@
+use std::convert::TryFrom;
 use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::net::TcpListener;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::process;
 
-extern crate sdl2;
-mod drivers;
-mod font;
+use chippe_rs::asm;
+use chippe_rs::debugger::dap::DapServer;
+use chippe_rs::debugger::gdb::GdbServer;
+use chippe_rs::recompile::recompile as recompile_rom;
+use chippe_rs::rewind::{DEFAULT_REWIND_INTERVAL, DEFAULT_REWIND_SECONDS};
+use chippe_rs::trace::{TraceLevel, Tracer};
+use chippe_rs::{
+    Debugger, Disassembly, ExitReason, Movie, Processor, Quirks, Random, RewindBuffer, Rom,
+    SaveState, Syntax, Timing, DEFAULT_INSTRUCTIONS_PER_FRAME, DEFAULT_LOAD_ADDRESS,
+};
 
-mod processor;
-use processor::Processor;
+fn main() {
+    let mut args = env::args().skip(1).peekable();
+    match args.peek().map(String::as_str) {
+        Some("asm") => {
+            args.next();
+            asm(args);
+        }
+        Some("disasm") => {
+            args.next();
+            disasm(args);
+        }
+        Some("recompile") => {
+            args.next();
+            recompile(args);
+        }
+        _ => (),
+    }
+    let mut headless = cfg!(not(feature = "sdl"));
+    let mut instructions_per_frame = DEFAULT_INSTRUCTIONS_PER_FRAME;
+    let mut quirks = Quirks::default();
+    let mut stack_in_ram = false;
+    let mut load_address = DEFAULT_LOAD_ADDRESS;
+    let mut state_dir = None;
+    let mut load_state = None;
+    let mut save_state = None;
+    let mut rewind_seconds = DEFAULT_REWIND_SECONDS;
+    let mut seed = None;
+    let mut timing = Timing::Instructions;
+    let mut block_cache = false;
+    let mut record = None;
+    let mut play = None;
+    let mut verify = false;
+    let mut debug = false;
+    let mut gdb_port = None;
+    let mut dap = false;
+    let mut dap_port = None;
+    let mut trace_level = None;
+    let mut trace_ranges = Vec::new();
+    let mut trace_file = None;
+    let mut trace_last = None;
+    let mut rom_name = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--headless" => headless = true,
+            "--ipf" => {
+                instructions_per_frame = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .expect("--ipf needs a number of instructions per frame.");
+            }
+            "--quirks" => {
+                let name = args.next().unwrap_or_default();
+                quirks = Quirks::preset(&name).unwrap_or_else(|| {
+                    panic!(
+                        "Unknown quirks '{}', expected one of: {}",
+                        name,
+                        Quirks::PRESET_NAMES.join(", ")
+                    )
+                });
+            }
+            "--stack-in-ram" => stack_in_ram = true,
+            "--load-address" => {
+                load_address = args
+                    .next()
+                    .and_then(|n| parse_number(&n))
+                    .and_then(|n| u16::try_from(n).ok())
+                    .expect("--load-address needs an address, like 0x600.");
+            }
+            "--state-dir" => state_dir = args.next(),
+            "--load-state" => load_state = args.next(),
+            "--save-state" => save_state = args.next(),
+            "--seed" => {
+                seed = Some(
+                    args.next()
+                        .and_then(|n| parse_number(&n))
+                        .expect("--seed needs a number."),
+                );
+            }
+            "--vip-timing" => timing = Timing::CosmacVip,
+            "--block-cache" => block_cache = true,
+            "--record" => record = args.next(),
+            "--play" => play = args.next(),
+            "--verify" => verify = true,
+            "--debug" => debug = true,
+            "--gdb" => {
+                gdb_port = Some(
+                    args.next()
+                        .and_then(|n| n.parse::<u16>().ok())
+                        .expect("--gdb needs a port to listen on."),
+                );
+            }
+            "--dap" => dap = true,
+            "--dap-port" => {
+                dap_port = Some(
+                    args.next()
+                        .and_then(|n| n.parse::<u16>().ok())
+                        .expect("--dap-port needs a port to listen on."),
+                );
+            }
+            "--rewind" => {
+                rewind_seconds = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .expect("--rewind needs a number of seconds, 0 turns it off.");
+            }
+            "-v" => trace_level = Some(TraceLevel::Instructions),
+            "-vv" => trace_level = Some(TraceLevel::Registers),
+            "--trace-range" => {
+                trace_ranges.push(
+                    args.next()
+                        .and_then(|range| parse_range(&range))
+                        .expect("--trace-range needs addresses, like 0x200-0x2ff."),
+                );
+            }
+            "--trace-file" => trace_file = args.next(),
+            "--trace-last" => {
+                trace_last = Some(
+                    args.next()
+                        .and_then(|n| n.parse().ok())
+                        .expect("--trace-last needs a number of instructions."),
+                );
+            }
+            _ => rom_name = Some(arg),
+        }
+    }
+    // The editor says which ROM to run, and over stdio nothing else can be
+    // printed there
+    if dap || dap_port.is_some() {
+        let mut cpu = if headless {
+            Processor::headless()
+        } else {
+            sdl_processor()
+        };
+        cpu.set_instructions_per_frame(instructions_per_frame);
+        cpu.set_timing(timing);
+        quirks.stack_in_ram |= stack_in_ram;
+        cpu.set_quirks(quirks);
+        cpu.set_load_address(load_address);
+        cpu.set_random(Random::seeded(seed.unwrap_or_else(rand::random)));
+        let exit = debug_adapter(&mut cpu, dap_port);
+        process::exit(exit.exit_code());
+    }
 
-const RAM_SIZE: usize = 4 * 1024; // 4 KB
-const GPR_SIZE: usize = 16;
+    let rom_name = rom_name.expect("Please provide a file name, or - for stdin.");
+    if load_state.is_some() && (record.is_some() || play.is_some()) {
+        eprintln!("Movies start from power on, they can't be combined with --load-state.");
+        process::exit(1);
+    }
 
-const CHIP8_WIDTH: usize = 64;
-const CHIP8_HEIGHT: usize = 32;
+    // A movie brings its own settings, whatever was on the command line
+    let movie = play.as_ref().map(|path| {
+        let movie = Movie::load_from_path(path).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        });
+        instructions_per_frame = movie.instructions_per_frame();
+        timing = movie.timing();
+        quirks = movie.quirks();
+        load_address = movie.load_address();
+        movie
+    });
 
-fn main() {
-    let rom_name = env::args().nth(1).expect("Please provide a file name.");
-    let sdl_context = sdl2::init().unwrap();
-    let mut cpu = Processor::new(&sdl_context);
+    let rom = if rom_name == "-" {
+        Rom::from_stdin()
+    } else {
+        Rom::from_path(&rom_name)
+    };
+    let rom = rom.unwrap_or_else(|e| {
+        eprintln!("{}: {}", rom_name, e);
+        process::exit(1);
+    });
+    println!(
+        "Loading {} ({} bytes, sha1 {})",
+        rom_name,
+        rom.len(),
+        rom.sha1_hex()
+    );
+
+    let mut cpu = if headless {
+        Processor::headless()
+    } else {
+        sdl_processor()
+    };
 
+    cpu.set_instructions_per_frame(instructions_per_frame);
+    cpu.set_timing(timing);
+    quirks.stack_in_ram |= stack_in_ram;
+    cpu.set_quirks(quirks);
+    cpu.set_block_cache(block_cache);
+    // Asking for any of it turns tracing on
+    let tracing = !trace_ranges.is_empty() || trace_file.is_some() || trace_last.is_some();
+    if let Some(level) = trace_level.or_else(|| tracing.then_some(TraceLevel::Instructions)) {
+        let mut tracer = Tracer::new(level);
+        for range in trace_ranges {
+            tracer.add_range(range);
+        }
+        if let Some(path) = &trace_file {
+            let file = File::create(path).unwrap_or_else(|e| {
+                eprintln!("{}: {}", path, e);
+                process::exit(1);
+            });
+            tracer.set_output(Box::new(BufWriter::new(file)));
+        }
+        if let Some(count) = trace_last {
+            tracer.keep_last(count);
+        }
+        cpu.set_tracer(Some(tracer));
+    }
+    cpu.set_load_address(load_address);
+    // Always start from a known seed so a run can be repeated
+    if movie.is_none() {
+        let seed = seed.unwrap_or_else(rand::random);
+        println!("Random seed {}", seed);
+        cpu.set_random(Random::seeded(seed));
+    }
     cpu.reset();
-    cpu.load_rom(rom_name);
-    cpu.run();
+    if let Err(e) = cpu.load_rom(&rom) {
+        eprintln!("{}: {}", rom_name, e);
+        process::exit(1);
+    }
+    if let Some(movie) = &movie {
+        if let Err(e) = cpu.play_movie(movie) {
+            eprintln!("{}: {}", play.as_deref().unwrap_or_default(), e);
+            process::exit(1);
+        }
+    }
+    if record.is_some() {
+        cpu.start_recording();
+    }
+    if rewind_seconds > 0 {
+        cpu.set_rewind(Some(RewindBuffer::new(
+            rewind_seconds,
+            DEFAULT_REWIND_INTERVAL,
+        )));
+    }
+    if let Some(dir) = state_dir {
+        cpu.set_state_dir(dir);
+    }
+    if let Some(path) = load_state {
+        if let Err(e) = SaveState::load_from_path(&path).and_then(|state| cpu.load_state(&state)) {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        }
+    }
+
+    let exit = if let Some(port) = gdb_port {
+        gdb(&mut cpu, port)
+    } else if debug {
+        Debugger::new().run(&mut cpu)
+    } else {
+        cpu.run()
+    };
+
+    if let Some(path) = save_state {
+        if let Err(e) = cpu.save_state().save_to_path(&path) {
+            eprintln!("{}: {}", path, e);
+        }
+    }
+    if let Some(path) = record {
+        if let Some(movie) = cpu.stop_recording() {
+            if let Err(e) = movie.save_to_path(&path) {
+                eprintln!("{}: {}", path, e);
+            }
+        }
+    }
+    if let Some(tracer) = cpu.tracer_mut() {
+        if let Err(e) = tracer.flush() {
+            eprintln!("{}: {}", trace_file.as_deref().unwrap_or("stderr"), e);
+        }
+    }
+    if let ExitReason::Fault { .. } = exit {
+        eprintln!("{}", exit);
+    }
+    if let (Some(movie), true) = (movie, verify) {
+        if let Err(e) = movie.verify_final_state(&cpu.save_state()) {
+            eprintln!("{}: {}", play.unwrap_or_default(), e);
+            process::exit(1);
+        }
+        println!("Final state matches the recording");
+    }
+    process::exit(exit.exit_code());
+}
+
+// Only listens locally, there's no authentication of any sort
+fn gdb(cpu: &mut Processor, port: u16) -> ExitReason {
+    let server = GdbServer::bind(("127.0.0.1", port)).unwrap_or_else(|e| {
+        eprintln!("Couldn't listen on port {}: {}", port, e);
+        process::exit(1);
+    });
+    println!("Waiting for GDB on 127.0.0.1:{}", port);
+    server.run(cpu).unwrap_or_else(|e| {
+        eprintln!("GDB connection failed: {}", e);
+        process::exit(1);
+    })
+}
+
+// chippe-rs asm [-o ROM] [--symbols FILE] SOURCE
+fn asm<I: Iterator<Item = String>>(mut args: I) -> ! {
+    let mut output = None;
+    let mut symbols = None;
+    let mut source = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = args.next(),
+            "--symbols" => symbols = args.next(),
+            _ => source = Some(arg),
+        }
+    }
+    let source = source.expect("Please provide a source file to assemble.");
+    let output = output.unwrap_or_else(|| {
+        Path::new(&source)
+            .with_extension("ch8")
+            .to_string_lossy()
+            .into_owned()
+    });
+
+    let assembly = asm::assemble_file(&source).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    if let Err(e) = fs::write(&output, assembly.bytes()) {
+        eprintln!("{}: {}", output, e);
+        process::exit(1);
+    }
+    if let Some(path) = symbols {
+        if let Err(e) = assembly.symbols().save_to_path(&path) {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        }
+    }
+    println!(
+        "Assembled {} bytes at 0x{:03x} into {}",
+        assembly.bytes().len(),
+        assembly.load_address(),
+        output
+    );
+    process::exit(0);
+}
+
+// chippe-rs disasm [--syntax classic|octo] [--load-address N] [-o FILE] ROM
+fn disasm<I: Iterator<Item = String>>(mut args: I) -> ! {
+    let mut syntax = Syntax::Classic;
+    let mut load_address = DEFAULT_LOAD_ADDRESS;
+    let mut output = None;
+    let mut rom_name = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--syntax" => {
+                let name = args.next().unwrap_or_default();
+                syntax = Syntax::from_name(&name).unwrap_or_else(|| {
+                    panic!(
+                        "Unknown syntax '{}', expected one of: {}",
+                        name,
+                        Syntax::NAMES.join(", ")
+                    )
+                });
+            }
+            "--load-address" => {
+                load_address = args
+                    .next()
+                    .and_then(|n| parse_number(&n))
+                    .and_then(|n| u16::try_from(n).ok())
+                    .expect("--load-address needs an address, like 0x600.");
+            }
+            "-o" => output = args.next(),
+            _ => rom_name = Some(arg),
+        }
+    }
+    let rom_name = rom_name.expect("Please provide a file name, or - for stdin.");
+    let rom = if rom_name == "-" {
+        Rom::from_stdin()
+    } else {
+        Rom::from_path(&rom_name)
+    };
+    let rom = rom.unwrap_or_else(|e| {
+        eprintln!("{}: {}", rom_name, e);
+        process::exit(1);
+    });
+
+    let disassembly = Disassembly::new(rom.bytes(), load_address);
+    let result = match &output {
+        Some(path) => File::create(path).and_then(|file| disassembly.write_to(syntax, file)),
+        None => disassembly.write_to(syntax, io::stdout().lock()),
+    };
+    if let Err(e) = result {
+        eprintln!("{}: {}", output.as_deref().unwrap_or("stdout"), e);
+        process::exit(1);
+    }
+    process::exit(0);
+}
+
+// chippe-rs recompile [--load-address N] [-o FILE] ROM
+fn recompile<I: Iterator<Item = String>>(mut args: I) -> ! {
+    let mut load_address = DEFAULT_LOAD_ADDRESS;
+    let mut output = None;
+    let mut rom_name = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--load-address" => {
+                load_address = args
+                    .next()
+                    .and_then(|n| parse_number(&n))
+                    .and_then(|n| u16::try_from(n).ok())
+                    .expect("--load-address needs an address, like 0x600.");
+            }
+            "-o" => output = args.next(),
+            _ => rom_name = Some(arg),
+        }
+    }
+    let rom_name = rom_name.expect("Please provide a file name, or - for stdin.");
+    let rom = if rom_name == "-" {
+        Rom::from_stdin()
+    } else {
+        Rom::from_path(&rom_name)
+    };
+    let rom = rom.unwrap_or_else(|e| {
+        eprintln!("{}: {}", rom_name, e);
+        process::exit(1);
+    });
+
+    let result = match &output {
+        Some(path) => {
+            File::create(path).and_then(|file| recompile_rom(rom.bytes(), load_address, file))
+        }
+        None => recompile_rom(rom.bytes(), load_address, io::stdout().lock()),
+    };
+    if let Err(e) = result {
+        eprintln!("{}: {}", output.as_deref().unwrap_or("stdout"), e);
+        process::exit(1);
+    }
+    process::exit(0);
+}
+
+// Only listens locally, like the GDB stub
+fn debug_adapter(cpu: &mut Processor, port: Option<u16>) -> ExitReason {
+    let result = match port {
+        Some(port) => {
+            let listener = TcpListener::bind(("127.0.0.1", port)).unwrap_or_else(|e| {
+                eprintln!("Couldn't listen on port {}: {}", port, e);
+                process::exit(1);
+            });
+            eprintln!("Waiting for an editor on 127.0.0.1:{}", port);
+            listener.accept().and_then(|(stream, _)| {
+                let input = BufReader::new(stream.try_clone()?);
+                DapServer::new(input, stream).run(cpu)
+            })
+        }
+        None => DapServer::new(BufReader::new(io::stdin()), io::stdout()).run(cpu),
+    };
+    result.unwrap_or_else(|e| {
+        eprintln!("Debug adapter connection failed: {}", e);
+        process::exit(1);
+    })
+}
+
+// START-END, both included, or just one address
+fn parse_range(s: &str) -> Option<RangeInclusive<u16>> {
+    let (start, end) = match s.split_once('-') {
+        Some((start, end)) => (start, end),
+        None => (s, s),
+    };
+    let address = |n: &str| parse_number(n).and_then(|n| u16::try_from(n).ok());
+    Some(address(start)?..=address(end)?)
+}
+
+// Decimal, or hex with a 0x in front
+fn parse_number(s: &str) -> Option<u64> {
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        u64::from_str_radix(hex, 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+#[cfg(feature = "sdl")]
+fn sdl_processor() -> Processor {
+    use chippe_rs::drivers::{AudioDriver, DisplayDriver, KeyboardDriver};
+
+    let sdl_context = sdl2::init().unwrap();
+    Processor::new(
+        Box::new(DisplayDriver::new(&sdl_context)),
+        Box::new(AudioDriver::new(&sdl_context)),
+        Box::new(KeyboardDriver::new(&sdl_context)),
+    )
+}
+
+#[cfg(not(feature = "sdl"))]
+fn sdl_processor() -> Processor {
+    unreachable!("built without the sdl feature")
 }
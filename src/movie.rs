@@ -0,0 +1,471 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use crate::drivers::{KeyState, Keyboard, Quit};
+use crate::quirks::{IndexIncrement, Quirks};
+use crate::random::Random;
+use crate::savestate::{bits_to_keys, keys_to_bits, read_array, read_u16, SaveState};
+use crate::timing::Timing;
+
+const MAGIC: &[u8; 4] = b"C8MV";
+pub const MOVIE_VERSION: u16 = 3;
+
+#[derive(Debug)]
+pub enum MovieError {
+    Io(io::Error),
+    /// Doesn't start with the movie header
+    NotAMovie,
+    UnsupportedVersion(u16),
+    /// Recorded against a different ROM
+    WrongRom,
+    /// Played back fine but didn't end up where the recording did
+    FinalStateMismatch,
+    Corrupt,
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::Io(e) => write!(f, "couldn't read or write movie: {}", e),
+            MovieError::NotAMovie => write!(f, "not a movie"),
+            MovieError::UnsupportedVersion(v) => write!(
+                f,
+                "movie is version {}, this build reads up to version {}",
+                v, MOVIE_VERSION
+            ),
+            MovieError::WrongRom => write!(f, "movie was recorded with a different ROM"),
+            MovieError::FinalStateMismatch => {
+                write!(f, "final state doesn't match the recording")
+            }
+            MovieError::Corrupt => write!(f, "movie is corrupt"),
+        }
+    }
+}
+
+impl Error for MovieError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MovieError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MovieError {
+    fn from(e: io::Error) -> MovieError {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => MovieError::Corrupt,
+            _ => MovieError::Io(e),
+        }
+    }
+}
+
+/// Every frame's keypad input from one run, plus everything else needed to
+/// play it back exactly: the ROM, the RNG and the machine settings.
+///
+/// Movies always start from a freshly loaded ROM.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    rom_sha1: [u8; 20],
+    random: Random,
+    instructions_per_frame: u32,
+    timing: Timing,
+    load_address: u16,
+    quirks: Quirks,
+    frames: Vec<KeyState>,
+    // SHA-1 of the save state at the end of the recording
+    final_state: Option<[u8; 20]>,
+}
+
+impl Movie {
+    pub fn new(
+        rom_sha1: [u8; 20],
+        random: Random,
+        instructions_per_frame: u32,
+        timing: Timing,
+        load_address: u16,
+        quirks: Quirks,
+    ) -> Movie {
+        Movie {
+            rom_sha1,
+            random,
+            instructions_per_frame,
+            timing,
+            load_address,
+            quirks,
+            frames: Vec::new(),
+            final_state: None,
+        }
+    }
+
+    pub fn rom_sha1(&self) -> &[u8; 20] {
+        &self.rom_sha1
+    }
+
+    /// The RNG as it was when recording started.
+    pub fn random(&self) -> Random {
+        self.random
+    }
+
+    pub fn instructions_per_frame(&self) -> u32 {
+        self.instructions_per_frame
+    }
+
+    pub fn timing(&self) -> Timing {
+        self.timing
+    }
+
+    pub fn load_address(&self) -> u16 {
+        self.load_address
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    pub fn frames(&self) -> &[KeyState] {
+        &self.frames
+    }
+
+    /// Only the keypad gets recorded, not commands or rewinding.
+    pub fn push_frame(&mut self, key_state: &KeyState) {
+        self.frames.push(*key_state);
+    }
+
+    pub fn final_state(&self) -> Option<&[u8; 20]> {
+        self.final_state.as_ref()
+    }
+
+    pub fn set_final_state(&mut self, state: &SaveState) {
+        self.final_state = Some(state.sha1());
+    }
+
+    /// Check a playback ended in the same state the recording did. Movies
+    /// recorded without a final state always pass.
+    pub fn verify_final_state(&self, state: &SaveState) -> Result<(), MovieError> {
+        match self.final_state {
+            Some(sha1) if sha1 != state.sha1() => Err(MovieError::FinalStateMismatch),
+            _ => Ok(()),
+        }
+    }
+
+    /// A keyboard that plays the movie's frames back, then quits. `inner`
+    /// is still polled every frame so the window keeps responding, but only
+    /// its quit gets through.
+    pub fn player(&self, inner: Box<dyn Keyboard>) -> MoviePlayer {
+        MoviePlayer {
+            frames: self.frames.clone().into_iter(),
+            inner,
+        }
+    }
+
+    pub fn save_to_path<P: AsRef<Path>>(&self, path: P) -> Result<(), MovieError> {
+        let mut file = io::BufWriter::new(fs::File::create(path)?);
+        self.write_to(&mut file)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    pub fn load_from_path<P: AsRef<Path>>(path: P) -> Result<Movie, MovieError> {
+        let file = fs::File::open(path)?;
+        Movie::read_from(io::BufReader::new(file))
+    }
+
+    // Everything is little endian:
+    //   magic, version, ROM SHA-1, RNG state (u64, after a kind byte that
+    //   was always 0 before version 3),
+    //   instructions per frame (u32), timing (u8, not in version 1),
+    //   load address (u16),
+    //   quirks (flags, index increment, stack depth),
+    //   final state present (u8) + SHA-1,
+    //   frame count (u32) then held/pressed/released bitmasks (u16) per frame
+    pub fn write_to<W: Write>(&self, mut w: W) -> Result<(), MovieError> {
+        w.write_all(MAGIC)?;
+        w.write_all(&MOVIE_VERSION.to_le_bytes())?;
+        w.write_all(&self.rom_sha1)?;
+        w.write_all(&self.random.to_bytes())?;
+        w.write_all(&self.instructions_per_frame.to_le_bytes())?;
+        w.write_all(&[timing_to_byte(self.timing)])?;
+        w.write_all(&self.load_address.to_le_bytes())?;
+        w.write_all(&quirks_to_bytes(&self.quirks))?;
+        match self.final_state {
+            Some(sha1) => {
+                w.write_all(&[1])?;
+                w.write_all(&sha1)?;
+            }
+            None => w.write_all(&[0; 21])?,
+        }
+
+        w.write_all(&(self.frames.len() as u32).to_le_bytes())?;
+        for frame in &self.frames {
+            w.write_all(&keys_to_bits(&frame.held).to_le_bytes())?;
+            w.write_all(&keys_to_bits(&frame.pressed).to_le_bytes())?;
+            w.write_all(&keys_to_bits(&frame.released).to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(mut r: R) -> Result<Movie, MovieError> {
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)
+            .map_err(|_| MovieError::NotAMovie)?;
+        if &magic != MAGIC {
+            return Err(MovieError::NotAMovie);
+        }
+        let version = read_u16(&mut r)?;
+        if !(1..=MOVIE_VERSION).contains(&version) {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+        let rom_sha1 = read_array(&mut r)?;
+        // Before version 3 the RNG state came after a kind byte, and there
+        // was only ever the one kind
+        if version < 3 {
+            let [kind] = read_array::<_, 1>(&mut r)?;
+            if kind != 0 {
+                return Err(MovieError::Corrupt);
+            }
+        }
+        let random = Random::from_bytes(read_array(&mut r)?);
+        let instructions_per_frame = u32::from_le_bytes(read_array(&mut r)?);
+        // Version 1 came before VIP timing, when every frame was a fixed
+        // number of instructions
+        let timing = if version == 1 {
+            Timing::Instructions
+        } else {
+            let [timing] = read_array::<_, 1>(&mut r)?;
+            timing_from_byte(timing).ok_or(MovieError::Corrupt)?
+        };
+        let load_address = read_u16(&mut r)?;
+        let quirks = quirks_from_bytes(read_array(&mut r)?).ok_or(MovieError::Corrupt)?;
+        let [has_final_state] = read_array::<_, 1>(&mut r)?;
+        let final_sha1 = read_array(&mut r)?;
+        let final_state = match has_final_state {
+            0 => None,
+            1 => Some(final_sha1),
+            _ => return Err(MovieError::Corrupt),
+        };
+
+        let count = u32::from_le_bytes(read_array(&mut r)?);
+        let mut frames = Vec::new();
+        for _ in 0..count {
+            frames.push(KeyState {
+                held: bits_to_keys(read_u16(&mut r)?),
+                pressed: bits_to_keys(read_u16(&mut r)?),
+                released: bits_to_keys(read_u16(&mut r)?),
+                ..KeyState::default()
+            });
+        }
+
+        Ok(Movie {
+            rom_sha1,
+            random,
+            instructions_per_frame,
+            timing,
+            load_address,
+            quirks,
+            frames,
+            final_state,
+        })
+    }
+}
+
+/// Plays a [`Movie`] back through the keypad, see [`Movie::player`].
+pub struct MoviePlayer {
+    frames: std::vec::IntoIter<KeyState>,
+    inner: Box<dyn Keyboard>,
+}
+
+impl MoviePlayer {
+    /// Frames still to be played.
+    pub fn remaining(&self) -> usize {
+        self.frames.len()
+    }
+}
+
+impl Keyboard for MoviePlayer {
+    fn poll(&mut self) -> Result<KeyState, Quit> {
+        self.inner.poll()?;
+        self.frames.next().ok_or(Quit)
+    }
+}
+
+fn quirks_to_bytes(quirks: &Quirks) -> [u8; 3] {
+    let flags = [
+        quirks.shift_uses_vy,
+        quirks.jump_uses_vx,
+        quirks.logic_resets_vf,
+        quirks.wrap_sprites,
+        quirks.display_wait,
+        quirks.stack_in_ram,
+        quirks.xo_chip,
+    ]
+    .iter()
+    .enumerate()
+    .fold(0, |bits, (i, &set)| bits | ((set as u8) << i));
+    let index_increment = match quirks.index_increment {
+        IndexIncrement::Unchanged => 0,
+        IndexIncrement::ByX => 1,
+        IndexIncrement::ByXPlusOne => 2,
+    };
+    [flags, index_increment, quirks.stack_depth as u8]
+}
+
+fn quirks_from_bytes([flags, index_increment, stack_depth]: [u8; 3]) -> Option<Quirks> {
+    let flag = |i: u8| flags & (1 << i) != 0;
+    Some(Quirks {
+        shift_uses_vy: flag(0),
+        jump_uses_vx: flag(1),
+        logic_resets_vf: flag(2),
+        wrap_sprites: flag(3),
+        display_wait: flag(4),
+        stack_in_ram: flag(5),
+        xo_chip: flag(6),
+        index_increment: match index_increment {
+            0 => IndexIncrement::Unchanged,
+            1 => IndexIncrement::ByX,
+            2 => IndexIncrement::ByXPlusOne,
+            _ => return None,
+        },
+        stack_depth: stack_depth as usize,
+    })
+}
+
+fn timing_to_byte(timing: Timing) -> u8 {
+    match timing {
+        Timing::Instructions => 0,
+        Timing::CosmacVip => 1,
+    }
+}
+
+fn timing_from_byte(byte: u8) -> Option<Timing> {
+    match byte {
+        0 => Some(Timing::Instructions),
+        1 => Some(Timing::CosmacVip),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Movie, MovieError, MOVIE_VERSION};
+    use crate::drivers::headless::{NullAudio, NullDisplay, NullKeyboard};
+    use crate::drivers::{KeyState, Keyboard};
+    use crate::testing::{load, processor};
+    use crate::{Processor, Quirks, Random, Timing};
+
+    // Adds up random numbers, and counts frames with key 5 held
+    const SOURCE: &str = "
+        loop:
+            RND V1, 0xFF
+            ADD V2, V1
+            LD V0, 5
+            SKNP V0
+            ADD V3, 1
+            JP loop
+        ";
+
+    fn with_keyboard(keyboard: Box<dyn Keyboard>) -> Processor {
+        let mut cpu = Processor::new(Box::new(NullDisplay), Box::new(NullAudio), keyboard);
+        load(&mut cpu, SOURCE);
+        cpu
+    }
+
+    // Somebody pressing 5 for a while, made out of a movie since that's the
+    // easiest keyboard to script
+    fn player() -> Box<dyn Keyboard> {
+        let mut script = Movie::new(
+            [0; 20],
+            Random::seeded(0),
+            10,
+            Timing::Instructions,
+            0x200,
+            Quirks::default(),
+        );
+        for frame in 0..12 {
+            let mut keys = KeyState::default();
+            keys.held[5] = (3..8).contains(&frame);
+            keys.pressed[5] = frame == 3;
+            keys.released[5] = frame == 8;
+            script.push_frame(&keys);
+        }
+        Box::new(script.player(Box::new(NullKeyboard)))
+    }
+
+    #[test]
+    fn record_save_load_play_and_verify() {
+        let mut cpu = with_keyboard(player());
+        cpu.set_random(Random::seeded(42));
+        cpu.start_recording();
+        cpu.run();
+        let recorded = cpu.stop_recording().unwrap();
+        assert_eq!(recorded.frames().len(), 12);
+        assert!(cpu.registers()[3] > 0);
+
+        let mut bytes = Vec::new();
+        recorded.write_to(&mut bytes).unwrap();
+        let movie = Movie::read_from(&bytes[..]).unwrap();
+        assert_eq!(movie, recorded);
+
+        let mut replay = with_keyboard(Box::new(NullKeyboard));
+        replay.play_movie(&movie).unwrap();
+        replay.run();
+        assert_eq!(replay.registers(), cpu.registers());
+        movie.verify_final_state(&replay.save_state()).unwrap();
+
+        // Something different happening is noticed
+        replay.set_register(3, 0);
+        assert!(matches!(
+            movie.verify_final_state(&replay.save_state()),
+            Err(MovieError::FinalStateMismatch)
+        ));
+    }
+
+    #[test]
+    fn other_roms_are_turned_away() {
+        let mut cpu = with_keyboard(player());
+        cpu.start_recording();
+        cpu.run();
+        let movie = cpu.stop_recording().unwrap();
+
+        let mut other = processor("LD V0, 1");
+        assert!(matches!(
+            other.play_movie(&movie),
+            Err(MovieError::WrongRom)
+        ));
+    }
+
+    #[test]
+    fn version_1_is_instructions_timing() {
+        let mut cpu = with_keyboard(player());
+        cpu.start_recording();
+        cpu.run();
+        let movie = cpu.stop_recording().unwrap();
+        let mut bytes = Vec::new();
+        movie.write_to(&mut bytes).unwrap();
+
+        // Magic, version and SHA-1, then the RNG kind byte versions 1 and 2
+        // had, then the RNG and instructions per frame, then the timing byte
+        // version 1 didn't have
+        bytes[4..6].copy_from_slice(&1u16.to_le_bytes());
+        bytes.insert(4 + 2 + 20, 0);
+        bytes.remove(4 + 2 + 20 + 1 + 8 + 4);
+        let old = Movie::read_from(&bytes[..]).unwrap();
+        assert_eq!(old.timing(), Timing::Instructions);
+        assert_eq!(old, movie);
+
+        bytes[4 + 2 + 20] = 1;
+        assert!(matches!(
+            Movie::read_from(&bytes[..]),
+            Err(MovieError::Corrupt)
+        ));
+
+        bytes[4..6].copy_from_slice(&(MOVIE_VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            Movie::read_from(&bytes[..]),
+            Err(MovieError::UnsupportedVersion(v)) if v == MOVIE_VERSION + 1
+        ));
+    }
+}
@@ -1,24 +1,45 @@
 use std::fmt;
-use std::fs;
-use std::io::Read;
-use std::path::Path;
-
-extern crate rand;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::GPR_SIZE;
 use crate::RAM_SIZE;
-
-use crate::CHIP8_HEIGHT;
-use crate::CHIP8_WIDTH;
-
-use crate::font::FONT_SET;
-
-use crate::drivers::audio::AudioDriver;
-use crate::drivers::display::DisplayDriver;
-use crate::drivers::keyboard::KeyboardDriver;
+use crate::XO_RAM_SIZE;
+
+use crate::cache::BlockCache;
+use crate::debugger::{Access, WatchHit, Watchpoint};
+use crate::disasm::disassemble_instruction;
+use crate::fault::{ExitReason, Fault};
+use crate::font::{BIG_FONT_SET, BIG_FONT_START, FONT_SET, FONT_START};
+use crate::framebuffer::Framebuffer;
+use crate::instruction::Instruction;
+use crate::movie::{Movie, MovieError};
+use crate::quirks::{IndexIncrement, Quirks};
+use crate::random::Random;
+use crate::recompile::RunBlock;
+use crate::rewind::RewindBuffer;
+use crate::rom::{Rom, RomError};
+use crate::savestate::{SaveState, SaveStateError};
+use crate::stack::{CallStack, VIP_STACK_TOP};
+use crate::timing::{vip_cycles, Timing, VIP_FRAME_BUDGET, VIP_SKIP_CYCLES};
+use crate::trace::{TraceEntry, Tracer};
+
+pub const FRAMES_PER_SECOND: u64 = 60;
+pub const DEFAULT_LOAD_ADDRESS: u16 = 0x200;
+pub const DEFAULT_STATE_DIR: &str = "states";
+// ~600 instructions a second, which most games seem happy with
+pub const DEFAULT_INSTRUCTIONS_PER_FRAME: u32 = 10;
+const MAX_FRAMES_BEHIND: u32 = 5;
+
+use crate::drivers::headless::{NullAudio, NullDisplay, NullKeyboard};
+use crate::drivers::{
+    Audio, Command, Display, KeyState, Keyboard, Quit, DEFAULT_AUDIO_PATTERN, DEFAULT_PITCH,
+};
 
 struct RamArray {
-    pub memory: Box<[u8; RAM_SIZE]>,
+    // RAM_SIZE, or XO_RAM_SIZE for XO-CHIP
+    pub memory: Vec<u8>,
 }
 
 impl fmt::Debug for RamArray {
@@ -29,9 +50,26 @@ impl fmt::Debug for RamArray {
 }
 
 impl RamArray {
-    fn new() -> RamArray {
+    fn new(size: usize) -> RamArray {
         RamArray {
-            memory: Box::new([0; RAM_SIZE]),
+            memory: vec![0; size],
+        }
+    }
+
+    fn read(&self, address: usize) -> Result<u8, Fault> {
+        self.memory
+            .get(address)
+            .copied()
+            .ok_or(Fault::MemoryOutOfBounds { address })
+    }
+
+    fn write(&mut self, address: usize, value: u8) -> Result<(), Fault> {
+        match self.memory.get_mut(address) {
+            Some(byte) => {
+                *byte = value;
+                Ok(())
+            }
+            None => Err(Fault::MemoryOutOfBounds { address }),
         }
     }
 }
@@ -39,517 +77,1886 @@ impl RamArray {
 //self.peripheral_driver.audio.start_beep();
 //self.peripheral_driver.audio.stop_beep();
 struct PeripheralDriver {
-    audio: AudioDriver,
-    display: DisplayDriver,
-    keyboard: KeyboardDriver,
+    audio: Box<dyn Audio>,
+    display: Box<dyn Display>,
+    keyboard: Box<dyn Keyboard>,
 }
 
 pub struct Processor {
     peripheral_driver: PeripheralDriver,
-    clock_speed: u64,
+    instructions_per_frame: u32,
+    // Instructions run so far this frame, and in all
+    frame_progress: u32,
+    instructions: u64,
+    timing: Timing,
+    // Machine cycles used so far this frame, and in all, with VIP timing
+    frame_cycles: u32,
+    cycles: u64,
+    // A sprite's cycles, held over to the frame it gets drawn in
+    draw_cycles: u32,
+    tracer: Option<Tracer>,
     program_counter: u16,
-    display_state: [[u8; CHIP8_WIDTH as usize]; CHIP8_HEIGHT as usize],
+    load_address: u16,
+    display_state: Framebuffer,
     keyboard_state: [bool; 16],
     gpr_v: [u8; GPR_SIZE], // General Purpose Registers (V0 - VF)
     reg_i: u16,
+    rpl_flags: [u8; 16],
+    random: Random,
+    // XO-CHIP audio, F002 and FX3A
+    audio_pattern: [u8; 16],
+    pitch: u8,
     delay_timer: u8,
     sound_timer: u8,
-    stack_pointer: u16,
+    stack: CallStack,
     ram: RamArray,
+    // Set once the program stops, for whatever reason
+    exit: Option<ExitReason>,
+    key_wait: Option<KeyWait>,
+    // Set by DXYN with the display_wait quirk, cleared at the end of the frame
+    vblank_wait: bool,
+    quirks: Quirks,
+    // Identifies the loaded ROM in save states
+    rom_sha1: [u8; 20],
+    // Where numbered save state slots go
+    state_dir: PathBuf,
+    rewind: Option<RewindBuffer>,
+    recording: Option<Movie>,
+    watchpoints: Vec<Watchpoint>,
+    // Set by the last instruction if it touched a watched address
+    watch_hit: Option<WatchHit>,
+    block_cache: Option<BlockCache>,
+    recompiled: Option<RunBlock>,
+}
+
+// Fx0A in progress
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct KeyWait {
+    pub(crate) register: usize,
+    // The key that went down, we still need to see it come back up
+    pub(crate) key: Option<u8>,
 }
 
 impl Processor {
-    pub fn new(sdl: &sdl2::Sdl) -> Processor {
+    pub fn new(
+        display: Box<dyn Display>,
+        audio: Box<dyn Audio>,
+        keyboard: Box<dyn Keyboard>,
+    ) -> Processor {
         Processor {
             peripheral_driver: PeripheralDriver {
-                audio: AudioDriver::new(&sdl),
-                display: DisplayDriver::new(&sdl),
-                keyboard: KeyboardDriver::new(&sdl),
+                audio,
+                display,
+                keyboard,
             },
-            clock_speed: 1,
+            instructions_per_frame: DEFAULT_INSTRUCTIONS_PER_FRAME,
+            frame_progress: 0,
+            timing: Timing::default(),
+            instructions: 0,
+            frame_cycles: 0,
+            cycles: 0,
+            draw_cycles: 0,
+            tracer: None,
             program_counter: 0,
+            load_address: DEFAULT_LOAD_ADDRESS,
             keyboard_state: [false; 16],
-            display_state: [[0 as u8; CHIP8_WIDTH as usize]; CHIP8_HEIGHT as usize],
+            display_state: Framebuffer::new(),
             gpr_v: [0; GPR_SIZE],
             reg_i: 0,
+            rpl_flags: [0; 16],
+            random: Random::from_entropy(),
+            audio_pattern: DEFAULT_AUDIO_PATTERN,
+            pitch: DEFAULT_PITCH,
             delay_timer: 0,
             sound_timer: 0,
-            stack_pointer: 0,
-            ram: RamArray::new(),
+            stack: CallStack::new(Quirks::default().stack_depth, false),
+            ram: RamArray::new(RAM_SIZE),
+            exit: None,
+            key_wait: None,
+            vblank_wait: false,
+            quirks: Quirks::default(),
+            rom_sha1: [0; 20],
+            state_dir: PathBuf::from(DEFAULT_STATE_DIR),
+            rewind: None,
+            recording: None,
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            block_cache: None,
+            recompiled: None,
         }
     }
 
-    pub fn load_rom<P: AsRef<Path>>(&mut self, path: P) {
-        // Open file and dump contents into file_buf
-        let mut file = fs::File::open(path).unwrap();
-        let mut file_buf = Vec::new();
-        file.read_to_end(&mut file_buf).unwrap();
+    // No window, no sound, no keys
+    pub fn headless() -> Processor {
+        Processor::new(
+            Box::new(NullDisplay),
+            Box::new(NullAudio),
+            Box::new(NullKeyboard),
+        )
+    }
 
+    /// Put a ROM in fresh RAM at the load address, with the fonts below it.
+    pub fn load_rom(&mut self, rom: &Rom) -> Result<(), RomError> {
         // Define temp ram array
-        let mut ram = [0; 4096];
+        let mut ram = vec![0; self.memory_size()];
+
+        let start = self.load_address as usize;
+        let max = ram.len().saturating_sub(start);
+        if rom.len() > max {
+            return Err(RomError::TooLarge {
+                size: rom.len(),
+                max,
+            });
+        }
 
         // read font into ram
-        for i in 0..FONT_SET.len() {
-            ram[i] = FONT_SET[i];
+        ram[FONT_START..FONT_START + FONT_SET.len()].copy_from_slice(&FONT_SET);
+        ram[BIG_FONT_START..BIG_FONT_START + BIG_FONT_SET.len()].copy_from_slice(&BIG_FONT_SET);
+        // Copy file binary into ram, starting at the load address
+        ram[start..start + rom.len()].copy_from_slice(rom.bytes());
+
+        self.ram = RamArray { memory: ram };
+        self.clear_block_cache();
+        self.rom_sha1 = rom.sha1();
+        self.exit = None;
+        self.key_wait = None;
+        if let Some(rewind) = self.rewind.as_mut() {
+            rewind.clear();
         }
-        // Copy file binary into ram, starting at 0x200
-        println!("Loading file length: {} bytes", file_buf.len());
-        ram[0x200..file_buf.len() + 0x200].copy_from_slice(&file_buf[..]);
+        Ok(())
+    }
 
-        self.ram = RamArray {
-            memory: Box::new(ram),
-        }
+    pub fn load_rom_bytes(&mut self, rom: &[u8]) -> Result<(), RomError> {
+        self.load_rom(&Rom::from_bytes(rom.to_vec())?)
+    }
+
+    /// Where ROMs get loaded and execution starts, 0x200 unless it's
+    /// something like an ETI-660 program (0x600).
+    pub fn load_address(&self) -> u16 {
+        self.load_address
+    }
+
+    pub fn set_load_address(&mut self, load_address: u16) {
+        self.load_address = load_address;
     }
 
     pub fn reset(&mut self) {
-        self.program_counter = 0x200;
-        self.stack_pointer = 0xfa0;
-        // screen is set to a slice of ram starting at 0xf00 ?
+        self.program_counter = self.load_address;
+        self.stack.clear();
+        self.exit = None;
+        self.frame_progress = 0;
+        self.instructions = 0;
+        self.frame_cycles = 0;
+        self.cycles = 0;
+        self.draw_cycles = 0;
+    }
+
+    /// Run until the user quits or the program stops, executing
+    /// `instructions_per_frame` instructions every 60 Hz frame. Nothing
+    /// needs checking between instructions, so each frame goes through
+    /// [`Processor::step_frame`] and can use the block cache and
+    /// recompiled code.
+    pub fn run(&mut self) -> ExitReason {
+        loop {
+            if let Some(exit) = self.run_frames(None) {
+                return exit;
+            }
+        }
     }
 
-    pub fn run(&mut self) {
-        'running: loop {
-            // set keyboard state and detect interrupt
-            match self.peripheral_driver.keyboard.poll() {
-                Ok(key_state) => self.keyboard_state = key_state,
-                Err(_e) => break 'running,
-            } // make this return the key set as a result
+    /// Like [`Processor::run`], but checks `should_break` before every
+    /// instruction and returns None, part way through a frame if need be,
+    /// as soon as it says to. Carrying on from there picks the frame up
+    /// where it left off.
+    pub fn run_until<F: FnMut(&Processor) -> bool>(
+        &mut self,
+        mut should_break: F,
+    ) -> Option<ExitReason> {
+        self.run_frames(Some(&mut should_break))
+    }
 
-            std::thread::sleep(std::time::Duration::from_millis(self.clock_speed));
+    fn run_frames(
+        &mut self,
+        mut should_break: Option<&mut dyn FnMut(&Processor) -> bool>,
+    ) -> Option<ExitReason> {
+        let frame = Duration::from_nanos(1_000_000_000 / FRAMES_PER_SECOND);
+        let mut next_frame = Instant::now();
+
+        loop {
+            if self.frame_progress == 0 {
+                // set keyboard state and detect interrupt
+                let rewinding = match self.peripheral_driver.keyboard.poll() {
+                    Ok(mut key_state) => {
+                        if let Some(movie) = self.recording.as_mut() {
+                            // Save/load and rewind jump around in time, which
+                            // a movie can't follow
+                            key_state.command = None;
+                            key_state.rewind = false;
+                            movie.push_frame(&key_state);
+                        }
+                        self.update_keys(&key_state);
+                        if let Some(command) = key_state.command {
+                            self.run_command(command);
+                        }
+                        key_state.rewind
+                    }
+                    Err(Quit) => return Some(ExitReason::Quit),
+                };
+
+                if rewinding && self.rewind_frame() {
+                    self.peripheral_driver.audio.stop_beep();
+                    self.peripheral_driver.display.draw(&self.display_state);
+                    wait_for_frame(&mut next_frame, frame);
+                    continue;
+                }
+            }
 
-            // This is just spots in memory, right?
-            // yep
-            self.peripheral_driver.display.draw(&self.display_state);
+            match should_break.as_mut() {
+                None => {
+                    if let Err(exit) = self.step_frame() {
+                        return Some(exit);
+                    }
+                }
+                Some(should_break) => loop {
+                    if should_break(self) {
+                        return None;
+                    }
+                    if let Err(exit) = self.step_instruction() {
+                        return Some(exit);
+                    }
+                    if self.frame_progress == 0 {
+                        break;
+                    }
+                },
+            }
+            self.record_rewind();
+            wait_for_frame(&mut next_frame, frame);
+        }
+    }
 
-            // Decrement DT and ST by 1 each 60 Hz?
-            // just gonna be a clock cycle for now
-            if self.delay_timer >= 1 {
-                self.delay_timer -= 1;
+    fn run_command(&mut self, command: Command) {
+        match command {
+            Command::SaveState(slot) => {
+                let path = SaveState::slot_path(&self.state_dir, &self.rom_sha1, slot);
+                match self.save_state().save_to_path(&path) {
+                    Ok(()) => eprintln!("Saved slot {}", slot),
+                    Err(e) => eprintln!("Couldn't save slot {}: {}", slot, e),
+                }
             }
-            if self.delay_timer >= 1 {
-                // play sound
-                self.peripheral_driver.audio.start_beep();
-                self.sound_timer -= 1;
-            } else {
-                self.peripheral_driver.audio.stop_beep();
+            Command::LoadState(slot) => {
+                let path = SaveState::slot_path(&self.state_dir, &self.rom_sha1, slot);
+                match SaveState::load_from_path(&path).and_then(|state| self.load_state(&state)) {
+                    Ok(()) => eprintln!("Loaded slot {}", slot),
+                    Err(e) => eprintln!("Couldn't load slot {}: {}", slot, e),
+                }
             }
+        }
+    }
 
-            //
-            // Instruction fetch and execute
-            //
-            let op1 = self.ram.memory[self.program_counter as usize];
-            let op2 = self.ram.memory[self.program_counter as usize + 1];
+    /// Snapshot the whole machine.
+    pub fn save_state(&self) -> SaveState {
+        SaveState {
+            rom_sha1: self.rom_sha1,
+            program_counter: self.program_counter,
+            gpr_v: self.gpr_v,
+            reg_i: self.reg_i,
+            rpl_flags: self.rpl_flags,
+            random: self.random,
+            instructions: self.instructions,
+            audio_pattern: self.audio_pattern,
+            pitch: self.pitch,
+            delay_timer: self.delay_timer,
+            sound_timer: self.sound_timer,
+            stack: self.stack.entries().to_vec(),
+            keyboard_state: self.keyboard_state,
+            key_wait: self.key_wait,
+            vblank_wait: self.vblank_wait,
+            display_state: self.display_state.clone(),
+            ram: self.ram.memory.clone(),
+        }
+    }
 
-            if op1 == 0x00 && op2 == 0x00 {
-                // Break on 0 byte? Avoids the zeroed out end of RAM, not sure if
-                // necessary or not though
-                break 'running;
-            }
+    /// Keep snapshots of the last few seconds so `rewind` can go back
+    /// through them, or stop keeping them with None.
+    pub fn set_rewind(&mut self, rewind: Option<RewindBuffer>) {
+        self.rewind = rewind;
+    }
+
+    pub fn rewind_buffer(&self) -> Option<&RewindBuffer> {
+        self.rewind.as_ref()
+    }
+
+    /// Go back one snapshot, false if there's nothing (left) to go back to.
+    pub fn rewind(&mut self) -> bool {
+        let state = match self.rewind.as_mut().and_then(RewindBuffer::pop) {
+            Some(state) => state,
+            None => return false,
+        };
+        self.load_state(&state).is_ok()
+    }
+
+    // A frame with the rewind key held. Snapshots are taken every few
+    // frames, so only some frames go back one, or it'd rewind faster than
+    // it played.
+    fn rewind_frame(&mut self) -> bool {
+        match self.rewind.as_mut() {
+            Some(rewind) if !rewind.is_empty() => !rewind.pop_due() || self.rewind(),
+            _ => false,
+        }
+    }
 
-            // display instructions for debugging
-            let str_instruction = fetch_instruction_str(op1, op2);
-            println!(
-                "{:04x?} {:02x} {:02x} :: {}",
-                self.program_counter, op1, op2, str_instruction
-            );
+    fn record_rewind(&mut self) {
+        if self.rewind.is_none() {
+            return;
+        }
+        let state = self.save_state();
+        if let Some(rewind) = self.rewind.as_mut() {
+            rewind.record(&state);
+        }
+    }
 
-            self.execute(op1, op2);
+    /// Start recording every frame's keys into a movie, from here. Movies
+    /// play back from a freshly loaded ROM so this wants calling straight
+    /// after `load_rom`.
+    pub fn start_recording(&mut self) {
+        self.recording = Some(Movie::new(
+            self.rom_sha1,
+            self.random,
+            self.instructions_per_frame,
+            self.timing,
+            self.load_address,
+            self.quirks,
+        ));
+    }
+
+    /// The movie so far, with the current state as its final state.
+    pub fn stop_recording(&mut self) -> Option<Movie> {
+        let mut movie = self.recording.take()?;
+        movie.set_final_state(&self.save_state());
+        Some(movie)
+    }
+
+    /// Drive the keypad from a movie instead of the keyboard, which still
+    /// gets to quit. The movie's settings (see [`Movie::quirks`] and
+    /// friends) need to be in place before the ROM is loaded.
+    pub fn play_movie(&mut self, movie: &Movie) -> Result<(), MovieError> {
+        if movie.rom_sha1() != &self.rom_sha1 {
+            return Err(MovieError::WrongRom);
         }
+        self.random = movie.random();
+        let keyboard =
+            std::mem::replace(&mut self.peripheral_driver.keyboard, Box::new(NullKeyboard));
+        self.peripheral_driver.keyboard = Box::new(movie.player(keyboard));
+        Ok(())
     }
 
-    fn execute(&mut self, byte1: u8, byte2: u8) {
-        let high_nibble = byte1 >> 4;
-        let lo_nibble = byte1 & 0x0F;
+    /// Put the machine back the way it was in `state`. States from another
+    /// ROM, or with a different amount of RAM, are turned away and leave the
+    /// machine untouched.
+    pub fn load_state(&mut self, state: &SaveState) -> Result<(), SaveStateError> {
+        if state.rom_sha1 != self.rom_sha1 {
+            return Err(SaveStateError::WrongRom);
+        }
+        if state.ram.len() != self.ram.memory.len() {
+            return Err(SaveStateError::WrongMemorySize {
+                saved: state.ram.len(),
+                current: self.ram.memory.len(),
+            });
+        }
+        if state.stack.len() > self.stack.limit() {
+            return Err(SaveStateError::Corrupt);
+        }
 
-        let high_nibble2 = byte2 >> 4;
-        let lo_nibble2 = byte2 & 0x0F;
+        self.program_counter = state.program_counter;
+        self.gpr_v = state.gpr_v;
+        self.reg_i = state.reg_i;
+        self.rpl_flags = state.rpl_flags;
+        self.random = state.random;
+        self.instructions = state.instructions;
+        self.audio_pattern = state.audio_pattern;
+        self.pitch = state.pitch;
+        self.peripheral_driver
+            .audio
+            .set_pattern(&self.audio_pattern, self.pitch);
+        self.delay_timer = state.delay_timer;
+        self.sound_timer = state.sound_timer;
+        self.stack.restore(&state.stack);
+        self.keyboard_state = state.keyboard_state;
+        self.key_wait = state.key_wait;
+        self.vblank_wait = state.vblank_wait;
+        self.frame_progress = 0;
+        self.frame_cycles = 0;
+        self.draw_cycles = 0;
+        self.display_state = state.display_state.clone();
+        self.ram.memory.copy_from_slice(&state.ram);
+        self.clear_block_cache();
+        self.exit = None;
+        Ok(())
+    }
 
-        match high_nibble {
-            0x0 => match byte2 {
-                0xE0 => {
-                    //CLS
-                    self.display_state = [[0 as u8; CHIP8_WIDTH as usize]; CHIP8_HEIGHT as usize];
-                    self.program_counter += 2;
-                }
-                0xEE => {
-                    //RET
-                    let pc_high = self.ram.memory[self.stack_pointer as usize];
-                    let pc_lo = self.ram.memory[(self.stack_pointer + 1) as usize];
+    /// Where [`Command::SaveState`] and [`Command::LoadState`] slots live.
+    pub fn set_state_dir<P: Into<PathBuf>>(&mut self, dir: P) {
+        self.state_dir = dir.into();
+    }
+
+    /// SHA-1 of the loaded ROM.
+    pub fn rom_sha1(&self) -> &[u8; 20] {
+        &self.rom_sha1
+    }
 
-                    let target: u16 = (((pc_high as u16) << 8) | (pc_lo as u16)).into();
-                    self.program_counter = target;
-                    // I've been putting CALL on the stack then popping it and immediately calling again
-                    self.program_counter += 2;
+    /// Fetch and execute a single instruction. Once the program has stopped
+    /// this keeps returning the reason why.
+    pub fn step(&mut self) -> Result<(), ExitReason> {
+        if let Some(exit) = self.exit {
+            return Err(exit);
+        }
+        self.watch_hit = None;
+        if self.key_wait.is_some() || self.vblank_wait {
+            // Idle until the next interrupt
+            self.frame_cycles = self.frame_cycles.max(VIP_FRAME_BUDGET);
+            return Ok(());
+        }
 
-                    self.stack_pointer -= 2;
+        let pc = self.program_counter;
+        let cached = match self.block_cache.as_mut() {
+            Some(cache) => cache.instruction(pc, &self.ram.memory),
+            None => None,
+        };
+        // The opcode's only needed for the trace and faults, so it isn't
+        // worked out again for cached instructions unless it has to be
+        let (instruction, opcode) = match cached {
+            Some(instruction) => (Some(instruction), None),
+            None => match self.fetch() {
+                Ok((op1, op2)) => {
+                    let opcode = (op1 as u16) << 8 | op2 as u16;
+                    (Instruction::decode(opcode), Some(opcode))
                 }
-                _ => {}
+                Err(fault) => return Err(self.fault(pc, 0, fault)),
             },
-            0x1 => {
-                // JP nnn
-                let target: u16 = (((lo_nibble as u16) << 8) | (byte2 as u16)).into();
-                //println!("\tJP {:x?}", target);
-                self.program_counter = target
-            }
-            0x2 => {
-                //CALL addr
+        };
+
+        self.trace(pc);
+
+        let cycles = match (self.timing, instruction) {
+            (Timing::CosmacVip, Some(instruction)) => vip_cycles(instruction, &self.gpr_v),
+            _ => 0,
+        };
+        let result = match instruction {
+            Some(instruction) => self.execute(instruction),
+            None => Err(Fault::UnknownOpcode),
+        };
+        if let Err(fault) = result {
+            // PC stays on the instruction that broke
+            self.program_counter = pc;
+            let opcode = opcode.or_else(|| instruction.map(|i| i.encode()));
+            return Err(self.fault(pc, opcode.unwrap_or(0), fault));
+        }
+        self.instructions += 1;
+        if let (Timing::CosmacVip, Some(instruction)) = (self.timing, instruction) {
+            let skipped = instruction.is_skip() && self.program_counter != pc.wrapping_add(2);
+            self.charge(cycles + if skipped { VIP_SKIP_CYCLES } else { 0 });
+        }
+
+        match self.exit {
+            Some(exit) => Err(exit),
+            None => Ok(()),
+        }
+    }
 
-                // inc by 2 since we want to store a 16 bit addr and our ram is u8
-                //println!("Current SP: {:?}", self.stack_pointer);
-                self.stack_pointer += 2;
+    // A DXYN that waits for the interrupt doesn't draw until after it, so
+    // its cycles come out of the next frame
+    fn charge(&mut self, cycles: u32) {
+        self.cycles += cycles as u64;
+        if self.vblank_wait {
+            self.draw_cycles = cycles;
+        } else {
+            self.frame_cycles += cycles;
+        }
+    }
 
-                let pc_high = (self.program_counter & 0xFF00) >> 8;
-                let pc_lo = self.program_counter & 0x00FF;
+    // At least one and up to `limit` instructions, by the fastest way
+    // there is, saying how many ran
+    fn run_some(&mut self, limit: u32) -> Result<u32, ExitReason> {
+        if self.tracer.is_some() {
+            return self.step().map(|()| 1);
+        }
+        if let Some(exit) = self.exit {
+            return Err(exit);
+        }
+        self.watch_hit = None;
+        if self.key_wait.is_some() || self.vblank_wait {
+            // Nothing happens until the next frame either way
+            return Ok(limit);
+        }
 
-                self.ram.memory[self.stack_pointer as usize] = pc_high as u8;
-                self.ram.memory[(self.stack_pointer + 1) as usize] = pc_lo as u8;
+        if let Some(run_block) = self.recompiled {
+            match run_block(self, limit) {
+                Ok(0) => (),
+                Ok(count) => {
+                    self.instructions += count as u64;
+                    return match self.exit {
+                        Some(exit) => Err(exit),
+                        None => Ok(count),
+                    };
+                }
+                Err(fault) => {
+                    // The recompiled code leaves the PC on whatever broke,
+                    // without saying how many ran before it, so those go
+                    // uncounted. Nothing runs after a fault anyway.
+                    let pc = self.program_counter;
+                    let opcode = self
+                        .fetch_at(pc)
+                        .map_or(0, |(hi, lo)| (hi as u16) << 8 | lo as u16);
+                    return Err(self.fault(pc, opcode, fault));
+                }
+            }
+        }
+        if self.block_cache.is_some() {
+            return self.run_block(limit);
+        }
+        self.step().map(|()| 1)
+    }
 
-                let target: u16 = (((lo_nibble as u16) << 8) | (byte2 as u16)).into();
-                self.program_counter = target
+    // Up to `limit` instructions straight from the cached block at the PC,
+    // without going back through step for each one. Stops early wherever
+    // step would have done something different, and says how many it ran.
+    fn run_block(&mut self, limit: u32) -> Result<u32, ExitReason> {
+        let pc = self.program_counter;
+        let cache = match self.block_cache.as_mut() {
+            Some(cache) => cache,
+            None => return self.step().map(|()| 1),
+        };
+        let generation = cache.generation();
+        let block = match cache.block(pc, &self.ram.memory) {
+            Some(block) if !block.ops.is_empty() => block,
+            // Out of RAM or undecodable, step reports it properly
+            _ => return self.step().map(|()| 1),
+        };
+
+        let mut count = 0;
+        let mut index = 0;
+        while count < limit {
+            let (address, instruction) = block.ops[index];
+            if let Err(fault) = self.execute(instruction) {
+                self.program_counter = address;
+                return Err(self.fault(address, instruction.encode(), fault));
             }
-            0x3 => {
-                // SE Vx, byte
-                println!(
-                    "\t SKIP IF if {:x?} == {:x?} ",
-                    self.gpr_v[lo_nibble as usize], byte2
-                );
-                if self.gpr_v[lo_nibble as usize] == byte2 {
-                    self.program_counter += 2;
-                }
-                self.program_counter += 2;
+            count += 1;
+            self.instructions += 1;
+            if let Some(exit) = self.exit {
+                return Err(exit);
             }
-            0x4 => {
-                // SNE Vx, byte
-                if self.gpr_v[lo_nibble as usize] != byte2 {
-                    self.program_counter += 2;
-                }
-                self.program_counter += 2;
+            let cache_changed =
+                self.block_cache.as_ref().map(BlockCache::generation) != Some(generation);
+            if self.key_wait.is_some() || self.vblank_wait || cache_changed {
+                break;
             }
-            0x5 => {
-                // SE Vx, Vy
-                if self.gpr_v[lo_nibble as usize] == self.gpr_v[high_nibble2 as usize] {
-                    self.program_counter += 2;
+            // On to the next instruction, or the one after if that was a
+            // skip, as long as that's where the PC went
+            let pc = self.program_counter;
+            match block.ops[index + 1..]
+                .iter()
+                .take(2)
+                .position(|&(address, _)| address == pc)
+            {
+                Some(offset) => index += offset + 1,
+                None => break,
+            }
+        }
+        Ok(count)
+    }
+
+    fn fault(&mut self, pc: u16, opcode: u16, fault: Fault) -> ExitReason {
+        let exit = ExitReason::Fault { pc, opcode, fault };
+        self.exit = Some(exit);
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.fault();
+        }
+        exit
+    }
+
+    /// Run a frame's worth of instructions, tick the timers once and
+    /// present the display.
+    pub fn step_frame(&mut self) -> Result<(), ExitReason> {
+        while !self.frame_finished() {
+            self.frame_progress += match self.timing {
+                // Every instruction goes through step to be charged for
+                Timing::CosmacVip => self.step().map(|()| 1)?,
+                Timing::Instructions => {
+                    self.run_some(self.instructions_per_frame - self.frame_progress)?
                 }
-                self.program_counter += 2;
+            };
+        }
+        self.end_frame();
+        Ok(())
+    }
+
+    /// A single instruction, like [`Processor::step`], but counted towards
+    /// the frame so the timers and display still move on after every
+    /// `instructions_per_frame` of them.
+    pub fn step_instruction(&mut self) -> Result<(), ExitReason> {
+        self.step()?;
+        self.frame_progress += 1;
+        if self.frame_finished() {
+            self.end_frame();
+        }
+        Ok(())
+    }
+
+    fn frame_finished(&self) -> bool {
+        match self.timing {
+            Timing::Instructions => self.frame_progress >= self.instructions_per_frame,
+            Timing::CosmacVip => self.frame_cycles >= VIP_FRAME_BUDGET,
+        }
+    }
+
+    fn end_frame(&mut self) {
+        self.frame_progress = 0;
+        // Whatever ran past the interrupt, and any sprite that was waiting
+        // for it
+        self.frame_cycles = self.frame_cycles.saturating_sub(VIP_FRAME_BUDGET) + self.draw_cycles;
+        self.draw_cycles = 0;
+        self.vblank_wait = false;
+        self.tick_timers();
+        self.peripheral_driver.display.draw(&self.display_state);
+    }
+
+    fn fetch(&self) -> Result<(u8, u8), Fault> {
+        self.fetch_at(self.program_counter)
+    }
+
+    fn fetch_at(&self, address: u16) -> Result<(u8, u8), Fault> {
+        let op1 = self.ram.read(address as usize)?;
+        let op2 = self.ram.read(address as usize + 1)?;
+        Ok((op1, op2))
+    }
+
+    // Both timers count down at 60 Hz, so this should be called once a frame
+    fn tick_timers(&mut self) {
+        if self.delay_timer > 0 {
+            self.delay_timer -= 1;
+        }
+        if self.sound_timer > 0 {
+            // play sound
+            self.peripheral_driver.audio.start_beep();
+            self.sound_timer -= 1;
+        } else {
+            self.peripheral_driver.audio.stop_beep();
+        }
+    }
+
+    /// Instructions run so far this frame, 0 at the start of one.
+    pub fn frame_progress(&self) -> u32 {
+        self.frame_progress
+    }
+
+    pub fn instructions_per_frame(&self) -> u32 {
+        self.instructions_per_frame
+    }
+
+    pub fn set_instructions_per_frame(&mut self, instructions_per_frame: u32) {
+        self.instructions_per_frame = instructions_per_frame;
+    }
+
+    pub fn timing(&self) -> Timing {
+        self.timing
+    }
+
+    /// Whether a frame is `instructions_per_frame` instructions, or as many
+    /// as a COSMAC VIP would have got through.
+    pub fn set_timing(&mut self, timing: Timing) {
+        self.timing = timing;
+        self.frame_cycles = 0;
+        self.draw_cycles = 0;
+    }
+
+    /// Instructions executed since the last reset, however they were run.
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    /// VIP machine cycles spent on instructions since the last reset, only
+    /// counted with [`Timing::CosmacVip`].
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Where CXNN's numbers come from, set a seeded one for runs that
+    /// play out the same every time.
+    pub fn set_random(&mut self, random: Random) {
+        self.random = random;
+    }
+
+    pub fn random(&self) -> Random {
+        self.random
+    }
+
+    pub fn quirks(&self) -> &Quirks {
+        &self.quirks
+    }
+
+    /// Switching in or out of XO-CHIP grows or shrinks RAM, so this is best
+    /// done before loading a ROM.
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+        self.stack = CallStack::new(quirks.stack_depth, quirks.stack_in_ram);
+        let size = self.memory_size();
+        self.ram.memory.resize(size, 0);
+        self.clear_block_cache();
+    }
+
+    fn memory_size(&self) -> usize {
+        if self.quirks.xo_chip {
+            XO_RAM_SIZE
+        } else {
+            RAM_SIZE
+        }
+    }
+
+    /// Decode each run of straight-line code once and keep it, instead of
+    /// decoding every instruction every time it runs. Worth it for long
+    /// headless runs; writes to cached code are noticed either way.
+    /// [`Processor::run`] and [`Processor::step_frame`] also run whole
+    /// blocks at once, anything that stops between instructions still goes
+    /// one at a time.
+    ///
+    /// Whole blocks, and recompiled code, are skipped while tracing or with
+    /// [`Timing::CosmacVip`], since every instruction has to be traced or
+    /// charged for on its own.
+    pub fn set_block_cache(&mut self, enabled: bool) {
+        self.block_cache = if enabled {
+            Some(BlockCache::new(self.ram.memory.len()))
+        } else {
+            None
+        };
+    }
+
+    pub fn block_cache_enabled(&self) -> bool {
+        self.block_cache.is_some()
+    }
+
+    /// Let [`Processor::run`] and [`Processor::step_frame`] run the
+    /// `run_block` of a ROM that's been through [`crate::recompile`],
+    /// whenever the PC is somewhere it knows about, and nothing rules it out
+    /// as it does whole blocks, see [`Processor::set_block_cache`].
+    pub fn set_recompiled(&mut self, run_block: Option<RunBlock>) {
+        self.recompiled = run_block;
+    }
+
+    fn clear_block_cache(&mut self) {
+        if let Some(cache) = self.block_cache.as_mut() {
+            cache.clear();
+        }
+    }
+
+    // Anything that writes to RAM outside of load_rom and load_state has to
+    // come through here, in case it's changing code that's been cached
+    fn invalidate_block_cache(&mut self, start: usize, len: usize) {
+        if let Some(cache) = self.block_cache.as_mut() {
+            cache.invalidate(start, len);
+        }
+    }
+
+    /// Record every instruction before it runs, or stop with None.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    pub fn tracer(&self) -> Option<&Tracer> {
+        self.tracer.as_ref()
+    }
+
+    pub fn tracer_mut(&mut self) -> Option<&mut Tracer> {
+        self.tracer.as_mut()
+    }
+
+    fn trace(&mut self, pc: u16) {
+        if !matches!(&self.tracer, Some(tracer) if tracer.wants(pc)) {
+            return;
+        }
+        let word = |address: u16| {
+            self.fetch_at(address)
+                .ok()
+                .map(|(hi, lo)| (hi as u16) << 8 | lo as u16)
+        };
+        let opcode = word(pc).unwrap_or(0);
+        let long = match opcode {
+            0xF000 => word(pc.wrapping_add(2)),
+            _ => None,
+        };
+        let entry = TraceEntry {
+            index: 0,
+            instructions: self.instructions,
+            cycles: match self.timing {
+                Timing::CosmacVip => Some(self.cycles),
+                Timing::Instructions => None,
+            },
+            pc,
+            opcode,
+            long,
+            registers: self.gpr_v,
+            i: self.reg_i,
+            delay_timer: self.delay_timer,
+            sound_timer: self.sound_timer,
+        };
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.record(entry);
+        }
+    }
+
+    /// True once the program has stopped, see [`Processor::exit_reason`].
+    pub fn halted(&self) -> bool {
+        self.exit.is_some()
+    }
+
+    pub fn exit_reason(&self) -> Option<ExitReason> {
+        self.exit
+    }
+
+    /// True while an Fx0A is waiting for a key to be pressed and released.
+    pub fn waiting_for_key(&self) -> bool {
+        self.key_wait.is_some()
+    }
+
+    pub fn set_key(&mut self, key: u8, pressed: bool) {
+        let key = key & 0xF;
+        let was_pressed = self.keyboard_state[key as usize];
+        self.keyboard_state[key as usize] = pressed;
+
+        if pressed && !was_pressed {
+            self.key_down(key);
+        } else if !pressed && was_pressed {
+            self.key_up(key);
+        }
+    }
+
+    /// Take a full poll from a [`Keyboard`](crate::drivers::Keyboard).
+    pub fn update_keys(&mut self, key_state: &KeyState) {
+        self.keyboard_state = key_state.held;
+
+        // Presses first, so a tap that fits entirely between two polls
+        // still counts as a press and a release
+        for key in 0..16 {
+            if key_state.pressed[key as usize] {
+                self.key_down(key);
+            }
+        }
+        for key in 0..16 {
+            if key_state.released[key as usize] {
+                self.key_up(key);
             }
-            0x6 => {
-                //LD Vx, byte
-                self.gpr_v[lo_nibble as usize] = byte2;
+        }
+    }
 
-                self.program_counter += 2;
+    fn key_down(&mut self, key: u8) {
+        if let Some(wait) = self.key_wait.as_mut() {
+            if wait.key.is_none() {
+                wait.key = Some(key);
             }
-            0x7 => {
-                // ADD Vx, byte
-                self.gpr_v[lo_nibble as usize] = self.gpr_v[lo_nibble as usize].wrapping_add(byte2);
+        }
+    }
 
-                self.program_counter += 2;
+    fn key_up(&mut self, key: u8) {
+        if let Some(wait) = self.key_wait {
+            if wait.key == Some(key) {
+                self.gpr_v[wait.register] = key;
+                self.key_wait = None;
+                self.advance(2);
             }
-            0x8 => match lo_nibble2 {
-                0x0 => {
-                    // LD Vx, Vy
-                    self.gpr_v[lo_nibble as usize] = self.gpr_v[high_nibble2 as usize];
+        }
+    }
 
-                    self.program_counter += 2;
-                }
-                0x1 => {
-                    // OR Vx, Vy
-                    self.gpr_v[lo_nibble as usize] =
-                        self.gpr_v[lo_nibble as usize] | self.gpr_v[high_nibble2 as usize];
+    pub fn keyboard_state(&self) -> &[bool; 16] {
+        &self.keyboard_state
+    }
 
-                    self.program_counter += 2;
-                }
-                0x2 => {
-                    // AND Vx, Vy
-                    self.gpr_v[lo_nibble as usize] =
-                        self.gpr_v[lo_nibble as usize] & self.gpr_v[high_nibble2 as usize];
+    pub fn registers(&self) -> &[u8; GPR_SIZE] {
+        &self.gpr_v
+    }
 
-                    self.program_counter += 2;
-                }
-                0x3 => {
-                    // XOR Vx, Vy
-                    self.gpr_v[lo_nibble as usize] =
-                        self.gpr_v[lo_nibble as usize] ^ self.gpr_v[high_nibble2 as usize];
+    pub fn registers_mut(&mut self) -> &mut [u8; GPR_SIZE] {
+        &mut self.gpr_v
+    }
 
-                    self.program_counter += 2;
-                }
-                0x4 => {
-                    // ADD Vx, Vy, set VF to carry
-                    let vx = self.gpr_v[lo_nibble as usize] as u16;
-                    let vy = self.gpr_v[high_nibble2 as usize] as u16;
-                    let result = vx + vy;
+    pub fn reg_i(&self) -> u16 {
+        self.reg_i
+    }
 
-                    self.gpr_v[lo_nibble as usize] = result as u8;
-                    self.gpr_v[0x0f] = if result > 0xFF { 1 } else { 0 };
+    pub fn program_counter(&self) -> u16 {
+        self.program_counter
+    }
 
-                    self.program_counter += 2;
-                }
-                0x5 => {
-                    //SUB Vx, Vy
-                    if self.gpr_v[lo_nibble as usize] > self.gpr_v[high_nibble2 as usize] {
-                        self.gpr_v[0xf] = 1
-                    } else {
-                        self.gpr_v[0xf] = 0
-                    }
+    pub fn stack(&self) -> &CallStack {
+        &self.stack
+    }
 
-                    self.gpr_v[lo_nibble as usize] =
-                        self.gpr_v[lo_nibble as usize] - self.gpr_v[high_nibble2 as usize];
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
 
-                    self.program_counter += 2;
-                }
-                0x6 => {
-                    // SHR Vx
-                    if self.gpr_v[lo_nibble as usize] & 0x1 == 1 {
-                        // I think this might also be wrong
-                        self.gpr_v[0xf] = 1
-                    } else {
-                        self.gpr_v[0xf] = 0
-                    }
-                    self.gpr_v[lo_nibble as usize] = self.gpr_v[lo_nibble as usize] >> 1;
+    pub fn sound_timer(&self) -> u8 {
+        self.sound_timer
+    }
 
-                    self.program_counter += 2;
-                }
-                0x7 => {
-                    // SUBN Vx, Vy
-                    if self.gpr_v[lo_nibble as usize] < self.gpr_v[high_nibble2 as usize] {
-                        self.gpr_v[0xf] = 1
-                    } else {
-                        self.gpr_v[0xf] = 0
-                    }
+    /// All of RAM, 4 KB or 64 KB for XO-CHIP.
+    pub fn memory(&self) -> &[u8] {
+        &self.ram.memory
+    }
 
-                    self.gpr_v[lo_nibble as usize] =
-                        self.gpr_v[high_nibble2 as usize] - self.gpr_v[lo_nibble as usize];
+    pub fn display_state(&self) -> &Framebuffer {
+        &self.display_state
+    }
 
-                    self.program_counter += 2;
-                }
-                0xE => {
-                    // SHL Vx
-                    if self.gpr_v[lo_nibble as usize] & 0x8 == 1 {
-                        // I think this is wrong
-                        self.gpr_v[0xf] = 1
-                    } else {
-                        self.gpr_v[0xf] = 0
-                    }
-                    self.gpr_v[lo_nibble as usize] = self.gpr_v[lo_nibble as usize] << 1;
+    // Poking at the machine from a debugger
+
+    pub fn set_register(&mut self, register: usize, value: u8) {
+        self.gpr_v[register & 0xF] = value;
+    }
+
+    pub fn set_reg_i(&mut self, value: u16) {
+        self.reg_i = value;
+    }
+
+    pub fn set_program_counter(&mut self, address: u16) {
+        self.program_counter = address;
+    }
+
+    pub fn set_delay_timer(&mut self, value: u8) {
+        self.delay_timer = value;
+    }
+
+    pub fn set_sound_timer(&mut self, value: u8) {
+        self.sound_timer = value;
+    }
+
+    pub fn write_memory(&mut self, address: usize, bytes: &[u8]) -> Result<(), Fault> {
+        self.invalidate_block_cache(address, bytes.len());
+        for (i, &byte) in bytes.iter().enumerate() {
+            self.ram.write(address + i, byte)?;
+        }
+        Ok(())
+    }
+
+    pub fn push_stack(&mut self, address: u16) -> Result<(), Fault> {
+        self.stack.push(address, &mut self.ram.memory)?;
+        self.invalidate_stack_slot();
+        Ok(())
+    }
+
+    // With the stack in RAM, a CALL writes its return address there
+    fn invalidate_stack_slot(&mut self) {
+        if self.stack.in_ram() {
+            let slot = VIP_STACK_TOP as usize - 2 * self.stack.depth();
+            self.invalidate_block_cache(slot, 2);
+        }
+    }
+
+    pub fn pop_stack(&mut self) -> Result<u16, Fault> {
+        Ok(self.stack.pop(&self.ram.memory)?)
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    /// Remove every watchpoint starting at `start`, false if there weren't
+    /// any.
+    pub fn remove_watchpoints(&mut self, start: u16) -> bool {
+        let before = self.watchpoints.len();
+        self.watchpoints
+            .retain(|watchpoint| watchpoint.start != start);
+        self.watchpoints.len() != before
+    }
 
-                    self.program_counter += 2;
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Whether the last instruction touched a watched address, and how.
+    pub fn watch_hit(&self) -> Option<WatchHit> {
+        self.watch_hit
+    }
+
+    // Called from execute wherever it reads or writes RAM at I
+    fn watch(&mut self, start: usize, len: usize, access: Access) {
+        // Carrying on from 0, see at_i
+        if self.quirks.xo_chip && start + len > XO_RAM_SIZE {
+            self.watch(start, XO_RAM_SIZE - start, access);
+            self.watch(0, start + len - XO_RAM_SIZE, access);
+            return;
+        }
+        if access == Access::Write {
+            self.invalidate_block_cache(start, len);
+        }
+        let end = start + len;
+        let hit = self.watchpoints.iter().find(|watchpoint| {
+            watchpoint.access.includes(access)
+                && start <= watchpoint.end as usize
+                && (watchpoint.start as usize) < end
+        });
+        if let Some(watchpoint) = hit {
+            self.watch_hit = Some(WatchHit {
+                pc: self.program_counter,
+                address: start.max(watchpoint.start as usize) as u16,
+                access,
+            });
+        }
+    }
+
+    /// The instruction at `address`, as it would show up in the trace.
+    pub fn disassemble(&self, address: u16) -> Result<String, Fault> {
+        let (op1, op2) = self.fetch_at(address)?;
+        let op = (op1 as u16) << 8 | op2 as u16;
+        // F000 takes its address from the next word
+        let long = match op {
+            0xF000 => self
+                .fetch_at(address.wrapping_add(2))
+                .ok()
+                .map(|(hi, lo)| (hi as u16) << 8 | lo as u16),
+            _ => None,
+        };
+        Ok(disassemble_instruction(op, long))
+    }
+
+    /// Carry out `instruction` as if it were at the PC, for recompiled
+    /// code. False once nothing else should run this frame: the program's
+    /// finished, or it's waiting for a key or the display.
+    pub fn execute_instruction(&mut self, instruction: Instruction) -> Result<bool, Fault> {
+        self.execute(instruction)?;
+        Ok(self.exit.is_none() && self.key_wait.is_none() && !self.vblank_wait)
+    }
+
+    fn execute(&mut self, instruction: Instruction) -> Result<(), Fault> {
+        use crate::instruction::Instruction::*;
+
+        // The XO-CHIP additions are unknown opcodes to everything else
+        let xo_chip_only = matches!(
+            instruction,
+            ScrollUp(_) | Save { .. } | Load { .. } | LoadILong | Plane(_) | Audio | Pitch(_)
+        );
+        if xo_chip_only && !self.quirks.xo_chip {
+            return Err(Fault::UnknownOpcode);
+        }
+
+        match instruction {
+            Cls => {
+                self.display_state.clear();
+                self.advance(2);
+            }
+            Ret => {
+                self.program_counter = self.stack.pop(&self.ram.memory)?;
+            }
+            ScrollUp(n) => {
+                self.display_state.scroll_up(n as usize);
+                self.advance(2);
+            }
+            ScrollDown(n) => {
+                self.display_state.scroll_down(n as usize);
+                self.advance(2);
+            }
+            ScrollRight => {
+                self.display_state.scroll_right();
+                self.advance(2);
+            }
+            ScrollLeft => {
+                self.display_state.scroll_left();
+                self.advance(2);
+            }
+            Exit => {
+                self.exit = Some(ExitReason::ProgramExit);
+            }
+            Low => {
+                self.display_state.set_hires(false);
+                self.advance(2);
+            }
+            High => {
+                self.display_state.set_hires(true);
+                self.advance(2);
+            }
+            Jump(target) => {
+                self.program_counter = target;
+            }
+            Call(target) => {
+                let return_address = self.program_counter.wrapping_add(2);
+                self.stack.push(return_address, &mut self.ram.memory)?;
+                self.invalidate_stack_slot();
+                self.program_counter = target;
+            }
+            SkipEqual { x, value } => {
+                if self.gpr_v[x as usize] == value {
+                    self.skip_next();
                 }
-                _ => {}
-            },
-            0x9 => {
-                // SNE Vx, Vy
-                if self.gpr_v[lo_nibble as usize] != self.gpr_v[high_nibble2 as usize] {
-                    // Extra +2 will skip the next instruction
-                    self.program_counter += 2;
+                self.advance(2);
+            }
+            SkipNotEqual { x, value } => {
+                if self.gpr_v[x as usize] != value {
+                    self.skip_next();
                 }
-                self.program_counter += 2;
-            }
-            0xA => {
-                // LD I, addr
-                let target: u16 = (((lo_nibble as u16) << 8) | (byte2 as u16)).into();
-                self.reg_i = target;
-
-                self.program_counter += 2;
-            }
-            0xB => {
-                // JP V0, addr
-                let target: u16 = (((lo_nibble as u16) << 8) | (byte2 as u16)).into();
-                self.program_counter = target + self.gpr_v[0 as usize] as u16;
-            }
-            0xC => {
-                // RND Vx, byte
-                let random = rand::random::<u8>();
-
-                self.gpr_v[lo_nibble as usize] = random & byte2;
-
-                self.program_counter += 2;
-            }
-            0xD => {
-                // DRW Vx, Vy, nibble
-
-                // Get a slice of ram[reg_i..reg_i + lo_nib2]
-                // each byte will occupy 8 rows
-                // num of columns represented by number of bytes
-                self.gpr_v[0x0f] = 0;
-                for byte in 0..(lo_nibble2 as usize) {
-                    let y = (self.gpr_v[high_nibble2 as usize] as usize + byte) % CHIP8_HEIGHT;
-                    for bit in 0..8 {
-                        let x = (self.gpr_v[lo_nibble as usize] as usize + bit) % CHIP8_WIDTH;
-                        let color = (self.ram.memory[(self.reg_i + (byte as u16)) as usize]
-                            >> (7 - bit))
-                            & 1;
-                        self.gpr_v[0x0f] |= color & self.display_state[y][x];
-                        self.display_state[y][x] ^= color;
-                    }
+                self.advance(2);
+            }
+            SkipEqualRegister { x, y } => {
+                if self.gpr_v[x as usize] == self.gpr_v[y as usize] {
+                    self.skip_next();
                 }
-
-                self.program_counter += 2;
+                self.advance(2);
             }
-            0xE => match byte2 {
-                0x9E => {
-                    // SKP Vx, lo_nibble
-                    if self.keyboard_state[self.gpr_v[lo_nibble as usize] as usize] {
-                        self.program_counter += 2;
-                    }
-
-                    self.program_counter += 2;
+            Save { x, y } => {
+                // Store Vx through Vy at I, either direction, I is untouched
+                let count = register_range(x, y).count();
+                self.watch(self.reg_i as usize, count, Access::Write);
+                for (offset, register) in register_range(x, y).enumerate() {
+                    self.ram.write(self.at_i(offset), self.gpr_v[register])?;
                 }
-                0xA1 => {
-                    // SKNP Vx, lo_nibble
-                    if !self.keyboard_state[self.gpr_v[lo_nibble as usize] as usize] {
-                        self.program_counter += 2;
-                    }
-                    self.program_counter += 2;
+                self.advance(2);
+            }
+            Load { x, y } => {
+                let count = register_range(x, y).count();
+                self.watch(self.reg_i as usize, count, Access::Read);
+                for (offset, register) in register_range(x, y).enumerate() {
+                    self.gpr_v[register] = self.ram.read(self.at_i(offset))?;
                 }
-                _ => {}
-            },
-            0xF => match byte2 {
-                0x07 => {
-                    // LD Vx, DT
-                    self.gpr_v[lo_nibble as usize] = self.delay_timer;
-
-                    self.program_counter += 2;
+                self.advance(2);
+            }
+            LoadByte { x, value } => {
+                self.gpr_v[x as usize] = value;
+                self.advance(2);
+            }
+            AddByte { x, value } => {
+                self.gpr_v[x as usize] = self.gpr_v[x as usize].wrapping_add(value);
+                self.advance(2);
+            }
+            Move { x, y } => {
+                self.gpr_v[x as usize] = self.gpr_v[y as usize];
+                self.advance(2);
+            }
+            Or { x, y } => {
+                self.gpr_v[x as usize] |= self.gpr_v[y as usize];
+                if self.quirks.logic_resets_vf {
+                    self.gpr_v[0xf] = 0;
                 }
-                0x0A => {
-                    println!("\n\n!!! TODO LD Vx, K !!!\n\n");
-                    //
-                    // TODO !!! not in pong !!!
-                    // LD Vx, K
-                    // OOO. Halt execution until a key is pressed, then load the value into Vx
-                    //
-                    // how does SDL handle this?
-                    // or just fucking loop in here dawg
-                    //
-                    //format!("LD V{:x?}, K", lo_nibble),
-                    self.program_counter += 2;
+                self.advance(2);
+            }
+            And { x, y } => {
+                self.gpr_v[x as usize] &= self.gpr_v[y as usize];
+                if self.quirks.logic_resets_vf {
+                    self.gpr_v[0xf] = 0;
                 }
-                0x15 => {
-                    // LD DT, Vx
-                    self.delay_timer = self.gpr_v[lo_nibble as usize];
-                    println!("\tDT: {:x?}", self.delay_timer);
-                    self.program_counter += 2;
+                self.advance(2);
+            }
+            Xor { x, y } => {
+                self.gpr_v[x as usize] ^= self.gpr_v[y as usize];
+                if self.quirks.logic_resets_vf {
+                    self.gpr_v[0xf] = 0;
                 }
-                0x18 => {
-                    //LD ST, Vx
-                    self.sound_timer = self.gpr_v[lo_nibble as usize];
-
-                    self.program_counter += 2;
+                self.advance(2);
+            }
+            Add { x, y } => {
+                // VF is the carry
+                let vx = self.gpr_v[x as usize] as u16;
+                let vy = self.gpr_v[y as usize] as u16;
+                let result = vx + vy;
+
+                self.gpr_v[x as usize] = result as u8;
+                self.gpr_v[0x0f] = (result > 0xFF) as u8;
+                self.advance(2);
+            }
+            Sub { x, y } => {
+                // VF is NOT borrow, set last in case it's also Vx
+                let vx = self.gpr_v[x as usize];
+                let vy = self.gpr_v[y as usize];
+                self.gpr_v[x as usize] = vx.wrapping_sub(vy);
+                self.gpr_v[0xf] = (vx >= vy) as u8;
+                self.advance(2);
+            }
+            ShiftRight { x, y } => {
+                let value = if self.quirks.shift_uses_vy {
+                    self.gpr_v[y as usize]
+                } else {
+                    self.gpr_v[x as usize]
+                };
+                self.gpr_v[x as usize] = value >> 1;
+                // VF last, it wins if it's also the destination
+                self.gpr_v[0xf] = value & 0x1;
+                self.advance(2);
+            }
+            SubN { x, y } => {
+                let vx = self.gpr_v[x as usize];
+                let vy = self.gpr_v[y as usize];
+                self.gpr_v[x as usize] = vy.wrapping_sub(vx);
+                self.gpr_v[0xf] = (vy >= vx) as u8;
+                self.advance(2);
+            }
+            ShiftLeft { x, y } => {
+                let value = if self.quirks.shift_uses_vy {
+                    self.gpr_v[y as usize]
+                } else {
+                    self.gpr_v[x as usize]
+                };
+                self.gpr_v[x as usize] = value << 1;
+                self.gpr_v[0xf] = value >> 7;
+                self.advance(2);
+            }
+            SkipNotEqualRegister { x, y } => {
+                if self.gpr_v[x as usize] != self.gpr_v[y as usize] {
+                    self.skip_next();
                 }
-                0x1E => {
-                    // ADD I, Vx
-                    self.reg_i = self.reg_i + self.gpr_v[lo_nibble as usize] as u16;
-
-                    self.program_counter += 2;
+                self.advance(2);
+            }
+            LoadI(address) => {
+                self.reg_i = address;
+                self.advance(2);
+            }
+            JumpV0(target) => {
+                // CHIP-48 misread this as BXNN, jump to XNN + VX
+                let offset = if self.quirks.jump_uses_vx {
+                    self.gpr_v[(target >> 8) as usize]
+                } else {
+                    self.gpr_v[0]
+                };
+                self.program_counter = target + offset as u16;
+            }
+            Random { x, mask } => {
+                let random = self.random.next_byte();
+                self.gpr_v[x as usize] = random & mask;
+                self.advance(2);
+            }
+            Draw { x, y, rows } => {
+                // 0 rows is SUPER-CHIP's 16x16 sprite, two bytes a row
+                let (rows, wide) = if rows == 0 {
+                    (16, true)
+                } else {
+                    (rows as usize, false)
+                };
+                let bytes = self.display_state.sprite_len(rows, wide);
+                self.watch(self.reg_i as usize, bytes, Access::Read);
+                let sprite = self.read_at_i(bytes)?;
+                let y = self.gpr_v[y as usize] as usize;
+                let collisions = self.display_state.draw_sprite(
+                    self.gpr_v[x as usize] as usize,
+                    y,
+                    &sprite,
+                    rows,
+                    wide,
+                    self.quirks.wrap_sprites,
+                );
+                self.gpr_v[0x0f] = if self.display_state.hires() && !self.quirks.xo_chip {
+                    // SUPER-CHIP 1.1 counts the rows that hit something,
+                    // and any that fell off the bottom of the screen
+                    let height = self.display_state.height();
+                    let clipped = if self.quirks.wrap_sprites {
+                        0
+                    } else {
+                        (y % height + rows).saturating_sub(height)
+                    };
+                    (collisions + clipped) as u8
+                } else {
+                    (collisions > 0) as u8
+                };
+
+                if self.quirks.display_wait {
+                    // Nothing else runs until the next frame
+                    self.vblank_wait = true;
                 }
-                0x29 => {
-                    // LD F, Vx
-
-                    // since the fonts are loaded at the front of the memory, accessing them
-                    // by this with no offset should get me what I want?
-                    let maybe_font =
-                        self.ram.memory[self.gpr_v[lo_nibble as usize] as usize] as u16;
-                    println!("\tmaybe font? {:x?}", maybe_font);
-                    self.reg_i = self.ram.memory[self.gpr_v[lo_nibble as usize] as usize] as u16;
-
-                    self.program_counter += 2;
+                self.advance(2);
+            }
+            SkipKey(x) => {
+                if self.keyboard_state[(self.gpr_v[x as usize] & 0xF) as usize] {
+                    self.skip_next();
                 }
-                0x33 => {
-                    // LD B, Vx
-
-                    let mut value = self.gpr_v[lo_nibble as usize];
-                    let ones = value % 10;
-                    value = value / 10;
-                    let tens = value % 10;
-                    let hundreds = value / 10;
-                    self.ram.memory[self.reg_i as usize] = hundreds;
-                    self.ram.memory[(self.reg_i + 1) as usize] = tens;
-                    self.ram.memory[(self.reg_i + 2) as usize] = ones;
-
-                    self.program_counter += 2;
+                self.advance(2);
+            }
+            SkipNotKey(x) => {
+                if !self.keyboard_state[(self.gpr_v[x as usize] & 0xF) as usize] {
+                    self.skip_next();
                 }
-                0x55 => {
-                    // LD [I], Vx
-                    //Store registers V0 through Vx in memory starting at location I.
-                    for i in 0x0..=lo_nibble {
-                        self.ram.memory[(self.reg_i + (i as u16)) as usize] =
-                            self.gpr_v[i as usize];
-                    }
-
-                    self.program_counter += 2;
+                self.advance(2);
+            }
+            LoadILong => {
+                // The address is the whole next word
+                let (hi, lo) = self.fetch_at(self.program_counter.wrapping_add(2))?;
+                self.reg_i = ((hi as u16) << 8) | (lo as u16);
+                self.advance(4);
+            }
+            Plane(planes) => {
+                self.display_state.set_planes(planes);
+                self.advance(2);
+            }
+            Audio => {
+                // Load the 16 byte (128 sample) pattern at I
+                self.watch(self.reg_i as usize, 16, Access::Read);
+                let pattern = self.read_at_i(16)?;
+                self.audio_pattern.copy_from_slice(&pattern);
+                self.peripheral_driver
+                    .audio
+                    .set_pattern(&self.audio_pattern, self.pitch);
+                self.advance(2);
+            }
+            Pitch(x) => {
+                self.pitch = self.gpr_v[x as usize];
+                self.peripheral_driver
+                    .audio
+                    .set_pattern(&self.audio_pattern, self.pitch);
+                self.advance(2);
+            }
+            GetDelay(x) => {
+                self.gpr_v[x as usize] = self.delay_timer;
+                self.advance(2);
+            }
+            WaitKey(x) => {
+                // Halt until a key is pressed and released, like the VIP.
+                // The PC is left alone, key_up() moves past us once the
+                // key comes back up.
+                self.key_wait = Some(KeyWait {
+                    register: x as usize,
+                    key: None,
+                });
+            }
+            SetDelay(x) => {
+                self.delay_timer = self.gpr_v[x as usize];
+                self.advance(2);
+            }
+            SetSound(x) => {
+                self.sound_timer = self.gpr_v[x as usize];
+                self.advance(2);
+            }
+            AddI(x) => {
+                self.reg_i = self.reg_i.wrapping_add(self.gpr_v[x as usize] as u16);
+                self.advance(2);
+            }
+            Font(x) => {
+                // Small digits are 5 bytes each
+                let digit = (self.gpr_v[x as usize] & 0xF) as usize;
+                self.reg_i = (FONT_START + digit * 5) as u16;
+                self.advance(2);
+            }
+            BigFont(x) => {
+                // Big digits are 10 bytes each
+                let digit = (self.gpr_v[x as usize] & 0xF) as usize;
+                self.reg_i = (BIG_FONT_START + digit * 10) as u16;
+                self.advance(2);
+            }
+            Bcd(x) => {
+                let mut value = self.gpr_v[x as usize];
+                let ones = value % 10;
+                value /= 10;
+                let tens = value % 10;
+                let hundreds = value / 10;
+                self.watch(self.reg_i as usize, 3, Access::Write);
+                for (offset, digit) in [hundreds, tens, ones].iter().enumerate() {
+                    self.ram.write(self.at_i(offset), *digit)?;
                 }
-                0x65 => {
-                    // LD Vx, [I]
-                    //Read registers V0 through Vx from memory starting at location I.
-                    for i in 0x0..=lo_nibble {
-                        self.gpr_v[i as usize] =
-                            self.ram.memory[(self.reg_i + (i as u16)) as usize];
-                    }
-                    self.program_counter += 2;
+                self.advance(2);
+            }
+            StoreRegisters(x) => {
+                // V0 through Vx to memory starting at I
+                self.watch(self.reg_i as usize, x as usize + 1, Access::Write);
+                for i in 0x0..=x as usize {
+                    self.ram.write(self.at_i(i), self.gpr_v[i])?;
                 }
-                _ => {}
-            },
-            _ => {}
+                self.increment_i_after_load_store(x);
+                self.advance(2);
+            }
+            LoadRegisters(x) => {
+                // V0 through Vx from memory starting at I
+                self.watch(self.reg_i as usize, x as usize + 1, Access::Read);
+                for i in 0x0..=x as usize {
+                    self.gpr_v[i] = self.ram.read(self.at_i(i))?;
+                }
+                self.increment_i_after_load_store(x);
+                self.advance(2);
+            }
+            SaveFlags(x) => {
+                // The HP48's RPL user flags, only 8 of them there but
+                // XO-CHIP has 16
+                for i in 0x0..=self.rpl_flag_limit(x) {
+                    self.rpl_flags[i as usize] = self.gpr_v[i as usize];
+                }
+                self.advance(2);
+            }
+            LoadFlags(x) => {
+                for i in 0x0..=self.rpl_flag_limit(x) {
+                    self.gpr_v[i as usize] = self.rpl_flags[i as usize];
+                }
+                self.advance(2);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn rpl_flag_limit(&self, x: u8) -> u8 {
+        if self.quirks.xo_chip {
+            x
+        } else {
+            x & 0x7
+        }
+    }
+
+    // Skip the next instruction, which for XO-CHIP might be the four byte
+    // F000 NNNN
+    fn skip_next(&mut self) {
+        let next = self.program_counter.wrapping_add(2);
+        if self.quirks.xo_chip && self.fetch_at(next) == Ok((0xF0, 0x00)) {
+            self.advance(4);
+        } else {
+            self.advance(2);
+        }
+    }
+
+    // Off the end of XO-CHIP's 64 KB comes back round to 0, like I does
+    fn advance(&mut self, bytes: u16) {
+        self.program_counter = self.program_counter.wrapping_add(bytes);
+    }
+
+    // The address `offset` bytes on from I. All 64 KB of XO-CHIP's RAM can
+    // be addressed, so that comes back round to 0 the way I itself does;
+    // anywhere else going off the end is a fault for the RAM to report.
+    fn at_i(&self, offset: usize) -> usize {
+        let address = self.reg_i as usize + offset;
+        if self.quirks.xo_chip {
+            address & 0xFFFF
+        } else {
+            address
+        }
+    }
+
+    fn read_at_i(&self, len: usize) -> Result<Vec<u8>, Fault> {
+        (0..len)
+            .map(|offset| self.ram.read(self.at_i(offset)))
+            .collect()
+    }
+
+    fn increment_i_after_load_store(&mut self, x: u8) {
+        match self.quirks.index_increment {
+            IndexIncrement::Unchanged => {}
+            IndexIncrement::ByX => self.reg_i = self.reg_i.wrapping_add(x as u16),
+            IndexIncrement::ByXPlusOne => self.reg_i = self.reg_i.wrapping_add(x as u16 + 1),
         }
     }
 }
 
-// Chip-8 Disassembler
-fn fetch_instruction_str(byte1: u8, byte2: u8) -> String {
-    let high_nibble = byte1 >> 4;
-    let lo_nibble = byte1 & 0x0F;
-
-    let high_nibble2 = byte2 >> 4;
-    let lo_nibble2 = byte2 & 0x0F;
-
-    match high_nibble {
-        0x0 => match byte2 {
-            0xE0 => format!("CLS"),
-            0xEE => format!("RET"),
-            _ => format!("not supported ({:x?}{:x?})", byte1, byte2),
-        },
-        0x1 => format!("JP {:x?}{:x?}", lo_nibble, byte2),
-        0x2 => format!("CALL {:x?}{:x?}", lo_nibble, byte2),
-        0x3 => format!("SE V{:x?}, {:x?}", lo_nibble, byte2),
-        0x4 => format!("SNE V{:x?}, {:x?}", lo_nibble, byte2),
-        0x5 => format!("SE V{:x?}, V{:x?}", lo_nibble, high_nibble2),
-        0x6 => format!("LD V{:x?}, {:x?}", lo_nibble, byte2),
-        0x7 => format!("ADD V{:x?}, {:x?}", lo_nibble, byte2),
-        0x8 => match lo_nibble2 {
-            0x0 => format!("LD V{:x?}, V{:x?}", lo_nibble, high_nibble2),
-            0x1 => format!("OR V{:x?}, V{:x?}", lo_nibble, high_nibble2),
-            0x2 => format!("AND V{:x?}, V{:x?}", lo_nibble, high_nibble2),
-            0x3 => format!("XOR V{:x?}, V{:x?}", lo_nibble, high_nibble2),
-            0x4 => format!("ADD V{:x?}, V{:x?}", lo_nibble, high_nibble2),
-            0x5 => format!("SUB V{:x?}, V{:x?}", lo_nibble, high_nibble2),
-            0x6 => format!("SHR V{:x?} {{, V{:x?}}}", lo_nibble, high_nibble2),
-            0x7 => format!("SUBN V{:x?}, V{:x?}", lo_nibble, high_nibble2),
-            0xE => format!("SHL V{:x?} {{, V{:x?}}}", lo_nibble, high_nibble2),
-            _ => format!("not supported ({:x?}{:x?})", byte1, byte2),
-        },
-        0x9 => format!("SNE V{:x?}, {:x?}", lo_nibble, byte2),
-        0xA => format!("LD I, {:x?}{:x?}", lo_nibble, byte2),
-        0xB => format!("JP V0, {:x?}{:x?}", lo_nibble, byte2),
-        0xC => format!("RND V{:x?}, {:x?}", lo_nibble, byte2),
-        0xD => format!(
-            "DRW V{:x?}, V{:x?}, {:x?}",
-            lo_nibble, high_nibble2, lo_nibble2
-        ),
-        0xE => match byte2 {
-            0x9E => format!("SKP V{:x?}", lo_nibble),
-            0xA1 => format!("SKNP V{:x?}", lo_nibble),
-            _ => format!("not supported ({:x?}{:x?})", byte1, byte2),
-        },
-        0xF => match byte2 {
-            0x07 => format!("LD V{:x?}, DT", lo_nibble),
-            0x0A => format!("LD V{:x?}, K", lo_nibble),
-            0x15 => format!("LD DT, V{:x?}", lo_nibble),
-            0x18 => format!("LD ST, V{:x?}", lo_nibble),
-            0x1E => format!("ADD I, V{:x?}", lo_nibble),
-            0x29 => format!("LD F, V{:x?}", lo_nibble),
-            0x33 => format!("LD B, V{:x?}", lo_nibble),
-            0x55 => format!("LD [I], V{:x?}", lo_nibble),
-            0x65 => format!("LD V{:x?}, [I]", lo_nibble),
-            _ => format!("not supported ({:x?}{:x?})", byte1, byte2),
-        },
-        _ => format!("{:x?}", high_nibble),
+// Vx through Vy, counting down if y < x
+fn register_range(x: u8, y: u8) -> Box<dyn Iterator<Item = usize>> {
+    let (x, y) = (x as usize, y as usize);
+    if x <= y {
+        Box::new(x..=y)
+    } else {
+        Box::new((y..=x).rev())
+    }
+}
+
+// Sleep against a fixed deadline rather than a fixed duration so a slow
+// frame gets made up by the next one instead of drifting.
+fn wait_for_frame(next_frame: &mut Instant, frame: Duration) {
+    *next_frame += frame;
+    let now = Instant::now();
+    if *next_frame > now {
+        thread::sleep(*next_frame - now);
+    } else if now - *next_frame > frame * MAX_FRAMES_BEHIND {
+        // Way behind (suspended, debugger, etc.), don't try to catch up
+        // with a burst of frames
+        *next_frame = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Processor;
+    use crate::debugger::{Access, Watchpoint};
+    use crate::drivers::KeyState;
+    use crate::fault::{ExitReason, Fault};
+    use crate::instruction::Instruction;
+    use crate::testing::{processor, processor_with};
+    use crate::timing::{vip_cycles, Timing, VIP_SKIP_CYCLES};
+    use crate::Quirks;
+
+    #[test]
+    fn unknown_opcodes_fault_where_they_are() {
+        let mut cpu = processor("LD V0, 1\nDW 0x5001");
+        cpu.step().unwrap();
+        let fault = ExitReason::Fault {
+            pc: 0x202,
+            opcode: 0x5001,
+            fault: Fault::UnknownOpcode,
+        };
+        assert_eq!(cpu.step(), Err(fault));
+        assert_eq!(cpu.program_counter(), 0x202);
+        // And keeps saying so
+        assert_eq!(cpu.step(), Err(fault));
+    }
+
+    #[test]
+    fn subtraction_borrows() {
+        let mut cpu = processor(
+            "
+            LD V0, 1
+            LD V1, 2
+            SUB V0, V1
+            LD V2, 1
+            LD V3, 2
+            SUBN V3, V2
+            ",
+        );
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.registers()[0], 0xFF);
+        assert_eq!(cpu.registers()[0xF], 0);
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.registers()[3], 0xFF);
+        assert_eq!(cpu.registers()[0xF], 0);
+
+        let mut cpu = processor("LD V0, 2\nLD V1, 2\nSUB V0, V1\nSUBN V1, V0");
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.registers()[0], 0);
+        assert_eq!(cpu.registers()[0xF], 1);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers()[1], 0xFE);
+        assert_eq!(cpu.registers()[0xF], 0);
+    }
+
+    #[test]
+    fn i_past_the_end_of_ram() {
+        let mut cpu = processor("LD I, 0xFFF\nLD [I], V1");
+        cpu.step().unwrap();
+        assert_eq!(
+            cpu.step(),
+            Err(ExitReason::Fault {
+                pc: 0x202,
+                opcode: 0xF155,
+                fault: Fault::MemoryOutOfBounds { address: 0x1000 },
+            })
+        );
+
+        let mut cpu = processor("LD I, 0xFFE\nDRW V0, V0, 3");
+        cpu.step().unwrap();
+        assert!(matches!(
+            cpu.step(),
+            Err(ExitReason::Fault {
+                fault: Fault::MemoryOutOfBounds { .. },
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn i_wraps_around_xo_chip_ram() {
+        let mut cpu = processor_with(Quirks::XO_CHIP, "LD I, LONG 0xFFFF\nLD [I], V0");
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.reg_i(), 0);
+        assert_eq!(cpu.memory()[0xFFFF], 0);
+
+        // Everything that goes through RAM at I carries on from 0 as well
+        let at_the_end = |source: &str| {
+            let mut cpu = processor_with(Quirks::XO_CHIP, source);
+            cpu.set_register(0, 0xAB);
+            cpu.set_register(1, 0xCD);
+            for _ in source.lines() {
+                cpu.step().unwrap();
+            }
+            cpu
+        };
+        let cpu = at_the_end("LD I, LONG 0xFFFF\nLD [I], V1");
+        assert_eq!(cpu.memory()[0xFFFF], 0xAB);
+        assert_eq!(cpu.memory()[0], 0xCD);
+        assert_eq!(cpu.reg_i(), 1);
+
+        let cpu = at_the_end("LD I, LONG 0xFFFE\nLD B, V0");
+        assert_eq!(&cpu.memory()[0xFFFE..], &[1, 7]);
+        assert_eq!(cpu.memory()[0], 1);
+
+        let cpu = at_the_end("LD I, LONG 0xFFFF\nLD V1, [I]");
+        // The top of the font
+        assert_eq!(cpu.registers()[1], 0xF0);
+
+        let cpu = at_the_end("LD I, LONG 0xFFFE\nSAVE V0, V1");
+        assert_eq!(&cpu.memory()[0xFFFE..], &[0xAB, 0xCD]);
+        let cpu = at_the_end("LD I, LONG 0xFFFF\nLOAD V0, V1");
+        assert_eq!(&cpu.registers()[..2], &[0, 0xF0]);
+
+        // Two bytes from the end and six from the font, the whole of 0
+        // (F0 90 90 90 F0) and the top of 1 (20)
+        let mut cpu = processor_with(Quirks::XO_CHIP, "LD I, LONG 0xFFFE\nDRW V0, V0, 8");
+        cpu.write_memory(0xFFFE, &[0x80, 0x80]).unwrap();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        let lit: Vec<bool> = (0..8).map(|y| cpu.display_state().get(0, y) != 0).collect();
+        assert_eq!(lit, [true, true, true, true, true, true, true, false]);
+        assert!(cpu.display_state().get(3, 2) != 0);
+        assert!(cpu.display_state().get(1, 3) == 0);
+        assert!(cpu.display_state().get(2, 7) != 0);
+    }
+
+    #[test]
+    fn pc_wraps_around_xo_chip_ram() {
+        let at_the_end = |bytes: &[u8]| {
+            let mut cpu = processor_with(Quirks::XO_CHIP, "LD V0, 1");
+            cpu.write_memory(0xFFFE, bytes).unwrap();
+            cpu.set_program_counter(0xFFFE);
+            cpu.step().unwrap();
+            cpu
+        };
+
+        // LD V1, 5
+        assert_eq!(at_the_end(&[0x61, 0x05]).program_counter(), 0);
+        // SE V0, 0 skips the word at 0
+        assert_eq!(at_the_end(&[0x30, 0x00]).program_counter(), 2);
+        // CALL 0x300 comes back to 0
+        let mut cpu = at_the_end(&[0x23, 0x00]);
+        assert_eq!(cpu.pop_stack(), Ok(0));
+        // F000 takes its address from the word at 0, the top of the font
+        let cpu = at_the_end(&[0xF0, 0x00]);
+        assert_eq!(cpu.reg_i(), 0xF090);
+        assert_eq!(cpu.program_counter(), 2);
+
+        // Charging for the skip looks at where it went too
+        let mut cpu = processor_with(Quirks::XO_CHIP, "LD V0, 1");
+        cpu.set_timing(Timing::CosmacVip);
+        cpu.write_memory(0xFFFE, &[0x30, 0x00]).unwrap();
+        cpu.set_program_counter(0xFFFE);
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter(), 2);
+        assert_eq!(
+            cpu.cycles(),
+            (vip_cycles(Instruction::SkipEqual { x: 0, value: 0 }, &[0; 16]) + VIP_SKIP_CYCLES)
+                as u64
+        );
+    }
+
+    #[test]
+    fn instructions_are_counted_however_they_run() {
+        // 1 + 10 ADDs and SEs + 9 JPs + EXIT
+        const SOURCE: &str = "LD V0, 0\nloop: ADD V0, 1\nSE V0, 10\nJP loop\nEXIT";
+
+        let mut cpu = processor(SOURCE);
+        while cpu.step().is_ok() {}
+        assert_eq!(cpu.instructions(), 31);
+
+        let mut cpu = processor(SOURCE);
+        cpu.set_block_cache(true);
+        assert_eq!(cpu.run(), ExitReason::ProgramExit);
+        assert_eq!(cpu.instructions(), 31);
+
+        // However many the recompiled code says it ran
+        let mut cpu = processor(SOURCE);
+        cpu.set_recompiled(Some(recompiled));
+        cpu.run();
+        assert_eq!(cpu.instructions(), 1);
+
+        cpu.reset();
+        assert_eq!(cpu.instructions(), 0);
+    }
+
+    // Stands in for recompiled code, doing something the ROM doesn't so it
+    // shows whether it ran
+    fn recompiled(cpu: &mut Processor, _limit: u32) -> Result<u32, Fault> {
+        cpu.gpr_v[0] = 0x42;
+        cpu.exit = Some(ExitReason::ProgramExit);
+        Ok(1)
+    }
+
+    #[test]
+    fn run_goes_a_frame_at_a_time() {
+        let mut cpu = processor("LD V0, 1\nEXIT");
+        cpu.set_recompiled(Some(recompiled));
+        assert_eq!(cpu.run(), ExitReason::ProgramExit);
+        assert_eq!(cpu.registers()[0], 0x42);
+
+        // Breaking has to be checked before every instruction
+        let mut cpu = processor("LD V0, 1\nEXIT");
+        cpu.set_recompiled(Some(recompiled));
+        assert_eq!(cpu.run_until(|_| false), Some(ExitReason::ProgramExit));
+        assert_eq!(cpu.registers()[0], 1);
+    }
+
+    // Sets both timers going, then waits for a key into V1
+    const WAIT_FOR_KEY: &str = "
+        LD V0, 30
+        LD DT, V0
+        LD ST, V0
+        LD V1, K
+        LD V2, 1
+    end:
+        JP end
+    ";
+
+    fn poll(held: &[u8], pressed: &[u8], released: &[u8]) -> KeyState {
+        let mut keys = KeyState::default();
+        for &key in held {
+            keys.held[key as usize] = true;
+        }
+        for &key in pressed {
+            keys.pressed[key as usize] = true;
+        }
+        for &key in released {
+            keys.released[key as usize] = true;
+        }
+        keys
+    }
+
+    fn waiting() -> Processor {
+        let mut cpu = processor(WAIT_FOR_KEY);
+        cpu.step_frame().unwrap();
+        assert!(cpu.waiting_for_key());
+        assert_eq!(cpu.program_counter(), 0x206);
+        cpu
+    }
+
+    #[test]
+    fn waiting_for_a_key_takes_a_press_and_a_release() {
+        let mut cpu = waiting();
+        cpu.update_keys(&poll(&[5], &[5], &[]));
+        cpu.step_frame().unwrap();
+        assert!(cpu.waiting_for_key());
+        assert_eq!(cpu.registers()[1], 0);
+
+        // Only letting go of it finishes the wait, and timers went on
+        // ticking all the while
+        cpu.update_keys(&poll(&[], &[], &[5]));
+        assert!(!cpu.waiting_for_key());
+        assert_eq!(cpu.registers()[1], 5);
+        assert_eq!(cpu.delay_timer(), 28);
+        assert_eq!(cpu.sound_timer(), 28);
+        cpu.step_frame().unwrap();
+        assert_eq!(cpu.registers()[2], 1);
+    }
+
+    #[test]
+    fn keys_held_before_the_wait_dont_count() {
+        let mut cpu = processor(WAIT_FOR_KEY);
+        cpu.update_keys(&poll(&[7], &[7], &[]));
+        cpu.step_frame().unwrap();
+        assert!(cpu.waiting_for_key());
+        cpu.update_keys(&poll(&[], &[], &[7]));
+        assert!(cpu.waiting_for_key());
+
+        // The next one does
+        cpu.update_keys(&poll(&[2], &[2], &[]));
+        cpu.update_keys(&poll(&[], &[], &[2]));
+        assert!(!cpu.waiting_for_key());
+        assert_eq!(cpu.registers()[1], 2);
+    }
+
+    #[test]
+    fn taps_between_polls_finish_the_wait() {
+        let mut cpu = waiting();
+        cpu.update_keys(&poll(&[], &[3], &[3]));
+        assert!(!cpu.waiting_for_key());
+        assert_eq!(cpu.registers()[1], 3);
+        assert_eq!(cpu.program_counter(), 0x208);
+    }
+
+    // One step per line of `source`
+    fn run(quirks: Quirks, source: &str) -> Processor {
+        let mut cpu = processor_with(quirks, source);
+        for _ in source.lines() {
+            cpu.step().unwrap();
+        }
+        cpu
+    }
+
+    #[test]
+    fn resolution() {
+        let cpu = run(Quirks::SUPER_CHIP, "HIGH");
+        assert!(cpu.display_state().hires());
+        let cpu = run(Quirks::SUPER_CHIP, "HIGH\nLOW");
+        assert!(!cpu.display_state().hires());
+    }
+
+    // A 16x16 square of the font's first bytes, at the bottom of the high
+    // resolution screen so half of it is clipped
+    #[test]
+    fn sixteen_by_sixteen_collisions_count_rows() {
+        let source = "HIGH\nLD V1, 56\nLD I, 0\nDRW V0, V1, 0";
+        let cpu = run(Quirks::SUPER_CHIP, source);
+        assert_eq!(cpu.registers()[0xF], 8);
+        let again = format!("{}\nDRW V0, V1, 0", source);
+        assert_eq!(run(Quirks::SUPER_CHIP, &again).registers()[0xF], 16);
+
+        // Just a flag in low resolution and for XO-CHIP
+        let lores = again.replace("HIGH", "LOW");
+        assert_eq!(run(Quirks::SUPER_CHIP, &lores).registers()[0xF], 1);
+        assert_eq!(run(Quirks::XO_CHIP, &again).registers()[0xF], 1);
+    }
+
+    #[test]
+    fn flags_round_trip() {
+        let round_trip = |quirks| {
+            let mut cpu = processor_with(quirks, "LD R, VF\nLD VF, R");
+            for register in 0..16 {
+                cpu.set_register(register, register as u8 + 1);
+            }
+            cpu.step().unwrap();
+            for register in 0..16 {
+                cpu.set_register(register, 0);
+            }
+            cpu.step().unwrap();
+            *cpu.registers()
+        };
+        // The HP48 only had 8
+        let schip = round_trip(Quirks::SUPER_CHIP);
+        assert_eq!(&schip[..9], &[1, 2, 3, 4, 5, 6, 7, 8, 0]);
+        let xo_chip = round_trip(Quirks::XO_CHIP);
+        assert_eq!(xo_chip[15], 16);
+    }
+
+    #[test]
+    fn drawing_to_the_second_plane_only() {
+        let cpu = run(Quirks::XO_CHIP, "PLANE 2\nLD I, 0\nDRW V0, V0, 1");
+        // The top of the font's 0, F0
+        for x in 0..4 {
+            assert_eq!(cpu.display_state().get(x, 0), 0b10);
+        }
+        assert_eq!(cpu.display_state().get(4, 0), 0);
+    }
+
+    // Each plane gets its own rows, one after the other
+    #[test]
+    fn two_plane_sprites_read_twice_as_much() {
+        let mut cpu = processor_with(Quirks::XO_CHIP, "PLANE 3\nLD I, 0x300\nDRW V0, V0, 2");
+        cpu.write_memory(0x300, &[0x80, 0x40, 0x20, 0x10]).unwrap();
+        cpu.add_watchpoint(Watchpoint {
+            start: 0x303,
+            end: 0x303,
+            access: Access::Read,
+        });
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        let pixel = |x, y| cpu.display_state().get(x, y);
+        assert_eq!((pixel(0, 0), pixel(1, 1)), (0b01, 0b01));
+        assert_eq!((pixel(2, 0), pixel(3, 1)), (0b10, 0b10));
+        assert!(cpu.watch_hit().is_some());
+    }
+
+    #[test]
+    fn register_ranges_either_way_round() {
+        let mut cpu = processor_with(Quirks::XO_CHIP, "LD I, 0x300\nSAVE V3, V1\nLOAD V1, V3");
+        cpu.set_register(1, 0x11);
+        cpu.set_register(2, 0x22);
+        cpu.set_register(3, 0x33);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        // V3 first, and I stays put
+        assert_eq!(&cpu.memory()[0x300..0x303], &[0x33, 0x22, 0x11]);
+        assert_eq!(cpu.reg_i(), 0x300);
+        cpu.step().unwrap();
+        assert_eq!(&cpu.registers()[1..4], &[0x33, 0x22, 0x11]);
+    }
+
+    #[test]
+    fn skipping_f000_skips_its_address_too() {
+        let mut cpu = processor_with(Quirks::XO_CHIP, "SE V0, 0\nLD I, LONG 0x1234");
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter(), 0x206);
+        // Without XO-CHIP there's no such thing, so it's just a word
+        let mut cpu = processor_with(Quirks::SUPER_CHIP, "SE V0, 0\nDW 0xF000");
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter(), 0x204);
     }
 }
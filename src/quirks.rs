@@ -0,0 +1,186 @@
+// The various CHIP-8 interpreters out there disagree on a handful of
+// instructions, and games were written against whichever one the author had.
+// See https://chip8.gulrak.net/ for the gory details.
+
+/// What FX55/FX65 do to I once they're done.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexIncrement {
+    /// I is left alone (SUPER-CHIP)
+    Unchanged,
+    /// I = I + X (CHIP-48)
+    ByX,
+    /// I = I + X + 1 (COSMAC VIP, XO-CHIP)
+    ByXPlusOne,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    /// 8XY6/8XYE shift VY and store it in VX, rather than shifting VX in place
+    pub shift_uses_vy: bool,
+    /// What FX55/FX65 do to I
+    pub index_increment: IndexIncrement,
+    /// BNNN jumps to XNN + VX instead of NNN + V0
+    pub jump_uses_vx: bool,
+    /// 8XY1/8XY2/8XY3 reset VF to 0
+    pub logic_resets_vf: bool,
+    /// DXYN wraps sprites around the screen edges instead of clipping them
+    pub wrap_sprites: bool,
+    /// DXYN waits for the next frame before drawing, so at most one sprite
+    /// gets drawn a frame
+    pub display_wait: bool,
+    /// How many CALLs deep a program can go before it's a stack overflow
+    pub stack_depth: usize,
+    /// Keep the stack in RAM where the VIP kept it, for programs that
+    /// read or write it directly
+    pub stack_in_ram: bool,
+    /// Not really a quirk, turns on the XO-CHIP extensions: 64 KB of RAM,
+    /// F000 NNNN, 5XY2/5XY3, bitplanes and audio patterns
+    pub xo_chip: bool,
+}
+
+impl Quirks {
+    pub const COSMAC_VIP: Quirks = Quirks {
+        shift_uses_vy: true,
+        index_increment: IndexIncrement::ByXPlusOne,
+        jump_uses_vx: false,
+        logic_resets_vf: true,
+        wrap_sprites: false,
+        display_wait: true,
+        stack_depth: 12,
+        stack_in_ram: false,
+        xo_chip: false,
+    };
+
+    pub const CHIP_48: Quirks = Quirks {
+        shift_uses_vy: false,
+        index_increment: IndexIncrement::ByX,
+        jump_uses_vx: true,
+        logic_resets_vf: false,
+        wrap_sprites: false,
+        display_wait: false,
+        stack_depth: 16,
+        stack_in_ram: false,
+        xo_chip: false,
+    };
+
+    pub const SUPER_CHIP: Quirks = Quirks {
+        shift_uses_vy: false,
+        index_increment: IndexIncrement::Unchanged,
+        jump_uses_vx: true,
+        logic_resets_vf: false,
+        wrap_sprites: false,
+        display_wait: false,
+        stack_depth: 16,
+        stack_in_ram: false,
+        xo_chip: false,
+    };
+
+    pub const XO_CHIP: Quirks = Quirks {
+        shift_uses_vy: true,
+        index_increment: IndexIncrement::ByXPlusOne,
+        jump_uses_vx: false,
+        logic_resets_vf: false,
+        wrap_sprites: true,
+        display_wait: false,
+        stack_depth: 16,
+        stack_in_ram: false,
+        xo_chip: true,
+    };
+
+    /// Names accepted by [`Quirks::preset`].
+    pub const PRESET_NAMES: [&'static str; 4] = ["vip", "chip48", "schip", "xochip"];
+
+    /// Look up a preset by its short name.
+    pub fn preset(name: &str) -> Option<Quirks> {
+        match name.to_ascii_lowercase().as_str() {
+            "vip" | "chip8" | "chip-8" => Some(Quirks::COSMAC_VIP),
+            "chip48" | "chip-48" => Some(Quirks::CHIP_48),
+            "schip" | "superchip" | "super-chip" => Some(Quirks::SUPER_CHIP),
+            "xochip" | "xo-chip" => Some(Quirks::XO_CHIP),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Quirks {
+    // Plain CHIP-8 means the original interpreter
+    fn default() -> Quirks {
+        Quirks::COSMAC_VIP
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Quirks;
+    use crate::testing::processor_with;
+    use crate::Processor;
+
+    // One step per line of `source`
+    fn run(quirks: Quirks, source: &str) -> Processor {
+        let mut cpu = processor_with(quirks, source);
+        for _ in source.lines() {
+            cpu.step().unwrap();
+        }
+        cpu
+    }
+
+    #[test]
+    fn shift_uses_vy() {
+        let source = "LD V1, 0x0C\nLD V2, 0x03\nSHR V1, V2";
+        let vip = run(Quirks::COSMAC_VIP, source);
+        assert_eq!((vip.registers()[1], vip.registers()[0xF]), (0x01, 1));
+        let chip48 = run(Quirks::CHIP_48, source);
+        assert_eq!((chip48.registers()[1], chip48.registers()[0xF]), (0x06, 0));
+    }
+
+    #[test]
+    fn index_increment() {
+        let source = "LD I, 0x300\nLD [I], V2";
+        assert_eq!(run(Quirks::COSMAC_VIP, source).reg_i(), 0x303);
+        assert_eq!(run(Quirks::CHIP_48, source).reg_i(), 0x302);
+        assert_eq!(run(Quirks::SUPER_CHIP, source).reg_i(), 0x300);
+    }
+
+    #[test]
+    fn jump_uses_vx() {
+        // B310, so X is 3
+        let source = "LD V0, 4\nLD V3, 8\nJP V0, 0x310";
+        assert_eq!(run(Quirks::COSMAC_VIP, source).program_counter(), 0x314);
+        assert_eq!(run(Quirks::SUPER_CHIP, source).program_counter(), 0x318);
+    }
+
+    #[test]
+    fn logic_resets_vf() {
+        let source = "LD VF, 5\nLD V1, 3\nOR V1, V1";
+        assert_eq!(run(Quirks::COSMAC_VIP, source).registers()[0xF], 0);
+        assert_eq!(run(Quirks::SUPER_CHIP, source).registers()[0xF], 5);
+    }
+
+    #[test]
+    fn wrap_sprites() {
+        // The top of the font's 0, four pixels from x = 62 so two of them
+        // are off the right edge
+        let source = "LD V0, 62\nLD I, 0\nDRW V0, V1, 1";
+        let clipped = run(Quirks::SUPER_CHIP, source);
+        assert_eq!(clipped.display_state().get(63, 0), 1);
+        assert_eq!(clipped.display_state().get(0, 0), 0);
+        let wrapped = run(Quirks::XO_CHIP, source);
+        assert_eq!(wrapped.display_state().get(63, 0), 1);
+        assert_eq!(wrapped.display_state().get(0, 0), 1);
+    }
+
+    #[test]
+    fn display_wait() {
+        // Counts the sprites drawn in V2, after the first
+        let source = "loop: DRW V0, V1, 1\nADD V2, 1\nJP loop";
+        let frames = |quirks| {
+            let mut cpu = processor_with(quirks, source);
+            for _ in 0..3 {
+                cpu.step_frame().unwrap();
+            }
+            cpu.registers()[2]
+        };
+        assert_eq!(frames(Quirks::COSMAC_VIP), 2);
+        assert_eq!(frames(Quirks::SUPER_CHIP), 10);
+    }
+}
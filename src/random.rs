@@ -0,0 +1,45 @@
+extern crate rand;
+
+/// Where CXNN gets its random numbers from.
+///
+/// The machine owns this rather than asking the thread RNG, so the same seed
+/// and the same key presses always play out the same way, and it goes into
+/// save states along with everything else.
+///
+/// SplitMix64: good quality, and any seed is fine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Random(u64);
+
+impl Random {
+    pub fn seeded(seed: u64) -> Random {
+        Random(seed)
+    }
+
+    /// Seeded from the OS, for when nobody asked for a particular seed.
+    pub fn from_entropy() -> Random {
+        Random(rand::random())
+    }
+
+    pub fn next_byte(&mut self) -> u8 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        (z ^ (z >> 31)) as u8
+    }
+
+    // Just the state, for save states and movies
+    pub(crate) fn to_bytes(self) -> [u8; 8] {
+        self.0.to_le_bytes()
+    }
+
+    pub(crate) fn from_bytes(bytes: [u8; 8]) -> Random {
+        Random(u64::from_le_bytes(bytes))
+    }
+}
+
+impl Default for Random {
+    fn default() -> Random {
+        Random::from_entropy()
+    }
+}
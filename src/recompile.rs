@@ -0,0 +1,493 @@
+//! Translating ROMs into Rust ahead of time.
+//!
+//! [`recompile`] finds the code the same way [`Disassembly`] does and writes
+//! a Rust module with a function for each basic block. Register arithmetic,
+//! skips and jumps become plain Rust; everything else goes through
+//! [`Processor::execute_instruction`] so it behaves exactly like the
+//! interpreter, quirks and all. The module's `run_block` is handed to
+//! [`Processor::set_recompiled`]:
+//!
+//! ```ignore
+//! mod rom; // chippe_rs recompile -o src/rom.rs ROM.ch8
+//!
+//! cpu.set_recompiled(Some(rom::run_block));
+//! cpu.step_frame()?;
+//! ```
+//!
+//! Each block checks its bytes in RAM are still the ones it was made from,
+//! so self-modifying code, computed BNNN jumps and anything else the
+//! recompiler didn't see coming are left to the interpreter.
+
+use std::collections::BTreeSet;
+use std::io::{self, Write};
+
+use crate::disasm::{disassemble_instruction, Disassembly};
+use crate::instruction::Instruction;
+use crate::{Fault, Processor};
+
+/// A recompiled ROM's entry point: run up to `limit` instructions from the
+/// PC and say how many ran, or 0 to leave it to the interpreter.
+pub type RunBlock = fn(&mut Processor, u32) -> Result<u32, Fault>;
+
+struct Program<'a> {
+    bytes: &'a [u8],
+    load_address: u16,
+    code: BTreeSet<u16>,
+}
+
+impl Program<'_> {
+    fn word(&self, address: u16) -> Option<u16> {
+        let offset = address.checked_sub(self.load_address)? as usize;
+        let bytes = self.bytes.get(offset..offset + 2)?;
+        Some((bytes[0] as u16) << 8 | bytes[1] as u16)
+    }
+
+    // Only ever asked about addresses in `code`, which all decode
+    fn instruction(&self, address: u16) -> Instruction {
+        self.word(address)
+            .and_then(Instruction::decode)
+            .expect("traced code decodes")
+    }
+
+    fn bytes(&self, start: u16, end: u16) -> &[u8] {
+        let offset = (start - self.load_address) as usize;
+        &self.bytes[offset..(end - self.load_address) as usize]
+    }
+
+    // The skip's target, if it can be worked out now. Skipping an F000
+    // depends on whether XO-CHIP is on, so that's left to the interpreter.
+    fn skip_target(&self, address: u16) -> Option<u16> {
+        let next = address.wrapping_add(2);
+        match self.word(next) {
+            Some(0xF000) => None,
+            _ => Some(next.wrapping_add(2)),
+        }
+    }
+
+    // Where the generated code might hand control back and need to pick up
+    // again
+    fn entry_points(&self) -> BTreeSet<u16> {
+        use crate::instruction::Instruction::*;
+
+        let mut entries = BTreeSet::new();
+        entries.insert(self.load_address);
+        for &address in &self.code {
+            let instruction = self.instruction(address);
+            let next = address.wrapping_add(instruction.size());
+            match instruction {
+                Jump(target) | JumpV0(target) => {
+                    entries.insert(target);
+                }
+                Call(target) => {
+                    entries.insert(target);
+                    entries.insert(next);
+                }
+                _ if instruction.is_skip() => match self.skip_target(address) {
+                    Some(target) => {
+                        entries.insert(target);
+                    }
+                    // Over the F000 and its address, or over just the F000
+                    None => {
+                        entries.insert(next.wrapping_add(4));
+                        entries.insert(next.wrapping_add(2));
+                    }
+                },
+                _ if ends_block(instruction) => {
+                    entries.insert(next);
+                }
+                _ => (),
+            }
+        }
+        entries.retain(|address| self.code.contains(address));
+        entries
+    }
+
+    // From `start` to the next entry point, or whatever ends the block
+    fn block(&self, start: u16, entries: &BTreeSet<u16>) -> Vec<u16> {
+        let mut addresses = vec![start];
+        let mut address = start;
+        loop {
+            let instruction = self.instruction(address);
+            if ends_block(instruction) {
+                break;
+            }
+            address = address.wrapping_add(instruction.size());
+            if !self.code.contains(&address) || entries.contains(&address) {
+                break;
+            }
+            addresses.push(address);
+        }
+        addresses
+    }
+}
+
+// Anything that can leave the PC somewhere other than the next
+// instruction, or write to RAM and maybe change the code after it
+fn ends_block(instruction: Instruction) -> bool {
+    use crate::instruction::Instruction::*;
+
+    matches!(
+        instruction,
+        Jump(_)
+            | JumpV0(_)
+            | Call(_)
+            | Ret
+            | Exit
+            | Draw { .. }
+            | WaitKey(_)
+            | Bcd(_)
+            | StoreRegisters(_)
+            | Save { .. }
+    )
+}
+
+/// Write `rom`, loaded at `load_address`, out as a Rust module with a
+/// `run_block` to pass to [`Processor::set_recompiled`].
+pub fn recompile<W: Write>(rom: &[u8], load_address: u16, mut writer: W) -> io::Result<()> {
+    let program = Program {
+        bytes: rom,
+        load_address,
+        code: Disassembly::new(rom, load_address).code().collect(),
+    };
+    let entries = program.entry_points();
+
+    writeln!(
+        writer,
+        "//! Recompiled by chippe_rs from a ROM loaded at {:#06x}. Don't edit, \
+         recompile it instead.",
+        load_address
+    )?;
+    writeln!(writer)?;
+    writeln!(
+        writer,
+        "#![allow(unused_imports, unused_mut, unused_variables, clippy::all)]"
+    )?;
+    writeln!(writer)?;
+    writeln!(writer, "use chippe_rs::{{Fault, Instruction, Processor}};")?;
+    writeln!(writer)?;
+    writeln!(
+        writer,
+        "/// Up to `limit` instructions from the PC, or 0 if it isn't the \
+         start of a block."
+    )?;
+    writeln!(
+        writer,
+        "pub fn run_block(cpu: &mut Processor, limit: u32) -> Result<u32, Fault> {{"
+    )?;
+    writeln!(writer, "    match cpu.program_counter() {{")?;
+    for &start in &entries {
+        writeln!(
+            writer,
+            "        {:#06x} => block_{:04x}(cpu, limit),",
+            start, start
+        )?;
+    }
+    writeln!(writer, "        _ => Ok(0),")?;
+    writeln!(writer, "    }}")?;
+    writeln!(writer, "}}")?;
+
+    for &start in &entries {
+        writeln!(writer)?;
+        write_block(&program, &program.block(start, &entries), &mut writer)?;
+    }
+    Ok(())
+}
+
+fn write_block<W: Write>(program: &Program, addresses: &[u16], writer: &mut W) -> io::Result<()> {
+    let start = addresses[0];
+    let last = *addresses.last().unwrap();
+    let end = last.wrapping_add(program.instruction(last).size());
+
+    writeln!(
+        writer,
+        "fn block_{:04x}(cpu: &mut Processor, limit: u32) -> Result<u32, Fault> {{",
+        start
+    )?;
+    let bytes: Vec<String> = program
+        .bytes(start, end)
+        .iter()
+        .map(|byte| format!("{:#04x}", byte))
+        .collect();
+    writeln!(
+        writer,
+        "    if cpu.memory().get({:#06x}..{:#06x}) != Some(&[{}][..]) {{",
+        start,
+        end,
+        bytes.join(", ")
+    )?;
+    writeln!(writer, "        return Ok(0);")?;
+    writeln!(writer, "    }}")?;
+    writeln!(writer, "    let mut n = 0;")?;
+
+    for (index, &address) in addresses.iter().enumerate() {
+        let instruction = program.instruction(address);
+        let long = match instruction {
+            Instruction::LoadILong => program.word(address.wrapping_add(2)),
+            _ => None,
+        };
+        writeln!(writer)?;
+        writeln!(
+            writer,
+            "    // {:04x}: {}",
+            address,
+            disassemble_instruction(instruction.encode(), long)
+        )?;
+        if index > 0 {
+            writeln!(writer, "    if n == limit {{")?;
+            writeln!(writer, "        cpu.set_program_counter({:#06x});", address)?;
+            writeln!(writer, "        return Ok(n);")?;
+            writeln!(writer, "    }}")?;
+        }
+        write_instruction(program, address, instruction, writer)?;
+    }
+
+    // Fell off the end into the next block
+    let next = last.wrapping_add(program.instruction(last).size());
+    if !ends_block(program.instruction(last)) {
+        writeln!(writer, "    cpu.set_program_counter({:#06x});", next)?;
+    }
+    writeln!(writer, "    Ok(n)")?;
+    writeln!(writer, "}}")
+}
+
+fn write_instruction<W: Write>(
+    program: &Program,
+    address: u16,
+    instruction: Instruction,
+    writer: &mut W,
+) -> io::Result<()> {
+    use crate::instruction::Instruction::*;
+
+    let next = address.wrapping_add(instruction.size());
+    // Straight into the registers
+    let registers = |writer: &mut W, lines: &[String]| -> io::Result<()> {
+        writeln!(writer, "    let v = cpu.registers_mut();")?;
+        for line in lines {
+            writeln!(writer, "    {}", line)?;
+        }
+        writeln!(writer, "    n += 1;")
+    };
+    // Skip when `condition` holds, which can't be worked out when there's an
+    // F000 to skip
+    let skip = |writer: &mut W, condition: String| -> io::Result<bool> {
+        let target = match program.skip_target(address) {
+            Some(target) => target,
+            None => return Ok(false),
+        };
+        writeln!(writer, "    let v = cpu.registers();")?;
+        writeln!(writer, "    let skip = {};", condition)?;
+        writeln!(writer, "    n += 1;")?;
+        writeln!(writer, "    if skip {{")?;
+        writeln!(writer, "        cpu.set_program_counter({:#06x});", target)?;
+        writeln!(writer, "        return Ok(n);")?;
+        writeln!(writer, "    }}")?;
+        Ok(true)
+    };
+
+    let translated = match instruction {
+        LoadByte { x, value } => {
+            registers(writer, &[format!("v[{:#x}] = {:#04x};", x, value)])?;
+            true
+        }
+        AddByte { x, value } => {
+            registers(
+                writer,
+                &[format!(
+                    "v[{:#x}] = v[{:#x}].wrapping_add({:#04x});",
+                    x, x, value
+                )],
+            )?;
+            true
+        }
+        Move { x, y } => {
+            registers(writer, &[format!("v[{:#x}] = v[{:#x}];", x, y)])?;
+            true
+        }
+        Or { x, y } | And { x, y } | Xor { x, y } => {
+            let operator = match instruction {
+                Or { .. } => "|",
+                And { .. } => "&",
+                _ => "^",
+            };
+            writeln!(writer, "    let reset = cpu.quirks().logic_resets_vf;")?;
+            registers(
+                writer,
+                &[
+                    format!("v[{:#x}] {}= v[{:#x}];", x, operator, y),
+                    "if reset {".to_string(),
+                    "    v[0xf] = 0;".to_string(),
+                    "}".to_string(),
+                ],
+            )?;
+            true
+        }
+        Add { x, y } => {
+            registers(
+                writer,
+                &[
+                    format!(
+                        "let (sum, carry) = v[{:#x}].overflowing_add(v[{:#x}]);",
+                        x, y
+                    ),
+                    format!("v[{:#x}] = sum;", x),
+                    "v[0xf] = carry as u8;".to_string(),
+                ],
+            )?;
+            true
+        }
+        Sub { x, y } | SubN { x, y } => {
+            let (from, take) = match instruction {
+                Sub { .. } => (x, y),
+                _ => (y, x),
+            };
+            registers(
+                writer,
+                &[
+                    format!("let (a, b) = (v[{:#x}], v[{:#x}]);", from, take),
+                    format!("v[{:#x}] = a.wrapping_sub(b);", x),
+                    "v[0xf] = (a >= b) as u8;".to_string(),
+                ],
+            )?;
+            true
+        }
+        ShiftRight { x, y } | ShiftLeft { x, y } => {
+            let (shifted, flag) = match instruction {
+                ShiftRight { .. } => ("value >> 1", "value & 1"),
+                _ => ("value << 1", "value >> 7"),
+            };
+            writeln!(writer, "    let uses_vy = cpu.quirks().shift_uses_vy;")?;
+            registers(
+                writer,
+                &[
+                    format!(
+                        "let value = if uses_vy {{ v[{:#x}] }} else {{ v[{:#x}] }};",
+                        y, x
+                    ),
+                    format!("v[{:#x}] = {};", x, shifted),
+                    format!("v[0xf] = {};", flag),
+                ],
+            )?;
+            true
+        }
+        LoadI(address) => {
+            writeln!(writer, "    cpu.set_reg_i({:#06x});", address)?;
+            writeln!(writer, "    n += 1;")?;
+            true
+        }
+        AddI(x) => {
+            writeln!(
+                writer,
+                "    cpu.set_reg_i(cpu.reg_i().wrapping_add(cpu.registers()[{:#x}] as u16));",
+                x
+            )?;
+            writeln!(writer, "    n += 1;")?;
+            true
+        }
+        GetDelay(x) => {
+            writeln!(writer, "    let delay = cpu.delay_timer();")?;
+            registers(writer, &[format!("v[{:#x}] = delay;", x)])?;
+            true
+        }
+        SetDelay(x) => {
+            writeln!(
+                writer,
+                "    cpu.set_delay_timer(cpu.registers()[{:#x}]);",
+                x
+            )?;
+            writeln!(writer, "    n += 1;")?;
+            true
+        }
+        SetSound(x) => {
+            writeln!(
+                writer,
+                "    cpu.set_sound_timer(cpu.registers()[{:#x}]);",
+                x
+            )?;
+            writeln!(writer, "    n += 1;")?;
+            true
+        }
+        SkipEqual { x, value } => skip(writer, format!("v[{:#x}] == {:#04x}", x, value))?,
+        SkipNotEqual { x, value } => skip(writer, format!("v[{:#x}] != {:#04x}", x, value))?,
+        SkipEqualRegister { x, y } => skip(writer, format!("v[{:#x}] == v[{:#x}]", x, y))?,
+        SkipNotEqualRegister { x, y } => skip(writer, format!("v[{:#x}] != v[{:#x}]", x, y))?,
+        Jump(target) => {
+            writeln!(writer, "    cpu.set_program_counter({:#06x});", target)?;
+            writeln!(writer, "    n += 1;")?;
+            true
+        }
+        _ => false,
+    };
+    if translated {
+        return Ok(());
+    }
+
+    // The interpreter does the rest
+    writeln!(writer, "    cpu.set_program_counter({:#06x});", address)?;
+    writeln!(
+        writer,
+        "    let carry_on = cpu.execute_instruction(Instruction::{:?})?;",
+        instruction
+    )?;
+    writeln!(writer, "    n += 1;")?;
+    if ends_block(instruction) {
+        return Ok(());
+    }
+    writeln!(
+        writer,
+        "    if !carry_on || cpu.program_counter() != {:#06x} {{",
+        next
+    )?;
+    writeln!(writer, "        return Ok(n);")?;
+    writeln!(writer, "    }}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::recompile;
+    use crate::asm::assemble;
+
+    fn recompiled(source: &str) -> String {
+        let assembly = assemble(source).unwrap();
+        let mut out = Vec::new();
+        recompile(assembly.bytes(), assembly.load_address(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn blocks_start_wherever_control_can_come_back() {
+        let module = recompiled(
+            "
+            main:
+                LD V0, 1
+                CALL sub
+                SE V0, 2
+                JP main
+                EXIT
+            sub:
+                ADD V0, 1
+                RET
+            ",
+        );
+        // The start, after the CALL, the SE's target and the subroutine
+        for start in &["0x0200", "0x0204", "0x0208", "0x020a"] {
+            assert!(
+                module.contains(&format!("{} => block_", start)),
+                "no block at {}",
+                start
+            );
+        }
+        // Straight-line code doesn't get split up
+        for middle in &["0x0202", "0x0206", "0x020c"] {
+            assert!(!module.contains(&format!("{} => ", middle)));
+        }
+    }
+
+    #[test]
+    fn computed_jumps_are_left_to_the_interpreter() {
+        let module = recompiled("LD V0, 2\nJP V0, table\ntable:\nJP table\nJP table");
+        assert!(module.contains("execute_instruction(Instruction::JumpV0(516))"));
+        // Only the V0 = 0 target is known
+        assert!(module.contains("0x0204 => "));
+        assert!(!module.contains("0x0206 => "));
+    }
+}
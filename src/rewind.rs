@@ -0,0 +1,251 @@
+use std::collections::VecDeque;
+
+use crate::savestate::SaveState;
+
+pub const DEFAULT_REWIND_SECONDS: u32 = 10;
+// Every other frame is plenty smooth and halves the memory
+pub const DEFAULT_REWIND_INTERVAL: u32 = 2;
+
+/// The last few seconds of snapshots, for stepping gameplay backwards.
+///
+/// Only the newest snapshot is kept whole. Every older one is stored as the
+/// difference from the snapshot after it, and since most of RAM and the
+/// screen don't change from one frame to the next those differences are
+/// mostly zeros, which get run-length encoded away.
+pub struct RewindBuffer {
+    capacity: usize,
+    interval: u32,
+    frames_since_snapshot: u32,
+    // Frames spent rewinding since the last pop_due that said yes
+    frames_since_pop: u32,
+    newest: Option<Vec<u8>>,
+    // Oldest at the front, each one rebuilds the snapshot before the one
+    // after it (or before `newest`, for the back)
+    older: VecDeque<Delta>,
+}
+
+enum Delta {
+    // Compressed XOR against the next newer snapshot
+    Xor(Vec<u8>),
+    // Different size to the next snapshot (the stack depth changed), so
+    // just the compressed snapshot itself
+    Full(Vec<u8>),
+}
+
+impl RewindBuffer {
+    /// Keep `seconds` worth of snapshots, taking one every `interval`
+    /// frames.
+    pub fn new(seconds: u32, interval: u32) -> RewindBuffer {
+        let interval = interval.max(1);
+        RewindBuffer {
+            capacity: (seconds as usize * crate::FRAMES_PER_SECOND as usize) / interval as usize,
+            interval,
+            frames_since_snapshot: 0,
+            frames_since_pop: 0,
+            newest: None,
+            older: VecDeque::new(),
+        }
+    }
+
+    /// Call once a frame, only every `interval`th state actually gets kept.
+    pub fn record(&mut self, state: &SaveState) {
+        self.frames_since_snapshot += 1;
+        if self.frames_since_snapshot < self.interval && self.newest.is_some() {
+            return;
+        }
+        self.frames_since_snapshot = 0;
+        self.frames_since_pop = 0;
+
+        let mut bytes = Vec::new();
+        state
+            .write_to(&mut bytes)
+            .expect("writing to a Vec can't fail");
+
+        if let Some(previous) = self.newest.take() {
+            let delta = if previous.len() == bytes.len() {
+                let xor: Vec<u8> = previous.iter().zip(&bytes).map(|(a, b)| a ^ b).collect();
+                Delta::Xor(compress(&xor))
+            } else {
+                Delta::Full(compress(&previous))
+            };
+            self.older.push_back(delta);
+            while self.older.len() > self.capacity {
+                self.older.pop_front();
+            }
+        }
+        self.newest = Some(bytes);
+    }
+
+    /// Step one snapshot back in time, or None once there's nothing left.
+    pub fn pop(&mut self) -> Option<SaveState> {
+        let newest = self.newest.as_mut()?;
+        *newest = match self.older.pop_back()? {
+            Delta::Xor(xor) => {
+                let xor = decompress(&xor);
+                newest.iter().zip(&xor).map(|(a, b)| a ^ b).collect()
+            }
+            Delta::Full(bytes) => decompress(&bytes),
+        };
+        self.frames_since_snapshot = 0;
+        SaveState::read_from(&newest[..]).ok()
+    }
+
+    /// Call once a frame while rewinding, true on the frames that should
+    /// `pop`. That's the first and then every `interval`th, so rewinding
+    /// goes back as fast as the snapshots were taken.
+    pub fn pop_due(&mut self) -> bool {
+        let due = self.frames_since_pop == 0;
+        self.frames_since_pop = (self.frames_since_pop + 1) % self.interval;
+        due
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.older.clear();
+    }
+
+    /// How many snapshots there are to go back through.
+    pub fn len(&self) -> usize {
+        self.older.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.older.is_empty()
+    }
+
+    /// Roughly how much memory the snapshots are taking up.
+    pub fn size_in_bytes(&self) -> usize {
+        let older: usize = self
+            .older
+            .iter()
+            .map(|delta| match delta {
+                Delta::Xor(bytes) | Delta::Full(bytes) => bytes.len(),
+            })
+            .sum();
+        older + self.newest.as_ref().map_or(0, Vec::len)
+    }
+}
+
+// Zero runs and literal runs, alternating, each preceded by its length as a
+// varint. Always starts with a (possibly empty) zero run.
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let zeros = data[i..].iter().take_while(|&&b| b == 0).count();
+        i += zeros;
+        let literals = data[i..].iter().take_while(|&&b| b != 0).count();
+        write_varint(&mut out, zeros);
+        write_varint(&mut out, literals);
+        out.extend_from_slice(&data[i..i + literals]);
+        i += literals;
+    }
+    out
+}
+
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let zeros = read_varint(data, &mut i);
+        let literals = read_varint(data, &mut i);
+        out.resize(out.len() + zeros, 0);
+        out.extend_from_slice(&data[i..i + literals]);
+        i += literals;
+    }
+    out
+}
+
+fn write_varint(out: &mut Vec<u8>, mut n: usize) {
+    loop {
+        let byte = (n & 0x7F) as u8;
+        n >>= 7;
+        if n == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn read_varint(data: &[u8], i: &mut usize) -> usize {
+    let mut n = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*i];
+        *i += 1;
+        n |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return n;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compress, decompress, RewindBuffer};
+    use crate::savestate::SaveState;
+    use crate::testing::processor;
+
+    #[test]
+    fn compress_then_decompress() {
+        let mut long = vec![0; 1000];
+        long.extend(1..=255);
+        long.extend(vec![0; 300]);
+        for data in &[vec![], vec![0], vec![7], vec![0, 0, 1, 2, 0, 3], long] {
+            assert_eq!(&decompress(&compress(data)), data);
+        }
+        // A run of zeros is a couple of bytes
+        assert_eq!(compress(&[0; 1000]).len(), 3);
+    }
+
+    // A state a frame, V0 counting up, with a CALL part way so the stack
+    // depth and the snapshot's size change
+    fn states() -> Vec<SaveState> {
+        let mut cpu = processor("loop:\nADD V0, 1\nSE V0, 5\nJP loop\nCALL loop");
+        (0..20)
+            .map(|_| {
+                cpu.step().unwrap();
+                cpu.save_state()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pops_what_was_recorded() {
+        let states = states();
+        let mut rewind = RewindBuffer::new(10, 1);
+        for state in &states {
+            rewind.record(state);
+        }
+        assert_eq!(rewind.len(), states.len() - 1);
+        for state in states.iter().rev().skip(1) {
+            assert!(rewind.pop().as_ref() == Some(state));
+        }
+        assert!(rewind.pop().is_none());
+    }
+
+    #[test]
+    fn every_interval_and_no_further_back_than_capacity() {
+        let states = states();
+        // Room for two snapshots behind the newest, one every 3 frames
+        let mut rewind = RewindBuffer::new(0, 3);
+        rewind.capacity = 2;
+        for state in &states {
+            rewind.record(state);
+        }
+        assert_eq!(rewind.len(), 2);
+        // Kept 0, 3, ..., 18, so the newest is 18
+        assert!(rewind.pop().as_ref() == Some(&states[15]));
+        assert!(rewind.pop().as_ref() == Some(&states[12]));
+        assert!(rewind.pop().is_none());
+    }
+
+    #[test]
+    fn pops_as_often_as_it_recorded() {
+        let mut rewind = RewindBuffer::new(10, 3);
+        let due: Vec<bool> = (0..7).map(|_| rewind.pop_due()).collect();
+        assert_eq!(due, [true, false, false, true, false, false, true]);
+    }
+}
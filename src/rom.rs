@@ -0,0 +1,164 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum RomError {
+    NotFound(PathBuf),
+    Io(io::Error),
+    Empty,
+    /// Doesn't fit between the load address and the end of RAM
+    TooLarge {
+        size: usize,
+        max: usize,
+    },
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::NotFound(path) => write!(f, "no ROM at {}", path.display()),
+            RomError::Io(e) => write!(f, "couldn't read ROM: {}", e),
+            RomError::Empty => write!(f, "ROM is empty"),
+            RomError::TooLarge { size, max } => write!(
+                f,
+                "ROM is {} bytes, only {} fit in memory at the load address",
+                size, max
+            ),
+        }
+    }
+}
+
+impl Error for RomError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RomError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RomError {
+    fn from(e: io::Error) -> RomError {
+        RomError::Io(e)
+    }
+}
+
+/// A program, checked to be non-empty. Whether it fits is up to
+/// [`Processor::load_rom`](crate::Processor::load_rom), which knows how much
+/// RAM there is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rom {
+    bytes: Vec<u8>,
+}
+
+impl Rom {
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Rom, RomError> {
+        if bytes.is_empty() {
+            return Err(RomError::Empty);
+        }
+        Ok(Rom { bytes })
+    }
+
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Rom, RomError> {
+        let path = path.as_ref();
+        match fs::read(path) {
+            Ok(bytes) => Rom::from_bytes(bytes),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                Err(RomError::NotFound(path.to_path_buf()))
+            }
+            Err(e) => Err(RomError::Io(e)),
+        }
+    }
+
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<Rom, RomError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        Rom::from_bytes(bytes)
+    }
+
+    pub fn from_stdin() -> Result<Rom, RomError> {
+        Rom::from_reader(io::stdin().lock())
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    // Never true, but clippy wants it next to len()
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// SHA-1 of the ROM, the usual way of identifying one.
+    pub fn sha1(&self) -> [u8; 20] {
+        sha1_smol::Sha1::from(&self.bytes).digest().bytes()
+    }
+
+    pub fn sha1_hex(&self) -> String {
+        sha1_smol::Sha1::from(&self.bytes).digest().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Rom, RomError};
+    use crate::{Processor, Quirks};
+
+    #[test]
+    fn empty() {
+        assert!(matches!(Rom::from_bytes(Vec::new()), Err(RomError::Empty)));
+        assert!(matches!(Rom::from_reader(&[][..]), Err(RomError::Empty)));
+    }
+
+    #[test]
+    fn too_large() {
+        for &(quirks, max) in &[
+            (Quirks::COSMAC_VIP, 0x1000 - 0x200),
+            (Quirks::XO_CHIP, 0x10000 - 0x200),
+        ] {
+            let mut cpu = Processor::headless();
+            cpu.set_quirks(quirks);
+            let fits = Rom::from_bytes(vec![0xAA; max]).unwrap();
+            cpu.load_rom(&fits).unwrap();
+            assert_eq!(cpu.memory()[0x200 + max - 1], 0xAA);
+
+            let too_big = Rom::from_bytes(vec![0xAA; max + 1]).unwrap();
+            assert!(matches!(
+                cpu.load_rom(&too_big),
+                Err(RomError::TooLarge { size, max: m }) if size == max + 1 && m == max
+            ));
+        }
+
+        // Less room above a higher load address
+        let mut cpu = Processor::headless();
+        cpu.set_load_address(0x600);
+        let rom = Rom::from_bytes(vec![0; 0x1000 - 0x5FF]).unwrap();
+        assert!(matches!(
+            cpu.load_rom(&rom),
+            Err(RomError::TooLarge { max: 0xA00, .. })
+        ));
+    }
+
+    #[test]
+    fn missing() {
+        let path = std::env::temp_dir().join("chippe_rs-no-such-rom.ch8");
+        match Rom::from_path(&path) {
+            Err(RomError::NotFound(missing)) => assert_eq!(missing, path),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn sha1() {
+        let rom = Rom::from_bytes(b"abc".to_vec()).unwrap();
+        assert_eq!(rom.sha1_hex(), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(rom.sha1()[..4], [0xa9, 0x99, 0x3e, 0x36]);
+    }
+}
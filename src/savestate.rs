@@ -0,0 +1,363 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::framebuffer::Framebuffer;
+use crate::processor::KeyWait;
+use crate::random::Random;
+use crate::{GPR_SIZE, SCHIP_HEIGHT, SCHIP_WIDTH, XO_RAM_SIZE};
+
+const MAGIC: &[u8; 4] = b"C8SS";
+/// Bumped whenever the layout below changes. Older states are rejected
+/// rather than guessed at.
+pub const SAVE_STATE_VERSION: u16 = 4;
+
+#[derive(Debug)]
+pub enum SaveStateError {
+    Io(io::Error),
+    /// Doesn't start with the save state header
+    NotASaveState,
+    UnsupportedVersion(u16),
+    /// Saved from a different ROM
+    WrongRom,
+    /// Saved with a different amount of RAM (XO-CHIP or not)
+    WrongMemorySize {
+        saved: usize,
+        current: usize,
+    },
+    /// The header was fine but the rest doesn't make sense
+    Corrupt,
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveStateError::Io(e) => write!(f, "couldn't read or write save state: {}", e),
+            SaveStateError::NotASaveState => write!(f, "not a save state"),
+            SaveStateError::UnsupportedVersion(v) => write!(
+                f,
+                "save state is version {}, this build reads version {}",
+                v, SAVE_STATE_VERSION
+            ),
+            SaveStateError::WrongRom => write!(f, "save state is for a different ROM"),
+            SaveStateError::WrongMemorySize { saved, current } => write!(
+                f,
+                "save state has {} bytes of RAM, this machine has {}",
+                saved, current
+            ),
+            SaveStateError::Corrupt => write!(f, "save state is corrupt"),
+        }
+    }
+}
+
+impl Error for SaveStateError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SaveStateError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SaveStateError {
+    fn from(e: io::Error) -> SaveStateError {
+        match e.kind() {
+            // Ran out of file part way through
+            io::ErrorKind::UnexpectedEof => SaveStateError::Corrupt,
+            _ => SaveStateError::Io(e),
+        }
+    }
+}
+
+/// Everything needed to put a [`Processor`](crate::Processor) back exactly
+/// where it was, see `Processor::save_state` and `Processor::load_state`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SaveState {
+    pub(crate) rom_sha1: [u8; 20],
+    pub(crate) program_counter: u16,
+    pub(crate) gpr_v: [u8; GPR_SIZE],
+    pub(crate) reg_i: u16,
+    pub(crate) rpl_flags: [u8; 16],
+    pub(crate) random: Random,
+    pub(crate) instructions: u64,
+    pub(crate) audio_pattern: [u8; 16],
+    pub(crate) pitch: u8,
+    pub(crate) delay_timer: u8,
+    pub(crate) sound_timer: u8,
+    pub(crate) stack: Vec<u16>,
+    pub(crate) keyboard_state: [bool; 16],
+    pub(crate) key_wait: Option<KeyWait>,
+    pub(crate) vblank_wait: bool,
+    pub(crate) display_state: Framebuffer,
+    pub(crate) ram: Vec<u8>,
+}
+
+impl fmt::Debug for SaveState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SaveState")
+            .field("program_counter", &self.program_counter)
+            .field("ram", &self.ram.len())
+            .finish()
+    }
+}
+
+impl SaveState {
+    /// SHA-1 of the ROM this was saved from.
+    pub fn rom_sha1(&self) -> &[u8; 20] {
+        &self.rom_sha1
+    }
+
+    /// SHA-1 of the whole state as it would be written out, for checking
+    /// two runs ended up in exactly the same place.
+    pub fn sha1(&self) -> [u8; 20] {
+        let mut bytes = Vec::new();
+        self.write_to(&mut bytes)
+            .expect("writing to a Vec can't fail");
+        sha1_smol::Sha1::from(&bytes).digest().bytes()
+    }
+
+    /// Where numbered slot `slot` for a ROM lives in `dir`.
+    pub fn slot_path<P: AsRef<Path>>(dir: P, rom_sha1: &[u8; 20], slot: u8) -> PathBuf {
+        let hash: String = rom_sha1.iter().map(|b| format!("{:02x}", b)).collect();
+        dir.as_ref().join(format!("{}.{}.state", hash, slot))
+    }
+
+    pub fn save_to_path<P: AsRef<Path>>(&self, path: P) -> Result<(), SaveStateError> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = io::BufWriter::new(fs::File::create(path)?);
+        self.write_to(&mut file)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    pub fn load_from_path<P: AsRef<Path>>(path: P) -> Result<SaveState, SaveStateError> {
+        let file = fs::File::open(path)?;
+        SaveState::read_from(io::BufReader::new(file))
+    }
+
+    pub fn write_to<W: Write>(&self, mut w: W) -> Result<(), SaveStateError> {
+        w.write_all(MAGIC)?;
+        w.write_all(&SAVE_STATE_VERSION.to_le_bytes())?;
+        w.write_all(&self.rom_sha1)?;
+
+        w.write_all(&self.program_counter.to_le_bytes())?;
+        w.write_all(&self.gpr_v)?;
+        w.write_all(&self.reg_i.to_le_bytes())?;
+        w.write_all(&self.rpl_flags)?;
+        w.write_all(&self.random.to_bytes())?;
+        w.write_all(&self.instructions.to_le_bytes())?;
+        w.write_all(&self.audio_pattern)?;
+        w.write_all(&[self.pitch, self.delay_timer, self.sound_timer])?;
+
+        w.write_all(&[self.stack.len() as u8])?;
+        for address in &self.stack {
+            w.write_all(&address.to_le_bytes())?;
+        }
+
+        w.write_all(&keys_to_bits(&self.keyboard_state).to_le_bytes())?;
+        match self.key_wait {
+            None => w.write_all(&[0, 0, 0])?,
+            Some(KeyWait { register, key }) => {
+                let (pressed, key) = match key {
+                    Some(key) => (1, key),
+                    None => (0, 0),
+                };
+                // High bit marks it as waiting
+                w.write_all(&[0x80 | register as u8, pressed, key])?
+            }
+        }
+        w.write_all(&[self.vblank_wait as u8])?;
+
+        let display = &self.display_state;
+        w.write_all(&[display.hires() as u8, display.planes()])?;
+        for row in display.raw().iter() {
+            w.write_all(row)?;
+        }
+
+        w.write_all(&(self.ram.len() as u32).to_le_bytes())?;
+        w.write_all(&self.ram)?;
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(mut r: R) -> Result<SaveState, SaveStateError> {
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)
+            .map_err(|_| SaveStateError::NotASaveState)?;
+        if &magic != MAGIC {
+            return Err(SaveStateError::NotASaveState);
+        }
+        let version = read_u16(&mut r)?;
+        if version != SAVE_STATE_VERSION {
+            return Err(SaveStateError::UnsupportedVersion(version));
+        }
+        let mut rom_sha1 = [0; 20];
+        r.read_exact(&mut rom_sha1)?;
+
+        let program_counter = read_u16(&mut r)?;
+        let mut gpr_v = [0; GPR_SIZE];
+        r.read_exact(&mut gpr_v)?;
+        let reg_i = read_u16(&mut r)?;
+        let mut rpl_flags = [0; 16];
+        r.read_exact(&mut rpl_flags)?;
+        let random = Random::from_bytes(read_array(&mut r)?);
+        let instructions = u64::from_le_bytes(read_array(&mut r)?);
+        let mut audio_pattern = [0; 16];
+        r.read_exact(&mut audio_pattern)?;
+        let [pitch, delay_timer, sound_timer] = read_array::<_, 3>(&mut r)?;
+
+        let [depth] = read_array::<_, 1>(&mut r)?;
+        let mut stack = Vec::with_capacity(depth as usize);
+        for _ in 0..depth {
+            stack.push(read_u16(&mut r)?);
+        }
+
+        let keyboard_state = bits_to_keys(read_u16(&mut r)?);
+        let key_wait = match read_array::<_, 3>(&mut r)? {
+            [0, _, _] => None,
+            [register, pressed, key] => Some(KeyWait {
+                register: (register & 0xF) as usize,
+                key: if pressed == 1 { Some(key) } else { None },
+            }),
+        };
+        let [vblank_wait] = read_array::<_, 1>(&mut r)?;
+
+        let [hires, planes] = read_array::<_, 2>(&mut r)?;
+        let mut pixels = Box::new([[0; SCHIP_WIDTH]; SCHIP_HEIGHT]);
+        for row in pixels.iter_mut() {
+            r.read_exact(row)?;
+        }
+        let display_state = Framebuffer::from_raw(hires == 1, planes & 0b11, pixels);
+
+        let ram_len = u32::from_le_bytes(read_array(&mut r)?) as usize;
+        if ram_len > XO_RAM_SIZE {
+            return Err(SaveStateError::Corrupt);
+        }
+        let mut ram = vec![0; ram_len];
+        r.read_exact(&mut ram)?;
+
+        Ok(SaveState {
+            rom_sha1,
+            program_counter,
+            gpr_v,
+            reg_i,
+            rpl_flags,
+            random,
+            instructions,
+            audio_pattern,
+            pitch,
+            delay_timer,
+            sound_timer,
+            stack,
+            keyboard_state,
+            key_wait,
+            vblank_wait: vblank_wait == 1,
+            display_state,
+            ram,
+        })
+    }
+}
+
+pub(crate) fn read_array<R: Read, const N: usize>(r: &mut R) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+pub(crate) fn read_u16<R: Read>(r: &mut R) -> io::Result<u16> {
+    Ok(u16::from_le_bytes(read_array(r)?))
+}
+
+pub(crate) fn keys_to_bits(keys: &[bool; 16]) -> u16 {
+    keys.iter()
+        .enumerate()
+        .fold(0, |bits, (i, &down)| bits | ((down as u16) << i))
+}
+
+pub(crate) fn bits_to_keys(bits: u16) -> [bool; 16] {
+    let mut keys = [false; 16];
+    for (i, key) in keys.iter_mut().enumerate() {
+        *key = bits & (1 << i) != 0;
+    }
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SaveState, SaveStateError, SAVE_STATE_VERSION};
+    use crate::testing::processor;
+
+    const SOURCE: &str = "LD V0, 7\nLD I, 0x300\nCALL sub\nsub:\nLD [I], V0\nDRW V0, V0, 5";
+
+    fn bytes(state: &SaveState) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        state.write_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    fn after_a_few_steps() -> SaveState {
+        let mut cpu = processor(SOURCE);
+        for _ in 0..5 {
+            cpu.step().unwrap();
+        }
+        cpu.save_state()
+    }
+
+    #[test]
+    fn round_trip() {
+        let state = after_a_few_steps();
+        let read = SaveState::read_from(&bytes(&state)[..]).unwrap();
+        assert!(read == state);
+
+        let mut cpu = processor(SOURCE);
+        cpu.load_state(&read).unwrap();
+        assert_eq!(cpu.registers()[0], 7);
+        // Moved on by the LD [I], V0
+        assert_eq!(cpu.reg_i(), 0x301);
+        assert_eq!(cpu.stack().entries(), &[0x206]);
+        assert_eq!(cpu.memory()[0x300], 7);
+        assert_eq!(cpu.instructions(), 5);
+        assert!(cpu.save_state() == state);
+    }
+
+    #[test]
+    fn other_roms_are_turned_away() {
+        let state = after_a_few_steps();
+        let mut cpu = processor("LD V0, 1");
+        assert!(matches!(
+            cpu.load_state(&state),
+            Err(SaveStateError::WrongRom)
+        ));
+        // And left alone
+        assert_eq!(cpu.program_counter(), 0x200);
+    }
+
+    #[test]
+    fn other_versions_are_turned_away() {
+        let mut bytes = bytes(&after_a_few_steps());
+        bytes[4..6].copy_from_slice(&(SAVE_STATE_VERSION - 1).to_le_bytes());
+        assert!(matches!(
+            SaveState::read_from(&bytes[..]),
+            Err(SaveStateError::UnsupportedVersion(v)) if v == SAVE_STATE_VERSION - 1
+        ));
+    }
+
+    #[test]
+    fn truncated_files_are_corrupt() {
+        let bytes = bytes(&after_a_few_steps());
+        for &len in &[10, 100, bytes.len() - 1] {
+            assert!(matches!(
+                SaveState::read_from(&bytes[..len]),
+                Err(SaveStateError::Corrupt)
+            ));
+        }
+        assert!(matches!(
+            SaveState::read_from(&b"C8"[..]),
+            Err(SaveStateError::NotASaveState)
+        ));
+    }
+}
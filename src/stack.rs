@@ -0,0 +1,175 @@
+use std::error::Error;
+use std::fmt;
+
+// Where the VIP interpreter keeps its stack on a 4K machine, growing down
+pub const VIP_STACK_TOP: u16 = 0xED0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackError {
+    /// CALL with the stack already `depth` deep
+    Overflow { depth: usize },
+    /// RET with nothing to return to
+    Underflow,
+}
+
+impl fmt::Display for StackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StackError::Overflow { depth } => {
+                write!(f, "stack overflow, already {} calls deep", depth)
+            }
+            StackError::Underflow => write!(f, "stack underflow, RET without a CALL"),
+        }
+    }
+}
+
+impl Error for StackError {}
+
+/// Return addresses for CALL/RET.
+///
+/// The addresses always live here, but with `in_ram` set they're also
+/// written below [`VIP_STACK_TOP`] the way the VIP did, and read back from
+/// there on RET, so programs that poke at their own stack still work.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallStack {
+    entries: Vec<u16>,
+    limit: usize,
+    in_ram: bool,
+}
+
+impl CallStack {
+    pub fn new(limit: usize, in_ram: bool) -> CallStack {
+        CallStack {
+            entries: Vec::with_capacity(limit),
+            limit,
+            in_ram,
+        }
+    }
+
+    pub fn push(&mut self, address: u16, memory: &mut [u8]) -> Result<(), StackError> {
+        if self.entries.len() >= self.limit {
+            return Err(StackError::Overflow {
+                depth: self.entries.len(),
+            });
+        }
+        if self.in_ram {
+            let slot = self.ram_slot(self.entries.len());
+            memory[slot] = (address >> 8) as u8;
+            memory[slot + 1] = address as u8;
+        }
+        self.entries.push(address);
+        Ok(())
+    }
+
+    pub fn pop(&mut self, memory: &[u8]) -> Result<u16, StackError> {
+        let address = self.entries.pop().ok_or(StackError::Underflow)?;
+        if self.in_ram {
+            let slot = self.ram_slot(self.entries.len());
+            return Ok(((memory[slot] as u16) << 8) | memory[slot + 1] as u16);
+        }
+        Ok(address)
+    }
+
+    // For save states. Anything in RAM comes back with the rest of RAM.
+    pub(crate) fn restore(&mut self, entries: &[u16]) {
+        self.entries = entries.to_vec();
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn depth(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn in_ram(&self) -> bool {
+        self.in_ram
+    }
+
+    /// Return addresses, oldest first.
+    pub fn entries(&self) -> &[u16] {
+        &self.entries
+    }
+
+    fn ram_slot(&self, index: usize) -> usize {
+        VIP_STACK_TOP as usize - 2 * (index + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StackError, VIP_STACK_TOP};
+    use crate::fault::{ExitReason, Fault};
+    use crate::testing::processor_with;
+    use crate::Quirks;
+
+    #[test]
+    fn overflow_at_the_depth_limit() {
+        let mut cpu = processor_with(Quirks::COSMAC_VIP, "loop: CALL loop");
+        for _ in 0..12 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(
+            cpu.step(),
+            Err(ExitReason::Fault {
+                pc: 0x200,
+                opcode: 0x2200,
+                fault: Fault::Stack(StackError::Overflow { depth: 12 }),
+            })
+        );
+
+        // SUPER-CHIP goes deeper
+        let mut cpu = processor_with(Quirks::SUPER_CHIP, "loop: CALL loop");
+        for _ in 0..16 {
+            cpu.step().unwrap();
+        }
+        assert!(cpu.step().is_err());
+    }
+
+    #[test]
+    fn underflow_on_ret_with_nothing_to_return_to() {
+        let mut cpu = processor_with(Quirks::COSMAC_VIP, "LD V0, 1\nRET");
+        cpu.step().unwrap();
+        assert_eq!(
+            cpu.step(),
+            Err(ExitReason::Fault {
+                pc: 0x202,
+                opcode: 0x00EE,
+                fault: Fault::Stack(StackError::Underflow),
+            })
+        );
+    }
+
+    // The subroutine rewrites its own return address to 0x300
+    #[test]
+    fn in_ram_where_the_vip_kept_it() {
+        let mut quirks = Quirks::COSMAC_VIP;
+        quirks.stack_in_ram = true;
+        let mut cpu = processor_with(
+            quirks,
+            "
+                CALL sub
+                EXIT
+            sub:
+                LD V0, 0x03
+                LD V1, 0x00
+                LD I, 0xECE
+                LD [I], V1
+                RET
+            ",
+        );
+        cpu.step().unwrap();
+        let slot = VIP_STACK_TOP as usize - 2;
+        assert_eq!(&cpu.memory()[slot..slot + 2], &[0x02, 0x02]);
+        for _ in 0..5 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(&cpu.memory()[slot..slot + 2], &[0x03, 0x00]);
+        assert_eq!(cpu.program_counter(), 0x300);
+    }
+}
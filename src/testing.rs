@@ -0,0 +1,24 @@
+//! Bits and pieces the tests share.
+
+use crate::asm::assemble;
+use crate::{Processor, Quirks};
+
+/// A headless processor with `source` assembled and loaded, ready to run.
+pub(crate) fn processor(source: &str) -> Processor {
+    processor_with(Quirks::default(), source)
+}
+
+pub(crate) fn processor_with(quirks: Quirks, source: &str) -> Processor {
+    let mut cpu = Processor::headless();
+    cpu.set_quirks(quirks);
+    load(&mut cpu, source);
+    cpu
+}
+
+/// Reset `cpu` and load `source` into it, for processors with their own
+/// drivers.
+pub(crate) fn load(cpu: &mut Processor, source: &str) {
+    cpu.reset();
+    cpu.load_rom_bytes(assemble(source).unwrap().bytes())
+        .unwrap();
+}
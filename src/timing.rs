@@ -0,0 +1,168 @@
+//! How long things take.
+//!
+//! By default a frame is just [`Processor::instructions_per_frame`]
+//! instructions, however long they'd have taken. [`Timing::CosmacVip`]
+//! instead charges each instruction what the VIP's interpreter spent on
+//! it, from the table cited on [`vip_cycles`], in 1802 machine cycles (8
+//! clocks, about 4.5 µs at 1.76 MHz), and ends the frame when the cycles
+//! left after the 60 Hz interrupt and the display's DMA have run out. So a
+//! frame of sprite drawing gets a lot less done than a frame of
+//! arithmetic, like on the real thing.
+//!
+//! [`Processor::instructions_per_frame`]: crate::Processor::instructions_per_frame
+
+use crate::instruction::Instruction;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Timing {
+    /// A fixed number of instructions a frame
+    #[default]
+    Instructions,
+    /// Machine cycles, as the COSMAC VIP counted them
+    CosmacVip,
+}
+
+/// 1.76064 MHz, 8 clocks a machine cycle, 60 frames a second.
+pub const VIP_CYCLES_PER_FRAME: u32 = 3668;
+
+/// The CDP1861 reads 8 bytes a line for 128 lines, a cycle a byte, and
+/// the interrupt routine sets it up and ticks the timers.
+pub const VIP_INTERRUPT_CYCLES: u32 = 128 * 8 + 46;
+
+/// What's left of each frame for the interpreter.
+pub const VIP_FRAME_BUDGET: u32 = VIP_CYCLES_PER_FRAME - VIP_INTERRUPT_CYCLES;
+
+// Fetching, decoding and dispatching, whatever the instruction. The table
+// below leaves this out, so it's an estimate of the VIP interpreter's main
+// loop rather than a measured figure.
+const VIP_FETCH_CYCLES: u32 = 40;
+
+/// Extra for a skip that skips.
+pub const VIP_SKIP_CYCLES: u32 = 4;
+
+/// What `instruction` costs on the VIP with `registers` as they are before
+/// it runs, not counting [`VIP_SKIP_CYCLES`].
+///
+/// The costs are the execution times in Jackson S.'s "Chip-8 Instruction
+/// Scheduling and Frequency" (2019), converted from microseconds to
+/// machine cycles at 4.54 µs each. That table gives one figure for each
+/// instruction, so BCD and storing or loading registers cost the same
+/// whatever the values or how many registers. The exceptions are:
+///
+/// - DXYN, where the table's figure is the worst case including the wait
+///   for the interrupt. That wait happens separately here, and the drawing
+///   itself is estimated from how the interpreter shifts each row into
+///   place.
+/// - Instructions the VIP never had, which aren't in the table and cost the
+///   same as the cheapest one that is, 6XNN.
+pub fn vip_cycles(instruction: Instruction, registers: &[u8; 16]) -> u32 {
+    use crate::instruction::Instruction::*;
+
+    VIP_FETCH_CYCLES
+        + match instruction {
+            // 109 µs
+            Cls => 24,
+            // 105 µs
+            Ret | Jump(_) | Call(_) | JumpV0(_) => 23,
+            // 55 µs
+            SkipEqual { .. } | SkipNotEqual { .. } | LoadI(_) => 12,
+            // 73 µs
+            SkipEqualRegister { .. } | SkipNotEqualRegister { .. } | SkipKey(_) | SkipNotKey(_) => {
+                16
+            }
+            // 27 µs
+            LoadByte { .. } => 6,
+            // 45 µs
+            AddByte { .. } | GetDelay(_) | WaitKey(_) | SetDelay(_) | SetSound(_) => 10,
+            // 200 µs, all of 8XYN goes through the same self-modifying
+            // routine
+            Move { .. }
+            | Or { .. }
+            | And { .. }
+            | Xor { .. }
+            | Add { .. }
+            | Sub { .. }
+            | ShiftRight { .. }
+            | SubN { .. }
+            | ShiftLeft { .. } => 44,
+            // 164 µs
+            Random { .. } => 36,
+            // Each row gets shifted into place a bit at a time, then both
+            // bytes it lands on are XORed in
+            Draw { x, rows, .. } => {
+                let shift = (registers[x as usize] % 8) as u32;
+                let rows = if rows == 0 { 16 } else { rows as u32 };
+                26 + rows * (24 + 4 * shift)
+            }
+            // 86 µs
+            AddI(_) => 19,
+            // 91 µs
+            Font(_) => 20,
+            // 927 µs
+            Bcd(_) => 204,
+            // 605 µs
+            StoreRegisters(_) | LoadRegisters(_) => 133,
+            _ => 6,
+        }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::instruction::Instruction;
+    use crate::testing::processor_with;
+    use crate::timing::{vip_cycles, Timing, VIP_FETCH_CYCLES, VIP_FRAME_BUDGET};
+    use crate::{Processor, Quirks};
+
+    fn vip(source: &str) -> Processor {
+        let mut cpu = processor_with(Quirks::COSMAC_VIP, source);
+        cpu.set_timing(Timing::CosmacVip);
+        cpu
+    }
+
+    fn instructions_in_a_frame(source: &str) -> u64 {
+        let mut cpu = vip(source);
+        let mut count = 0;
+        loop {
+            cpu.step_instruction().unwrap();
+            count += 1;
+            if cpu.cycles() >= VIP_FRAME_BUDGET as u64 {
+                return count;
+            }
+        }
+    }
+
+    #[test]
+    fn frames_are_cycles_not_instructions() {
+        let cheap = instructions_in_a_frame("loop:\nLD V0, 1\nJP loop");
+        let dear = instructions_in_a_frame("loop:\nLD V0, V1\nJP loop");
+        assert!(cheap > dear, "{} vs {}", cheap, dear);
+    }
+
+    // DRW waits for the interrupt, so there's one a frame however cheap
+    // everything else is
+    #[test]
+    fn sprites_wait_for_the_interrupt() {
+        let mut cpu = vip("loop:\nDRW V0, V1, 1\nADD V0, 1\nJP loop");
+        for _ in 0..10 {
+            cpu.step_frame().unwrap();
+        }
+        assert_eq!(cpu.registers()[0], 9);
+    }
+
+    // The table has one figure for these, whatever the registers hold
+    #[test]
+    fn costs_dont_depend_on_values() {
+        let cost = |instruction, value| vip_cycles(instruction, &[value; 16]) - VIP_FETCH_CYCLES;
+        assert_eq!(cost(Instruction::Bcd(0), 0), 204);
+        assert_eq!(cost(Instruction::Bcd(0), 199), 204);
+        assert_eq!(cost(Instruction::StoreRegisters(0), 0), 133);
+        assert_eq!(cost(Instruction::LoadRegisters(15), 0), 133);
+        // Except for drawing, which shifts each row into place
+        let draw = Instruction::Draw {
+            x: 0,
+            y: 1,
+            rows: 1,
+        };
+        assert!(cost(draw, 3) > cost(draw, 0));
+    }
+}
@@ -0,0 +1,298 @@
+//! Recording what the interpreter does, one instruction at a time.
+//!
+//! A [`Tracer`] handed to [`Processor::set_tracer`] gets a [`TraceEntry`]
+//! for every instruction about to run in the address ranges it cares
+//! about, and writes each one out as a line of JSON. Or, with
+//! [`Tracer::keep_last`], it only holds on to the most recent few and
+//! writes those out if the program faults, which costs a lot less than
+//! writing out everything on the chance that something goes wrong.
+//!
+//! [`Processor::set_tracer`]: crate::Processor::set_tracer
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+
+use serde_json::json;
+
+use crate::disasm::disassemble_instruction;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TraceLevel {
+    /// Where each instruction is and what it is
+    Instructions,
+    /// Plus the registers, I and the timers before it runs
+    Registers,
+}
+
+/// The machine just before one instruction runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry {
+    /// Instructions traced before this one
+    pub index: u64,
+    /// Instructions executed before this one, traced or not, see
+    /// [`crate::Processor::instructions`]
+    pub instructions: u64,
+    /// VIP machine cycles so far, see [`crate::Processor::cycles`]. Only
+    /// there with [`crate::Timing::CosmacVip`].
+    pub cycles: Option<u64>,
+    pub pc: u16,
+    pub opcode: u16,
+    /// The word after an F000
+    pub long: Option<u16>,
+    pub registers: [u8; 16],
+    pub i: u16,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+}
+
+impl TraceEntry {
+    /// One line of JSON, with as much as `level` asks for.
+    pub fn write_to<W: Write>(&self, level: TraceLevel, mut writer: W) -> io::Result<()> {
+        let mut line = json!({
+            "index": self.index,
+            "instructions": self.instructions,
+            "pc": self.pc,
+            "opcode": self.opcode,
+            "mnemonic": disassemble_instruction(self.opcode, self.long),
+        });
+        if let Some(cycles) = self.cycles {
+            line["cycles"] = json!(cycles);
+        }
+        if level >= TraceLevel::Registers {
+            line["v"] = json!(self.registers);
+            line["i"] = json!(self.i);
+            line["dt"] = json!(self.delay_timer);
+            line["st"] = json!(self.sound_timer);
+        }
+        writeln!(writer, "{}", line)
+    }
+}
+
+pub struct Tracer {
+    level: TraceLevel,
+    // Everywhere if empty
+    ranges: Vec<RangeInclusive<u16>>,
+    output: Box<dyn Write>,
+    // With keep_last, the newest at the back
+    history: Option<VecDeque<TraceEntry>>,
+    capacity: usize,
+    count: u64,
+}
+
+impl Tracer {
+    /// Trace every instruction to stderr, until told otherwise.
+    pub fn new(level: TraceLevel) -> Tracer {
+        Tracer {
+            level,
+            ranges: Vec::new(),
+            output: Box::new(io::stderr()),
+            history: None,
+            capacity: 0,
+            count: 0,
+        }
+    }
+
+    pub fn level(&self) -> TraceLevel {
+        self.level
+    }
+
+    /// Write to `output` instead of stderr.
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    /// Only trace instructions in `range`. Can be called more than once
+    /// for more than one range.
+    pub fn add_range(&mut self, range: RangeInclusive<u16>) {
+        self.ranges.push(range);
+    }
+
+    /// Instead of writing out every entry, keep the last `count` and only
+    /// write them out if the program faults.
+    pub fn keep_last(&mut self, count: usize) {
+        self.capacity = count;
+        self.history = Some(VecDeque::with_capacity(count));
+    }
+
+    /// Whether the instruction at `pc` should be traced.
+    pub fn wants(&self, pc: u16) -> bool {
+        self.ranges.is_empty() || self.ranges.iter().any(|range| range.contains(&pc))
+    }
+
+    /// The entries being kept by `keep_last`, oldest first.
+    pub fn history(&self) -> impl Iterator<Item = &TraceEntry> {
+        self.history.iter().flatten()
+    }
+
+    /// Called by the processor, with `index` filled in here.
+    pub(crate) fn record(&mut self, mut entry: TraceEntry) {
+        entry.index = self.count;
+        self.count += 1;
+        match self.history.as_mut() {
+            Some(history) => {
+                if history.len() == self.capacity {
+                    history.pop_front();
+                }
+                if self.capacity > 0 {
+                    history.push_back(entry);
+                }
+            }
+            None => {
+                let result = entry.write_to(self.level, &mut self.output);
+                self.check(result);
+            }
+        }
+    }
+
+    /// Called by the processor when the program faults.
+    pub(crate) fn fault(&mut self) {
+        if let Some(history) = self.history.as_mut() {
+            let entries = std::mem::take(history);
+            let level = self.level;
+            let result = entries
+                .iter()
+                .try_for_each(|entry| entry.write_to(level, &mut self.output));
+            self.check(result);
+        }
+        let result = self.output.flush();
+        self.check(result);
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+
+    // A trace that can't be written isn't worth stopping the program for,
+    // but isn't worth trying again every instruction either
+    fn check(&mut self, result: io::Result<()>) {
+        if let Err(e) = result {
+            eprintln!("Couldn't write trace, giving up on it: {}", e);
+            self.output = Box::new(io::sink());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+
+    use super::{TraceLevel, Tracer};
+    use crate::testing::processor;
+    use crate::{Processor, Timing};
+
+    // Somewhere to write that the test can still read afterwards
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Shared {
+        fn lines(&self) -> Vec<serde_json::Value> {
+            let bytes = self.0.lock().unwrap().clone();
+            String::from_utf8(bytes)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        }
+    }
+
+    fn run(source: &str, tracer: Tracer, steps: usize) -> Processor {
+        let mut cpu = processor(source);
+        cpu.set_tracer(Some(tracer));
+        for _ in 0..steps {
+            if cpu.step().is_err() {
+                break;
+            }
+        }
+        cpu
+    }
+
+    #[test]
+    fn writes_a_line_per_instruction() {
+        let out = Shared::default();
+        let mut tracer = Tracer::new(TraceLevel::Registers);
+        tracer.set_output(Box::new(out.clone()));
+        run("LD V0, 5\nADD V0, 1\nLD I, 0x300", tracer, 3);
+
+        let lines = out.lines();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1]["pc"], 0x202);
+        assert_eq!(lines[1]["opcode"], 0x7001);
+        assert_eq!(lines[1]["mnemonic"], "ADD V0, 0x01");
+        // Before it runs
+        assert_eq!(lines[1]["v"][0], 5);
+        assert_eq!(lines[2]["index"], 2);
+    }
+
+    #[test]
+    fn ranges_and_levels() {
+        let out = Shared::default();
+        let mut tracer = Tracer::new(TraceLevel::Instructions);
+        tracer.set_output(Box::new(out.clone()));
+        tracer.add_range(0x202..=0x203);
+        run("LD V0, 5\nADD V0, 1\nLD I, 0x300", tracer, 3);
+
+        let lines = out.lines();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["pc"], 0x202);
+        assert!(lines[0].get("v").is_none());
+        // The first one traced, but not the first one run
+        assert_eq!(lines[0]["index"], 0);
+        assert_eq!(lines[0]["instructions"], 1);
+    }
+
+    #[test]
+    fn cycles_only_with_vip_timing() {
+        let trace = |timing| {
+            let out = Shared::default();
+            let mut tracer = Tracer::new(TraceLevel::Instructions);
+            tracer.set_output(Box::new(out.clone()));
+            let mut cpu = processor("LD V0, 5\nADD V0, 1");
+            cpu.set_timing(timing);
+            cpu.set_tracer(Some(tracer));
+            cpu.step().unwrap();
+            cpu.step().unwrap();
+            (cpu, out.lines())
+        };
+
+        let (_, lines) = trace(Timing::Instructions);
+        assert!(lines[1].get("cycles").is_none());
+
+        let (cpu, lines) = trace(Timing::CosmacVip);
+        assert_eq!(lines[0]["cycles"], 0);
+        assert!(lines[1]["cycles"].as_u64().unwrap() > 0);
+        assert!(lines[1]["cycles"].as_u64().unwrap() < cpu.cycles());
+    }
+
+    #[test]
+    fn keeps_the_last_few_for_a_fault() {
+        let out = Shared::default();
+        let tracer = || {
+            let mut tracer = Tracer::new(TraceLevel::Instructions);
+            tracer.set_output(Box::new(out.clone()));
+            tracer.keep_last(2);
+            tracer
+        };
+        let source = "LD V0, 1\nADD V0, 1\nADD V0, 1\nDW 0x5001";
+
+        let cpu = run(source, tracer(), 3);
+        assert!(out.lines().is_empty());
+        assert_eq!(cpu.tracer().unwrap().history().count(), 2);
+
+        // The last one is the one that broke
+        run(source, tracer(), 10);
+        let pcs: Vec<_> = out.lines().iter().map(|line| line["pc"].clone()).collect();
+        assert_eq!(pcs, [0x204, 0x206]);
+    }
+}
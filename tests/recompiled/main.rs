@@ -0,0 +1,88 @@
+//! The checked-in recompiled ROM in `rom.rs` against the interpreter
+//! running the same ROM, frame by frame.
+
+#[rustfmt::skip]
+mod rom;
+
+use std::fs;
+
+use chippe_rs::asm::assemble_file;
+use chippe_rs::recompile::recompile;
+use chippe_rs::{ExitReason, Processor, Random};
+
+const SOURCE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/recompiled/rom.asm");
+const MODULE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/recompiled/rom.rs");
+
+fn processor(recompiled: bool) -> Processor {
+    let assembly = assemble_file(SOURCE).unwrap();
+    let mut cpu = Processor::headless();
+    cpu.set_random(Random::seeded(8));
+    // Odd, so frames end part way through blocks
+    cpu.set_instructions_per_frame(7);
+    if recompiled {
+        cpu.set_recompiled(Some(rom::run_block));
+    }
+    cpu.reset();
+    cpu.load_rom_bytes(assembly.bytes()).unwrap();
+    cpu
+}
+
+#[test]
+fn module_is_up_to_date() {
+    let assembly = assemble_file(SOURCE).unwrap();
+    let mut module = Vec::new();
+    recompile(assembly.bytes(), assembly.load_address(), &mut module).unwrap();
+    assert!(
+        String::from_utf8(module).unwrap() == fs::read_to_string(MODULE).unwrap(),
+        "rom.rs is out of date, see rom.asm for how to remake it"
+    );
+}
+
+#[test]
+fn runs_the_blocks_it_has() {
+    let mut cpu = processor(false);
+    assert_eq!(rom::run_block(&mut cpu, 100), Ok(4));
+    assert_eq!(cpu.program_counter(), 0x208);
+    // Not the start of a block
+    cpu.set_program_counter(0x202);
+    assert_eq!(rom::run_block(&mut cpu, 100), Ok(0));
+}
+
+#[test]
+fn lockstep_with_the_interpreter() {
+    let mut interpreted = processor(false);
+    let mut recompiled = processor(true);
+    for frame in 0.. {
+        let expected = interpreted.step_frame();
+        assert_eq!(recompiled.step_frame(), expected, "frame {}", frame);
+        assert!(
+            recompiled.save_state() == interpreted.save_state(),
+            "frame {}: {:x?} I={:03x} PC={:03x}, should be {:x?} I={:03x} PC={:03x}",
+            frame,
+            recompiled.registers(),
+            recompiled.reg_i(),
+            recompiled.program_counter(),
+            interpreted.registers(),
+            interpreted.reg_i(),
+            interpreted.program_counter(),
+        );
+        if expected.is_err() {
+            assert_eq!(expected, Err(ExitReason::ProgramExit));
+            break;
+        }
+        assert!(frame < 1000, "never finished");
+    }
+}
+
+// run goes a frame at a time through step_frame too
+#[test]
+fn run_ends_up_in_the_same_place() {
+    let mut interpreted = processor(false);
+    let mut recompiled = processor(true);
+    assert_eq!(
+        interpreted.run_until(|_| false),
+        Some(ExitReason::ProgramExit)
+    );
+    assert_eq!(recompiled.run(), ExitReason::ProgramExit);
+    assert!(recompiled.save_state() == interpreted.save_state());
+}
@@ -0,0 +1,65 @@
+; What tests/recompiled/rom.rs was made from, a bit of everything the
+; recompiler handles itself and a bit of what it leaves to the interpreter.
+; After changing this, or the recompiler:
+;
+;   cargo run -- asm -o /tmp/rom.ch8 tests/recompiled/rom.asm
+;   cargo run -- recompile -o tests/recompiled/rom.rs /tmp/rom.ch8
+
+main:
+    LD VA, 0
+    LD VB, 2
+    LD I, sprite
+    DRW VA, VA, 4
+
+loop:
+    ADD VA, 1
+    LD V1, VA
+    SHR V1, V1
+    XOR V2, V1
+    RND V3, 0x0f
+    SE V3, 7
+    ADD V4, V3
+    SNE V4, 0
+    CALL reset
+    LD I, scratch
+    LD B, VA
+    LD V2, [I]
+    LD I, sprite
+    DRW V1, V4, 4
+    LD VC, DT
+    SE VC, 0
+    JP patch
+    LD DT, VB
+
+    ; Computed, so only the first entry's known ahead of time
+    LD V0, VA
+    AND V0, VB
+    JP V0, table
+table:
+    JP even
+    JP odd
+even:
+    ADD V5, 1
+    JP patch
+odd:
+    ADD V6, VF
+
+    ; Rewrites its own LD V8, so the recompiled block can't be trusted
+patch:
+    LD V8, 0
+    ADD V7, V8
+    LD V0, VA
+    LD I, patch + 1
+    LD [I], V0
+    SE VA, 20
+    JP loop
+    EXIT
+
+reset:
+    LD V4, 1
+    RET
+
+sprite:
+    DB 0x60, 0xf0, 0xf0, 0x60
+scratch:
+    DB 0, 0, 0
@@ -0,0 +1,449 @@
+//! Recompiled by chippe_rs from a ROM loaded at 0x0200. Don't edit, recompile it instead.
+
+#![allow(unused_imports, unused_mut, unused_variables, clippy::all)]
+
+use chippe_rs::{Fault, Instruction, Processor};
+
+/// Up to `limit` instructions from the PC, or 0 if it isn't the start of a block.
+pub fn run_block(cpu: &mut Processor, limit: u32) -> Result<u32, Fault> {
+    match cpu.program_counter() {
+        0x0200 => block_0200(cpu, limit),
+        0x0208 => block_0208(cpu, limit),
+        0x0216 => block_0216(cpu, limit),
+        0x021a => block_021a(cpu, limit),
+        0x021e => block_021e(cpu, limit),
+        0x0224 => block_0224(cpu, limit),
+        0x022a => block_022a(cpu, limit),
+        0x0232 => block_0232(cpu, limit),
+        0x0236 => block_0236(cpu, limit),
+        0x023c => block_023c(cpu, limit),
+        0x0246 => block_0246(cpu, limit),
+        0x024a => block_024a(cpu, limit),
+        0x024c => block_024c(cpu, limit),
+        _ => Ok(0),
+    }
+}
+
+fn block_0200(cpu: &mut Processor, limit: u32) -> Result<u32, Fault> {
+    if cpu.memory().get(0x0200..0x0208) != Some(&[0x6a, 0x00, 0x6b, 0x02, 0xa2, 0x50, 0xda, 0xa4][..]) {
+        return Ok(0);
+    }
+    let mut n = 0;
+
+    // 0200: LD VA, 0x00
+    let v = cpu.registers_mut();
+    v[0xa] = 0x00;
+    n += 1;
+
+    // 0202: LD VB, 0x02
+    if n == limit {
+        cpu.set_program_counter(0x0202);
+        return Ok(n);
+    }
+    let v = cpu.registers_mut();
+    v[0xb] = 0x02;
+    n += 1;
+
+    // 0204: LD I, 0x250
+    if n == limit {
+        cpu.set_program_counter(0x0204);
+        return Ok(n);
+    }
+    cpu.set_reg_i(0x0250);
+    n += 1;
+
+    // 0206: DRW VA, VA, 0x4
+    if n == limit {
+        cpu.set_program_counter(0x0206);
+        return Ok(n);
+    }
+    cpu.set_program_counter(0x0206);
+    let carry_on = cpu.execute_instruction(Instruction::Draw { x: 10, y: 10, rows: 4 })?;
+    n += 1;
+    Ok(n)
+}
+
+fn block_0208(cpu: &mut Processor, limit: u32) -> Result<u32, Fault> {
+    if cpu.memory().get(0x0208..0x0216) != Some(&[0x7a, 0x01, 0x81, 0xa0, 0x81, 0x16, 0x82, 0x13, 0xc3, 0x0f, 0x33, 0x07, 0x84, 0x34][..]) {
+        return Ok(0);
+    }
+    let mut n = 0;
+
+    // 0208: ADD VA, 0x01
+    let v = cpu.registers_mut();
+    v[0xa] = v[0xa].wrapping_add(0x01);
+    n += 1;
+
+    // 020a: LD V1, VA
+    if n == limit {
+        cpu.set_program_counter(0x020a);
+        return Ok(n);
+    }
+    let v = cpu.registers_mut();
+    v[0x1] = v[0xa];
+    n += 1;
+
+    // 020c: SHR V1, V1
+    if n == limit {
+        cpu.set_program_counter(0x020c);
+        return Ok(n);
+    }
+    let uses_vy = cpu.quirks().shift_uses_vy;
+    let v = cpu.registers_mut();
+    let value = if uses_vy { v[0x1] } else { v[0x1] };
+    v[0x1] = value >> 1;
+    v[0xf] = value & 1;
+    n += 1;
+
+    // 020e: XOR V2, V1
+    if n == limit {
+        cpu.set_program_counter(0x020e);
+        return Ok(n);
+    }
+    let reset = cpu.quirks().logic_resets_vf;
+    let v = cpu.registers_mut();
+    v[0x2] ^= v[0x1];
+    if reset {
+        v[0xf] = 0;
+    }
+    n += 1;
+
+    // 0210: RND V3, 0x0f
+    if n == limit {
+        cpu.set_program_counter(0x0210);
+        return Ok(n);
+    }
+    cpu.set_program_counter(0x0210);
+    let carry_on = cpu.execute_instruction(Instruction::Random { x: 3, mask: 15 })?;
+    n += 1;
+    if !carry_on || cpu.program_counter() != 0x0212 {
+        return Ok(n);
+    }
+
+    // 0212: SE V3, 0x07
+    if n == limit {
+        cpu.set_program_counter(0x0212);
+        return Ok(n);
+    }
+    let v = cpu.registers();
+    let skip = v[0x3] == 0x07;
+    n += 1;
+    if skip {
+        cpu.set_program_counter(0x0216);
+        return Ok(n);
+    }
+
+    // 0214: ADD V4, V3
+    if n == limit {
+        cpu.set_program_counter(0x0214);
+        return Ok(n);
+    }
+    let v = cpu.registers_mut();
+    let (sum, carry) = v[0x4].overflowing_add(v[0x3]);
+    v[0x4] = sum;
+    v[0xf] = carry as u8;
+    n += 1;
+    cpu.set_program_counter(0x0216);
+    Ok(n)
+}
+
+fn block_0216(cpu: &mut Processor, limit: u32) -> Result<u32, Fault> {
+    if cpu.memory().get(0x0216..0x021a) != Some(&[0x44, 0x00, 0x22, 0x4c][..]) {
+        return Ok(0);
+    }
+    let mut n = 0;
+
+    // 0216: SNE V4, 0x00
+    let v = cpu.registers();
+    let skip = v[0x4] != 0x00;
+    n += 1;
+    if skip {
+        cpu.set_program_counter(0x021a);
+        return Ok(n);
+    }
+
+    // 0218: CALL 0x24c
+    if n == limit {
+        cpu.set_program_counter(0x0218);
+        return Ok(n);
+    }
+    cpu.set_program_counter(0x0218);
+    let carry_on = cpu.execute_instruction(Instruction::Call(588))?;
+    n += 1;
+    Ok(n)
+}
+
+fn block_021a(cpu: &mut Processor, limit: u32) -> Result<u32, Fault> {
+    if cpu.memory().get(0x021a..0x021e) != Some(&[0xa2, 0x54, 0xfa, 0x33][..]) {
+        return Ok(0);
+    }
+    let mut n = 0;
+
+    // 021a: LD I, 0x254
+    cpu.set_reg_i(0x0254);
+    n += 1;
+
+    // 021c: LD B, VA
+    if n == limit {
+        cpu.set_program_counter(0x021c);
+        return Ok(n);
+    }
+    cpu.set_program_counter(0x021c);
+    let carry_on = cpu.execute_instruction(Instruction::Bcd(10))?;
+    n += 1;
+    Ok(n)
+}
+
+fn block_021e(cpu: &mut Processor, limit: u32) -> Result<u32, Fault> {
+    if cpu.memory().get(0x021e..0x0224) != Some(&[0xf2, 0x65, 0xa2, 0x50, 0xd1, 0x44][..]) {
+        return Ok(0);
+    }
+    let mut n = 0;
+
+    // 021e: LD V2, [I]
+    cpu.set_program_counter(0x021e);
+    let carry_on = cpu.execute_instruction(Instruction::LoadRegisters(2))?;
+    n += 1;
+    if !carry_on || cpu.program_counter() != 0x0220 {
+        return Ok(n);
+    }
+
+    // 0220: LD I, 0x250
+    if n == limit {
+        cpu.set_program_counter(0x0220);
+        return Ok(n);
+    }
+    cpu.set_reg_i(0x0250);
+    n += 1;
+
+    // 0222: DRW V1, V4, 0x4
+    if n == limit {
+        cpu.set_program_counter(0x0222);
+        return Ok(n);
+    }
+    cpu.set_program_counter(0x0222);
+    let carry_on = cpu.execute_instruction(Instruction::Draw { x: 1, y: 4, rows: 4 })?;
+    n += 1;
+    Ok(n)
+}
+
+fn block_0224(cpu: &mut Processor, limit: u32) -> Result<u32, Fault> {
+    if cpu.memory().get(0x0224..0x022a) != Some(&[0xfc, 0x07, 0x3c, 0x00, 0x12, 0x3c][..]) {
+        return Ok(0);
+    }
+    let mut n = 0;
+
+    // 0224: LD VC, DT
+    let delay = cpu.delay_timer();
+    let v = cpu.registers_mut();
+    v[0xc] = delay;
+    n += 1;
+
+    // 0226: SE VC, 0x00
+    if n == limit {
+        cpu.set_program_counter(0x0226);
+        return Ok(n);
+    }
+    let v = cpu.registers();
+    let skip = v[0xc] == 0x00;
+    n += 1;
+    if skip {
+        cpu.set_program_counter(0x022a);
+        return Ok(n);
+    }
+
+    // 0228: JP 0x23c
+    if n == limit {
+        cpu.set_program_counter(0x0228);
+        return Ok(n);
+    }
+    cpu.set_program_counter(0x023c);
+    n += 1;
+    Ok(n)
+}
+
+fn block_022a(cpu: &mut Processor, limit: u32) -> Result<u32, Fault> {
+    if cpu.memory().get(0x022a..0x0232) != Some(&[0xfb, 0x15, 0x80, 0xa0, 0x80, 0xb2, 0xb2, 0x32][..]) {
+        return Ok(0);
+    }
+    let mut n = 0;
+
+    // 022a: LD DT, VB
+    cpu.set_delay_timer(cpu.registers()[0xb]);
+    n += 1;
+
+    // 022c: LD V0, VA
+    if n == limit {
+        cpu.set_program_counter(0x022c);
+        return Ok(n);
+    }
+    let v = cpu.registers_mut();
+    v[0x0] = v[0xa];
+    n += 1;
+
+    // 022e: AND V0, VB
+    if n == limit {
+        cpu.set_program_counter(0x022e);
+        return Ok(n);
+    }
+    let reset = cpu.quirks().logic_resets_vf;
+    let v = cpu.registers_mut();
+    v[0x0] &= v[0xb];
+    if reset {
+        v[0xf] = 0;
+    }
+    n += 1;
+
+    // 0230: JP V0, 0x232
+    if n == limit {
+        cpu.set_program_counter(0x0230);
+        return Ok(n);
+    }
+    cpu.set_program_counter(0x0230);
+    let carry_on = cpu.execute_instruction(Instruction::JumpV0(562))?;
+    n += 1;
+    Ok(n)
+}
+
+fn block_0232(cpu: &mut Processor, limit: u32) -> Result<u32, Fault> {
+    if cpu.memory().get(0x0232..0x0234) != Some(&[0x12, 0x36][..]) {
+        return Ok(0);
+    }
+    let mut n = 0;
+
+    // 0232: JP 0x236
+    cpu.set_program_counter(0x0236);
+    n += 1;
+    Ok(n)
+}
+
+fn block_0236(cpu: &mut Processor, limit: u32) -> Result<u32, Fault> {
+    if cpu.memory().get(0x0236..0x023a) != Some(&[0x75, 0x01, 0x12, 0x3c][..]) {
+        return Ok(0);
+    }
+    let mut n = 0;
+
+    // 0236: ADD V5, 0x01
+    let v = cpu.registers_mut();
+    v[0x5] = v[0x5].wrapping_add(0x01);
+    n += 1;
+
+    // 0238: JP 0x23c
+    if n == limit {
+        cpu.set_program_counter(0x0238);
+        return Ok(n);
+    }
+    cpu.set_program_counter(0x023c);
+    n += 1;
+    Ok(n)
+}
+
+fn block_023c(cpu: &mut Processor, limit: u32) -> Result<u32, Fault> {
+    if cpu.memory().get(0x023c..0x0246) != Some(&[0x68, 0x00, 0x87, 0x84, 0x80, 0xa0, 0xa2, 0x3d, 0xf0, 0x55][..]) {
+        return Ok(0);
+    }
+    let mut n = 0;
+
+    // 023c: LD V8, 0x00
+    let v = cpu.registers_mut();
+    v[0x8] = 0x00;
+    n += 1;
+
+    // 023e: ADD V7, V8
+    if n == limit {
+        cpu.set_program_counter(0x023e);
+        return Ok(n);
+    }
+    let v = cpu.registers_mut();
+    let (sum, carry) = v[0x7].overflowing_add(v[0x8]);
+    v[0x7] = sum;
+    v[0xf] = carry as u8;
+    n += 1;
+
+    // 0240: LD V0, VA
+    if n == limit {
+        cpu.set_program_counter(0x0240);
+        return Ok(n);
+    }
+    let v = cpu.registers_mut();
+    v[0x0] = v[0xa];
+    n += 1;
+
+    // 0242: LD I, 0x23d
+    if n == limit {
+        cpu.set_program_counter(0x0242);
+        return Ok(n);
+    }
+    cpu.set_reg_i(0x023d);
+    n += 1;
+
+    // 0244: LD [I], V0
+    if n == limit {
+        cpu.set_program_counter(0x0244);
+        return Ok(n);
+    }
+    cpu.set_program_counter(0x0244);
+    let carry_on = cpu.execute_instruction(Instruction::StoreRegisters(0))?;
+    n += 1;
+    Ok(n)
+}
+
+fn block_0246(cpu: &mut Processor, limit: u32) -> Result<u32, Fault> {
+    if cpu.memory().get(0x0246..0x024a) != Some(&[0x3a, 0x14, 0x12, 0x08][..]) {
+        return Ok(0);
+    }
+    let mut n = 0;
+
+    // 0246: SE VA, 0x14
+    let v = cpu.registers();
+    let skip = v[0xa] == 0x14;
+    n += 1;
+    if skip {
+        cpu.set_program_counter(0x024a);
+        return Ok(n);
+    }
+
+    // 0248: JP 0x208
+    if n == limit {
+        cpu.set_program_counter(0x0248);
+        return Ok(n);
+    }
+    cpu.set_program_counter(0x0208);
+    n += 1;
+    Ok(n)
+}
+
+fn block_024a(cpu: &mut Processor, limit: u32) -> Result<u32, Fault> {
+    if cpu.memory().get(0x024a..0x024c) != Some(&[0x00, 0xfd][..]) {
+        return Ok(0);
+    }
+    let mut n = 0;
+
+    // 024a: EXIT
+    cpu.set_program_counter(0x024a);
+    let carry_on = cpu.execute_instruction(Instruction::Exit)?;
+    n += 1;
+    Ok(n)
+}
+
+fn block_024c(cpu: &mut Processor, limit: u32) -> Result<u32, Fault> {
+    if cpu.memory().get(0x024c..0x0250) != Some(&[0x64, 0x01, 0x00, 0xee][..]) {
+        return Ok(0);
+    }
+    let mut n = 0;
+
+    // 024c: LD V4, 0x01
+    let v = cpu.registers_mut();
+    v[0x4] = 0x01;
+    n += 1;
+
+    // 024e: RET
+    if n == limit {
+        cpu.set_program_counter(0x024e);
+        return Ok(n);
+    }
+    cpu.set_program_counter(0x024e);
+    let carry_on = cpu.execute_instruction(Instruction::Ret)?;
+    n += 1;
+    Ok(n)
+}